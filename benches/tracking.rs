@@ -0,0 +1,155 @@
+//! Seeds in memory storage with users tracking a few centers each and
+//! measures the work a poll does for them. Run with `cargo bench`.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nexus_pls::center::{slot_notifications, CenterId, PendingNotification, Service, Slot};
+use nexus_pls::storage::{MemoryStorage, Storage};
+use nexus_pls::tracking::{TrackingManager, UserData, UserId, USERS_KEY};
+use nexus_pls::{set_cli_args, MANAGER};
+use tokio::runtime::Runtime;
+
+const USER_COUNTS: [u64; 3] = [100, 1_000, 10_000];
+/// The centers in `centers.toml`, each user tracks [`SUBSCRIPTIONS`] of them.
+const CENTERS: [CenterId; 6] = [5161, 5022, 5027, 5025, 5020, 5060];
+const SUBSCRIPTIONS: usize = 3;
+/// Center the fan-out slots are at, tracked by half of the users.
+const POLLED: CenterId = 5161;
+
+/// Centers tracked by `user`.
+fn subscriptions(user: UserId) -> Vec<CenterId> {
+  (0..SUBSCRIPTIONS)
+    .map(|i| CENTERS[(user as usize + i) % CENTERS.len()])
+    .collect()
+}
+
+fn runtime() -> Runtime {
+  set_cli_args(&["nexus-pls", "run", "--storage-backend", "memory", "--dry-run"]);
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+}
+
+/// Replaces the shared manager with one loaded from storage holding `users`
+/// users, each tracking [`SUBSCRIPTIONS`] centers.
+async fn seed(users: u64) {
+  let mut storage = MemoryStorage::default();
+  let ids = (0..users).map(|x| x.to_string()).collect();
+  storage.add_members(USERS_KEY, ids).await.unwrap();
+  for user in 0..users {
+    let user_data = UserData {
+      subscriptions: subscriptions(user),
+      chat_id: user as i64,
+      ..Default::default()
+    };
+    storage
+      .set(&user.to_string(), serde_json::to_string(&user_data).unwrap())
+      .await
+      .unwrap();
+  }
+  *MANAGER.lock().await = Some(TrackingManager::new(Box::new(storage)).await);
+}
+
+/// A slot nobody was alerted about yet, so every subscriber gets an alert.
+fn new_slot() -> Slot {
+  static MINUTES: AtomicI64 = AtomicI64::new(0);
+  let start = Local::now().naive_local().date().and_hms_opt(0, 0, 0).unwrap()
+    + chrono::Duration::days(1)
+    + chrono::Duration::minutes(MINUTES.fetch_add(1, Ordering::Relaxed));
+  serde_json::from_value(serde_json::json!({
+    "locationId": POLLED,
+    "startTimestamp": start.format("%Y-%m-%dT%H:%M").to_string(),
+    "active": 1,
+  }))
+  .unwrap()
+}
+
+/// A poll that finds the slot alerted about last time gone and a new one
+/// open, returning the alerts for it.
+async fn poll() -> Vec<PendingNotification> {
+  let slots = [new_slot()];
+  MANAGER
+    .lock()
+    .await
+    .as_mut()
+    .unwrap()
+    .forget_unavailable_slots(POLLED, &slots, Local::now().naive_local())
+    .await
+    .unwrap();
+  slot_notifications(POLLED, &slots, &[]).await
+}
+
+fn subscriber_index(c: &mut Criterion) {
+  let rt = runtime();
+  let mut group = c.benchmark_group("subscriber_index");
+  for users in USER_COUNTS {
+    rt.block_on(seed(users));
+    let mut lock = rt.block_on(MANAGER.lock());
+    let manager = lock.as_mut().unwrap();
+    group.bench_with_input(BenchmarkId::from_parameter(users), &users, |b, _| {
+      b.iter(|| manager.rebuild_subscriber_index())
+    });
+  }
+  group.finish();
+}
+
+fn fan_out(c: &mut Criterion) {
+  let rt = runtime();
+  let mut group = c.benchmark_group("fan_out");
+  group.sample_size(20);
+  for users in USER_COUNTS {
+    rt.block_on(seed(users));
+    let subscribers = (0..users).filter(|x| subscriptions(*x).contains(&POLLED)).count();
+    group.bench_with_input(BenchmarkId::from_parameter(users), &users, |b, _| {
+      b.to_async(&rt).iter(|| async {
+        assert_eq!(poll().await.len(), subscribers);
+      })
+    });
+  }
+  group.finish();
+}
+
+/// Latency of a `/track` and `/untrack` pair while polls keep alerting every
+/// subscriber in the background.
+fn command_under_load(c: &mut Criterion) {
+  let rt = runtime();
+  let mut group = c.benchmark_group("command_under_load");
+  for users in USER_COUNTS {
+    rt.block_on(seed(users));
+    let load = rt.spawn(async {
+      loop {
+        poll().await;
+        tokio::task::yield_now().await;
+      }
+    });
+    let user: UserId = users;
+    group.bench_with_input(BenchmarkId::from_parameter(users), &users, |b, _| {
+      b.to_async(&rt).iter_custom(|iters| async move {
+        let start = Instant::now();
+        for _ in 0..iters {
+          let mut lock = MANAGER.lock().await;
+          let manager = lock.as_mut().unwrap();
+          manager
+            .track_center(user as i64, user, POLLED, Service::Nexus)
+            .await
+            .unwrap();
+          manager.untrack_center(user, POLLED).await.unwrap();
+        }
+        start.elapsed()
+      })
+    });
+    load.abort();
+  }
+  group.finish();
+}
+
+criterion_group! {
+  name = benches;
+  config = Criterion::default().measurement_time(Duration::from_secs(10));
+  targets = subscriber_index, fan_out, command_under_load
+}
+criterion_main!(benches);
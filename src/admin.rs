@@ -0,0 +1,312 @@
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::center::{match_center, Center, CenterMatch, Service};
+use crate::http::has_bearer;
+use crate::tracking::UserId;
+use crate::{centers, CONFIG, MANAGER};
+
+/// Source recorded in the audit log for changes made through the API.
+const AUDIT_SOURCE: &str = "api";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route<'a> {
+  User(UserId),
+  Subscriptions(UserId),
+  Subscribers(&'a str),
+}
+
+/// Matches a path below `/admin/` to its resource.
+pub fn route(path: &str) -> Option<Route<'_>> {
+  let segments = path.strip_prefix("/admin/")?.split('/').collect::<Vec<_>>();
+  match segments.as_slice() {
+    ["users", user] => user.parse().ok().map(Route::User),
+    ["users", user, "subscriptions"] => user.parse().ok().map(Route::Subscriptions),
+    ["centers", center, "subscribers"] => Some(Route::Subscribers(center)),
+    _ => None,
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+  Add,
+  Remove,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscriptionChange {
+  action: Action,
+  center: String,
+  /// Chat notifications go to for new users, their private chat by default.
+  chat_id: Option<i64>,
+  #[serde(default)]
+  service: Service,
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+  let mut response = Response::new(Body::from(body.to_string()));
+  *response.status_mut() = status;
+  response
+    .headers_mut()
+    .insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+  response
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+  respond(status, json!({ "error": message }))
+}
+
+/// Looks up a center the same way bot commands do, explaining a failed
+/// lookup with the matching or closest short names.
+fn center_or_error(query: &str) -> Result<Center, (StatusCode, Value)> {
+  let all = centers();
+  match match_center(&all, query) {
+    CenterMatch::Found(center) => Ok(center.clone()),
+    CenterMatch::Ambiguous(candidates) => Err((
+      StatusCode::BAD_REQUEST,
+      json!({
+        "error": format!("{} matches several centers", query.trim()),
+        "candidates": short_names(&candidates),
+      }),
+    )),
+    CenterMatch::Missing(close) => Err((
+      StatusCode::NOT_FOUND,
+      json!({
+        "error": format!("unknown center {}", query.trim()),
+        "suggestions": short_names(&close),
+      }),
+    )),
+  }
+}
+
+fn short_names(centers: &[&Center]) -> Vec<String> {
+  centers.iter().map(|x| x.short_name.clone()).collect()
+}
+
+pub async fn handle(request: Request<Body>) -> Response<Body> {
+  let token = match &CONFIG.admin_token {
+    Some(token) => token,
+    None => return error(StatusCode::NOT_FOUND, "admin api is disabled"),
+  };
+  if !has_bearer(&request, token) {
+    return error(StatusCode::UNAUTHORIZED, "missing or invalid admin token");
+  }
+
+  let method = request.method().clone();
+  let path = request.uri().path().to_string();
+  match (method, route(&path)) {
+    (Method::GET, Some(Route::User(user))) => get_user(user).await,
+    (Method::DELETE, Some(Route::User(user))) => delete_user(user).await,
+    (Method::POST, Some(Route::Subscriptions(user))) => change_subscription(user, request.into_body()).await,
+    (Method::GET, Some(Route::Subscribers(center))) => subscribers(center).await,
+    (_, Some(_)) => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+    (_, None) => error(StatusCode::NOT_FOUND, "not found"),
+  }
+}
+
+async fn get_user(user: UserId) -> Response<Body> {
+  let mut lock = MANAGER.lock().await;
+  match lock.as_mut().unwrap().get_user_data(user).await {
+    Ok(Some(user_data)) => respond(
+      StatusCode::OK,
+      json!({ "user": user, "data": user_data, "tracked_centers": user_data.tracked_centers() }),
+    ),
+    Ok(None) => error(StatusCode::NOT_FOUND, "user not found"),
+    Err(err) => error(StatusCode::SERVICE_UNAVAILABLE, &err.to_string()),
+  }
+}
+
+async fn delete_user(user: UserId) -> Response<Body> {
+  let mut lock = MANAGER.lock().await;
+  let manager = lock.as_mut().unwrap();
+  match manager.get_user_data(user).await {
+    Ok(Some(_)) => {},
+    Ok(None) => return error(StatusCode::NOT_FOUND, "user not found"),
+    Err(err) => return error(StatusCode::SERVICE_UNAVAILABLE, &err.to_string()),
+  }
+
+  if let Err(err) = manager.delete_user(user).await {
+    return error(StatusCode::SERVICE_UNAVAILABLE, &err.to_string());
+  }
+  if let Err(err) = manager.record_audit(AUDIT_SOURCE, user, "delete user").await {
+    warn!(user_id = user, "Failed to record audit entry: {}", err);
+  }
+  info!(user_id = user, "User deleted through the admin api");
+  respond(StatusCode::OK, json!({ "user": user, "deleted": true }))
+}
+
+async fn change_subscription(user: UserId, body: Body) -> Response<Body> {
+  let change = match hyper::body::to_bytes(body).await {
+    Ok(body) => serde_json::from_slice::<SubscriptionChange>(&body),
+    Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+  };
+  let change = match change {
+    Ok(change) => change,
+    Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+  };
+  let center = match center_or_error(&change.center) {
+    Ok(center) => center,
+    Err((status, body)) => return respond(status, body),
+  };
+
+  let mut lock = MANAGER.lock().await;
+  let manager = lock.as_mut().unwrap();
+  let (result, action) = match change.action {
+    Action::Add if !center.enabled => return error(StatusCode::BAD_REQUEST, &center.disabled_msg()),
+    Action::Add if !center.offers(change.service) => {
+      return error(
+        StatusCode::BAD_REQUEST,
+        &format!("{} does not offer {}", center.short_name, change.service.name()),
+      )
+    },
+    Action::Add => (
+      manager
+        .track_center(change.chat_id.unwrap_or(user as i64), user, center.id, change.service)
+        .await,
+      format!("track {}", center.short_name),
+    ),
+    Action::Remove => (
+      manager.untrack_center(user, center.id).await,
+      format!("untrack {}", center.short_name),
+    ),
+  };
+  if let Err(err) = result {
+    return error(StatusCode::CONFLICT, &err.to_string());
+  }
+  if let Err(err) = manager.record_audit(AUDIT_SOURCE, user, &action).await {
+    warn!(user_id = user, "Failed to record audit entry: {}", err);
+  }
+  info!(user_id = user, "Admin api: {}", action);
+
+  match manager.get_user_data(user).await {
+    Ok(Some(user_data)) => respond(StatusCode::OK, json!({ "user": user, "data": user_data })),
+    Ok(None) => error(StatusCode::NOT_FOUND, "user not found"),
+    Err(err) => error(StatusCode::SERVICE_UNAVAILABLE, &err.to_string()),
+  }
+}
+
+async fn subscribers(query: &str) -> Response<Body> {
+  let center = match center_or_error(query) {
+    Ok(center) => center,
+    Err((status, body)) => return respond(status, body),
+  };
+  let subscribers = MANAGER
+    .lock()
+    .await
+    .as_mut()
+    .unwrap()
+    .get_center_subscribers()
+    .get(&center.id)
+    .cloned()
+    .unwrap_or_default();
+  respond(
+    StatusCode::OK,
+    json!({ "center": center.id, "short_name": center.short_name, "subscribers": subscribers }),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{start_test_manager, TEST_ADMIN_TOKEN};
+
+  #[test]
+  fn routes_match_admin_paths() {
+    assert_eq!(route("/admin/users/42"), Some(Route::User(42)));
+    assert_eq!(route("/admin/users/42/subscriptions"), Some(Route::Subscriptions(42)));
+    assert_eq!(
+      route("/admin/centers/peace arch/subscribers"),
+      Some(Route::Subscribers("peace arch"))
+    );
+    assert_eq!(route("/admin/users/bob"), None);
+    assert_eq!(route("/admin/users/42/"), None);
+    assert_eq!(route("/admin/centers"), None);
+    assert_eq!(route("/users/42"), None);
+  }
+
+  async fn call(method: Method, path: &str, token: Option<&str>, body: Value) -> (StatusCode, Value) {
+    let mut request = Request::builder().method(method).uri(path);
+    if let Some(token) = token {
+      request = request.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = handle(request.body(Body::from(body.to_string())).unwrap()).await;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+  }
+
+  async fn admin(method: Method, path: &str, body: Value) -> (StatusCode, Value) {
+    call(method, path, Some(TEST_ADMIN_TOKEN), body).await
+  }
+
+  #[tokio::test]
+  async fn requests_need_the_admin_token() {
+    for token in [None, Some("wrong")] {
+      let (status, body) = call(Method::GET, "/admin/users/1", token, Value::Null).await;
+      assert_eq!(status, StatusCode::UNAUTHORIZED);
+      assert_eq!(body["error"], "missing or invalid admin token");
+    }
+  }
+
+  #[tokio::test]
+  async fn invalid_requests_are_rejected() {
+    start_test_manager().await;
+    let path = "/admin/users/9000/subscriptions";
+    let (status, _) = admin(Method::POST, path, json!({ "action": "follow", "center": "niagara" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = admin(Method::POST, path, json!({ "action": "add", "center": "niagra" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["suggestions"], json!(["niagara"]));
+
+    let (status, body) = admin(Method::POST, path, json!({ "action": "add", "center": "b" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["candidates"], json!(["buffalo", "blane"]));
+
+    let (status, _) = admin(Method::POST, path, json!({ "action": "remove", "center": "niagara" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    assert_eq!(
+      admin(Method::PUT, "/admin/users/9000", Value::Null).await.0,
+      StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+      admin(Method::GET, "/admin/nothing", Value::Null).await.0,
+      StatusCode::NOT_FOUND
+    );
+  }
+
+  #[tokio::test]
+  async fn subscription_changes_show_up_in_later_reads() {
+    start_test_manager().await;
+    let (status, body) = admin(
+      Method::POST,
+      "/admin/users/9001/subscriptions",
+      json!({ "action": "add", "center": "Peace Arch" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, body) = admin(Method::GET, "/admin/users/9001", Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["tracked_centers"], json!([5020]));
+
+    let (_, body) = admin(Method::GET, "/admin/centers/blane/subscribers", Value::Null).await;
+    assert!(body["subscribers"].as_array().unwrap().contains(&json!(9001)));
+
+    assert_eq!(
+      admin(Method::DELETE, "/admin/users/9001", Value::Null).await.0,
+      StatusCode::OK
+    );
+    assert_eq!(
+      admin(Method::GET, "/admin/users/9001", Value::Null).await.0,
+      StatusCode::NOT_FOUND
+    );
+    let (_, body) = admin(Method::GET, "/admin/centers/blane/subscribers", Value::Null).await;
+    assert!(!body["subscribers"].as_array().unwrap().contains(&json!(9001)));
+  }
+}
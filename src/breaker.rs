@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::center::CenterId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+  Closed,
+  /// Failing, the center is skipped until the backoff expires.
+  BackingOff,
+  /// Failed often enough that the center is skipped for the full cooldown.
+  Open,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CenterFailures {
+  failures: u32,
+  retry_at: Instant,
+}
+
+/// Tracks consecutive scheduler failures per center so a failing center is
+/// polled less and less often, and not at all for a while once it has failed
+/// `threshold` times in a row.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+  base: Duration,
+  cooldown: Duration,
+  threshold: u32,
+  centers: HashMap<CenterId, CenterFailures>,
+}
+
+impl CircuitBreaker {
+  /// Backoff starts at `base` and doubles with every failure up to
+  /// `cooldown`, which is also how long the circuit stays open.
+  pub fn new(base: Duration, cooldown: Duration, threshold: u32) -> Self {
+    Self {
+      base,
+      cooldown,
+      threshold,
+      centers: HashMap::new(),
+    }
+  }
+
+  /// Delay before polling a center again after `failures` failures in a row.
+  pub fn backoff(&self, failures: u32) -> Duration {
+    if failures >= self.threshold {
+      return self.cooldown;
+    }
+    let doublings = failures.saturating_sub(1).min(16);
+    (self.base * 2u32.pow(doublings)).min(self.cooldown)
+  }
+
+  pub fn state(&self, center: CenterId) -> BreakerState {
+    match self.centers.get(&center) {
+      None => BreakerState::Closed,
+      Some(record) if record.failures >= self.threshold => BreakerState::Open,
+      Some(_) => BreakerState::BackingOff,
+    }
+  }
+
+  /// Whether `center` should be polled at `now`. A center whose backoff or
+  /// cooldown has run out gets a single attempt, and another failure puts it
+  /// back.
+  pub fn allows(&self, center: CenterId, now: Instant) -> bool {
+    self.centers.get(&center).is_none_or(|record| now >= record.retry_at)
+  }
+
+  /// Records a failed poll, returning the new state and how long the center
+  /// is skipped for.
+  pub fn record_failure(&mut self, center: CenterId, now: Instant) -> (BreakerState, Duration) {
+    let failures = self.centers.get(&center).map_or(0, |x| x.failures) + 1;
+    let delay = self.backoff(failures);
+    self.centers.insert(
+      center,
+      CenterFailures {
+        failures,
+        retry_at: now + delay,
+      },
+    );
+    (self.state(center), delay)
+  }
+
+  /// Records a successful poll, returning the state the center was in.
+  pub fn record_success(&mut self, center: CenterId) -> BreakerState {
+    let previous = self.state(center);
+    self.centers.remove(&center);
+    previous
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+  }
+
+  fn breaker() -> CircuitBreaker {
+    CircuitBreaker::new(secs(15), secs(480), 5)
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_the_cooldown() {
+    let breaker = breaker();
+    let delays = (1..=6).map(|x| breaker.backoff(x)).collect::<Vec<_>>();
+    assert_eq!(delays, [secs(15), secs(30), secs(60), secs(120), secs(480), secs(480)]);
+
+    let long = CircuitBreaker::new(secs(15), secs(480), 100);
+    assert_eq!(long.backoff(6), secs(480));
+    assert_eq!(long.backoff(99), secs(480));
+  }
+
+  #[test]
+  fn failures_back_off_then_open_the_circuit() {
+    let mut breaker = breaker();
+    let now = Instant::now();
+    assert_eq!(breaker.state(1), BreakerState::Closed);
+    assert!(breaker.allows(1, now));
+
+    assert_eq!(breaker.record_failure(1, now), (BreakerState::BackingOff, secs(15)));
+    assert!(!breaker.allows(1, now + secs(14)));
+    assert!(breaker.allows(1, now + secs(15)));
+    assert!(breaker.allows(2, now));
+
+    for _ in 2..5 {
+      breaker.record_failure(1, now);
+    }
+    assert_eq!(breaker.record_failure(1, now), (BreakerState::Open, secs(480)));
+    assert!(!breaker.allows(1, now + secs(479)));
+    assert!(breaker.allows(1, now + secs(480)));
+  }
+
+  #[test]
+  fn a_failed_attempt_after_the_cooldown_reopens_it() {
+    let mut breaker = breaker();
+    let now = Instant::now();
+    for _ in 0..5 {
+      breaker.record_failure(1, now);
+    }
+    let later = now + secs(480);
+    assert_eq!(breaker.record_failure(1, later), (BreakerState::Open, secs(480)));
+    assert!(!breaker.allows(1, later + secs(1)));
+  }
+
+  #[test]
+  fn success_resets_the_center() {
+    let mut breaker = breaker();
+    let now = Instant::now();
+    for _ in 0..5 {
+      breaker.record_failure(1, now);
+    }
+    assert_eq!(breaker.record_success(1), BreakerState::Open);
+    assert_eq!(breaker.state(1), BreakerState::Closed);
+    assert!(breaker.allows(1, now));
+    assert_eq!(breaker.record_failure(1, now), (BreakerState::BackingOff, secs(15)));
+    assert_eq!(breaker.record_success(2), BreakerState::Closed);
+  }
+}
@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// A small map whose entries expire `ttl` after they were inserted.
+pub struct TtlCache<K, V> {
+  ttl: Duration,
+  entries: HashMap<K, (Instant, V)>,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      entries: HashMap::new(),
+    }
+  }
+
+  pub fn get(&mut self, key: &K) -> Option<V> {
+    let now = Instant::now();
+    self
+      .entries
+      .retain(|_, (inserted, _)| now.duration_since(*inserted) < self.ttl);
+    self.entries.get(key).map(|(_, value)| value.clone())
+  }
+
+  pub fn insert(&mut self, key: K, value: V) {
+    if !self.ttl.is_zero() {
+      self.entries.insert(key, (Instant::now(), value));
+    }
+  }
+}
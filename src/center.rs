@@ -1,34 +1,185 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
+use std::fs;
 use std::future::Future;
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::mpsc::{self, Receiver, Sender};
 use std::task::{Context, Poll};
-use std::thread;
 use std::time::{Duration, Instant};
 
-use chrono::{NaiveDateTime, Datelike, NaiveDate};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
+use hyper::client::connect::Connect;
 use hyper::client::HttpConnector;
 use hyper::{Client, Uri};
 use hyper_rustls::HttpsConnector;
-use serde::Deserialize;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
 use teloxide::adaptors::AutoSend;
 use teloxide::payloads::SendMessageSetters;
 use teloxide::prelude::Requester;
 use teloxide::types::{ChatId, ParseMode, Recipient};
-use teloxide::utils::markdown::escape;
+use teloxide::utils::markdown::{escape, link};
 use teloxide::Bot;
-use tracing::{info, warn};
+use teloxide::{ApiError, RequestError};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::Sleep;
+use tracing::{debug, info, warn};
 
-use crate::{CENTER_LUT, MANAGER};
+use crate::breaker::{BreakerState, CircuitBreaker};
+use crate::closure::Closure;
+use crate::health;
+use crate::history::SlotHistory;
+use crate::metrics;
+use crate::tracking::{DigestEntry, NotificationMode, TrackingError, TrackingManager, UserData, UserId};
+use crate::{center_lut, CONFIG, MANAGER, SLOT_CACHE};
 
 pub type CenterId = u32;
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
 
-#[derive(Deserialize, Clone)]
+const SCHEDULE_LINK: &str =
+  "https://ttp.cbp.dhs.gov/schedulerui/schedule-interview/location?lang=en&vo=true&returnUrl=ttp-external&service=";
+const CANADA_SCHEDULE_LINK: &str = "https://www.cbsa-asfc.gc.ca/prog/nexus/application-demande-eng.html";
+/// Zone of centers without a `timezone`, where most centers are.
+const DEFAULT_TIMEZONE: Tz = chrono_tz::America::New_York;
+/// Most slot times listed in one alert, the rest are summed up.
+const MAX_LISTED_SLOTS: usize = 5;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Country {
+  #[default]
+  #[serde(rename = "US")]
+  UnitedStates,
+  #[serde(rename = "CA")]
+  Canada,
+}
+
+impl Country {
+  /// Maps an ISO country code, as used by the locations API, to a country.
+  pub fn from_code(code: &str) -> Option<Self> {
+    match code.trim().to_uppercase().as_str() {
+      "US" | "USA" => Some(Self::UnitedStates),
+      "CA" | "CAN" => Some(Self::Canada),
+      _ => None,
+    }
+  }
+
+  /// Parses a user supplied `/list` filter such as `canada` or `us`.
+  pub fn from_filter(filter: &str) -> Option<Self> {
+    match normalize_name(filter).as_str() {
+      "canada" => Some(Self::Canada),
+      "usa" | "united states" => Some(Self::UnitedStates),
+      other => Self::from_code(other),
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::UnitedStates => "USA",
+      Self::Canada => "Canada",
+    }
+  }
+}
+
+/// Trusted traveler program a subscription is for. Centers files may also
+/// use the names the locations api gives them.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Service {
+  #[default]
+  #[serde(rename = "nexus", alias = "NEXUS")]
+  Nexus,
+  #[serde(rename = "ge", alias = "Global Entry")]
+  GlobalEntry,
+  #[serde(rename = "sentri", alias = "SENTRI")]
+  Sentri,
+  #[serde(rename = "fast", alias = "FAST")]
+  Fast,
+}
+
+impl Service {
+  /// Parses a user supplied service such as `ge` or `global entry`.
+  pub fn from_filter(filter: &str) -> Option<Self> {
+    match normalize_name(filter).as_str() {
+      "nexus" | "nh" => Some(Self::Nexus),
+      "ge" | "global entry" | "globalentry" => Some(Self::GlobalEntry),
+      "sentri" => Some(Self::Sentri),
+      "fast" => Some(Self::Fast),
+      _ => None,
+    }
+  }
+
+  /// Name of the service as used by the locations api.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Nexus => "NEXUS",
+      Self::GlobalEntry => "Global Entry",
+      Self::Sentri => "SENTRI",
+      Self::Fast => "FAST",
+    }
+  }
+
+  /// The `service` parameter of the scheduler's booking page.
+  fn schedule_code(&self) -> &'static str {
+    match self {
+      Self::Nexus => "nh",
+      Self::GlobalEntry => "up",
+      Self::Sentri => "sh",
+      Self::Fast => "fp",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Center {
   pub id: CenterId,
   pub short_name: String,
   pub full_name: String,
   pub address: String,
+  #[serde(default)]
+  pub country: Country,
+  #[serde(default)]
+  pub state: Option<String>,
+  #[serde(default)]
+  pub aliases: Vec<String>,
+  /// Programs the center enrolls for, NEXUS only when not configured.
+  #[serde(default = "default_services")]
+  pub services: Vec<Service>,
+  #[serde(default)]
+  pub latitude: Option<f64>,
+  #[serde(default)]
+  pub longitude: Option<f64>,
+  #[serde(default)]
+  pub hours: Option<String>,
+  #[serde(default)]
+  pub phone: Option<String>,
+  #[serde(default = "default_enabled")]
+  pub enabled: bool,
+  #[serde(default)]
+  pub closures: Vec<Closure>,
+  #[serde(default)]
+  pub booking_url: Option<String>,
+  /// IANA name of the zone the scheduler reports the center's slot times in.
+  #[serde(default)]
+  pub timezone: Option<String>,
+}
+
+fn default_enabled() -> bool {
+  true
+}
+
+fn default_services() -> Vec<Service> {
+  vec![Service::Nexus]
+}
+
+/// The soonest slot seen for a center on the most recent poll that found any.
+#[derive(Debug, Clone)]
+pub struct Availability {
+  pub observed_at: NaiveDateTime,
+  pub soonest: Slot,
 }
 
 impl Display for Center {
@@ -38,20 +189,702 @@ impl Display for Center {
 }
 
 impl Center {
-  fn appointment_avaliable_msg(&self, slot: &Slot) -> String {
-    let timeslot = NaiveDateTime::parse_from_str(&slot.start_timestamp, "%Y-%m-%dT%H:%M").unwrap();
-    let timeslot = timeslot.format("%l:%M %p on %A %B %-d").to_string();
-    let link = "https://ttp.cbp.dhs.gov/schedulerui/schedule-interview/location?lang=en&vo=true&returnUrl=ttp-external&service=nh";
+  /// State or province of the center, preferring the configured `state` and
+  /// falling back to the last component of the address.
+  pub fn state(&self) -> Option<String> {
+    if let Some(state) = &self.state {
+      return Some(state.clone());
+    }
+
+    let (_, region) = self.address.rsplit_once(',')?;
+    let words = region
+      .split_whitespace()
+      .filter(|word| !word.chars().any(|c| c.is_ascii_digit()))
+      .map(|word| {
+        let mut chars = word.chars();
+        chars.next().map_or_else(String::new, |first| {
+          first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect()
+        })
+      })
+      .collect::<Vec<_>>();
+
+    if words.is_empty() {
+      None
+    } else {
+      Some(words.join(" "))
+    }
+  }
+
+  /// The center as shown in listings, annotated when disabled or temporarily
+  /// closed.
+  pub fn status_line(&self, closed: &HashSet<CenterId>) -> String {
+    if !self.enabled {
+      format!("{} _\\(disabled\\)_", self)
+    } else if closed.contains(&self.id) {
+      format!("{} _\\(temporarily closed\\)_", self)
+    } else {
+      format!("{}", self)
+    }
+  }
+
+  pub fn disabled_msg(&self) -> String {
+    format!(
+      "{} is currently disabled and can't be tracked. Existing subscriptions are kept.",
+      self.full_name
+    )
+  }
+
+  /// Google Maps search URL for the center, using its coordinates when
+  /// configured and its address otherwise.
+  pub fn map_url(&self) -> String {
+    format!(
+      "https://www.google.com/maps/search/?api=1&query={}",
+      utf8_percent_encode(&self.map_query().unwrap_or_default(), NON_ALPHANUMERIC)
+    )
+  }
+
+  /// Google Maps directions URL to the center, optionally starting from
+  /// `origin`. `None` when the center has neither coordinates nor an address.
+  pub fn directions_url(&self, origin: Option<&str>) -> Option<String> {
+    let mut url = format!(
+      "https://www.google.com/maps/dir/?api=1&destination={}",
+      utf8_percent_encode(&self.map_query()?, NON_ALPHANUMERIC)
+    );
+    if let Some(origin) = origin.map(str::trim).filter(|x| !x.is_empty()) {
+      url.push_str(&format!("&origin={}", utf8_percent_encode(origin, NON_ALPHANUMERIC)));
+    }
+    Some(url)
+  }
+
+  fn map_query(&self) -> Option<String> {
+    match (self.latitude, self.longitude) {
+      (Some(latitude), Some(longitude)) => Some(format!("{},{}", latitude, longitude)),
+      _ => Some(self.formatted_address()).filter(|_| !self.address.trim().is_empty()),
+    }
+  }
+
+  /// Postal address with the country appended and, for Canada, the postal
+  /// code in its usual `A1A 1A1` form.
+  pub fn formatted_address(&self) -> String {
+    let address = self.address.trim();
+    let address = match self.country {
+      Country::Canada => match address.rsplit_once(' ') {
+        Some((rest, postal_code)) if postal_code.len() == 6 && postal_code.chars().any(|c| c.is_ascii_digit()) => {
+          format!("{} {} {}", rest, &postal_code[..3], &postal_code[3..])
+        },
+        _ => address.to_string(),
+      },
+      Country::UnitedStates => address.to_string(),
+    };
+    format!("{}, {}", address, self.country.name())
+  }
+
+  /// Zone the center's slot times are in, Eastern unless configured.
+  pub fn tz(&self) -> Tz {
+    self
+      .timezone
+      .as_deref()
+      .and_then(|x| x.parse().ok())
+      .unwrap_or(DEFAULT_TIMEZONE)
+  }
+
+  /// The time at the center right now, which slot times are compared to.
+  pub fn now(&self) -> NaiveDateTime {
+    Utc::now().with_timezone(&self.tz()).naive_local()
+  }
+
+  pub fn today(&self) -> NaiveDate {
+    self.now().date()
+  }
+
+  /// Where users should go to book an appointment for `service` at this
+  /// center.
+  pub fn booking_url(&self, service: Service) -> String {
+    match (&self.booking_url, self.country) {
+      (Some(url), _) => url.clone(),
+      (None, Country::UnitedStates) => format!("{}{}", SCHEDULE_LINK, service.schedule_code()),
+      (None, Country::Canada) => CANADA_SCHEDULE_LINK.to_string(),
+    }
+  }
+
+  pub fn offers(&self, service: Service) -> bool {
+    self.services.contains(&service)
+  }
+
+  /// Detailed MarkdownV2 description of the center for `/info`.
+  pub fn info_msg(
+    &self,
+    closed: bool,
+    subscribers: usize,
+    availability: Option<&Availability>,
+    tracking: bool,
+  ) -> String {
+    let mut lines = vec![format!(
+      "*{}* \\(`{}`\\)",
+      escape(&self.full_name),
+      escape(&self.short_name)
+    )];
+    if !self.enabled {
+      lines.push("_Disabled_".to_string());
+    } else if closed {
+      lines.push("_Temporarily closed_".to_string());
+    }
+    if !self.aliases.is_empty() {
+      lines.push(format!("Also known as: {}", escape(&self.aliases.join(", "))));
+    }
+    lines.push(format!("Address: {}", self.map_link()));
+    if let Some(state) = self.state() {
+      lines.push(format!("State: {}", escape(&state)));
+    }
+    if !self.services.is_empty() {
+      let services = self.services.iter().map(Service::name).collect::<Vec<_>>();
+      lines.push(format!("Services: {}", escape(&services.join(", "))));
+    }
+    if let Some(hours) = &self.hours {
+      lines.push(format!("Hours: {}", escape(hours)));
+    }
+    if let Some(phone) = &self.phone {
+      lines.push(format!("Phone: {}", escape(phone)));
+    }
+    lines.push(escape(&format!("Tracked by {} user(s)", subscribers)));
+    lines.push(match availability {
+      Some(availability) => escape(&format!(
+        "Last availability: {} (seen {})",
+        format_slot_time(&availability.soonest.start_timestamp, Some(self.tz())),
+        availability.observed_at.format("%b %-d %l:%M %p")
+      )),
+      None => "Last availability: none observed".to_string(),
+    });
+    lines.push(if tracking {
+      "You are tracking this center".to_string()
+    } else {
+      "You are not tracking this center".to_string()
+    });
+    lines.join("\n")
+  }
+
+  fn map_link(&self) -> String {
+    link(&self.map_url(), &escape(&self.formatted_address()))
+  }
+
+  /// Alert for new `slots` at this center, listing the soonest
+  /// `MAX_LISTED_SLOTS` times on their own bullets when there is more than one.
+  pub fn appointment_avaliable_msg(&self, slots: &[&Slot], user_data: &UserData) -> String {
+    let service = user_data.service_for(self.id);
+    let (title, times) = match slots {
+      [slot] => ("Appointment Avaliable", escape(&slot.describe(Some(self.tz())))),
+      slots => {
+        let mut slots = slots.to_vec();
+        slots.sort_by(|a, b| a.start_timestamp.cmp(&b.start_timestamp));
+        let mut times = slots
+          .iter()
+          .take(MAX_LISTED_SLOTS)
+          .map(|x| escape(&format!("• {}", x.describe(Some(self.tz())).trim())))
+          .collect::<Vec<_>>();
+        if slots.len() > MAX_LISTED_SLOTS {
+          times.push(escape(&format!(
+            "…and {} more times available",
+            slots.len() - MAX_LISTED_SLOTS
+          )));
+        }
+        ("Appointments Avaliable", times.join("\n"))
+      },
+    };
+    let address = if user_data.map_link {
+      self.map_link()
+    } else {
+      escape(&self.formatted_address())
+    };
+    let mut msg = format!(
+      "{} for {}{}\n{}\n{}\n[Schedule Appointment]({})",
+      title,
+      escape(&self.full_name),
+      service_suffix(service),
+      address,
+      times,
+      self.booking_url(service)
+    );
+    if let Some(url) = self.directions_url(user_data.home.as_deref()) {
+      msg.push_str(" \\| ");
+      msg.push_str(&link(&url, "Directions"));
+    }
+    msg
+  }
+
+  /// MarkdownV2 reply to `/slots` listing the soonest `MAX_LISTED_SLOTS`
+  /// open `slots` that can still be attended, linking to `service`'s booking.
+  pub fn slots_msg(&self, slots: &[Slot], service: Service) -> String {
+    let mut slots = upcoming_slots(slots, self.now(), CONFIG.min_lead_time);
+    if slots.is_empty() {
+      return escape(&format!("No appointments found at {}.", self.full_name));
+    }
+    slots.sort_by(|a, b| a.start_timestamp.cmp(&b.start_timestamp));
+    let times = slots
+      .iter()
+      .take(MAX_LISTED_SLOTS)
+      .map(|x| escape(&format_slot_time(&x.start_timestamp, Some(self.tz()))))
+      .collect::<Vec<_>>();
+    format!(
+      "Appointments Avaliable for {}{}\n{}\n[Schedule Appointment]({})",
+      escape(&self.full_name),
+      service_suffix(service),
+      times.join("\n"),
+      self.booking_url(service)
+    )
+  }
+
+  fn volatile_slot_msg(&self, slot: &Slot, reopen_count: u32, service: Service) -> String {
+    let timeslot = slot.describe(Some(self.tz()));
     format!(
-      "Appointment Avaliable for {}\n{}\n[Schedule Appointment]({})",
-      self.full_name, timeslot, link
+      "⚡ *Frequently Reopening Appointment* at {}{}\n{}\n{}\n[Schedule Appointment]({})",
+      escape(&self.full_name),
+      service_suffix(service),
+      escape(timeslot.trim()),
+      escape(&format!(
+        "This slot has reopened {} times, it may be gone again soon.",
+        reopen_count
+      )),
+      self.booking_url(service)
     )
   }
 }
 
-#[derive(Deserialize)]
+/// Names the service after a center in alerts, unless it is plain NEXUS.
+pub fn service_suffix(service: Service) -> String {
+  match service {
+    Service::Nexus => String::new(),
+    service => escape(&format!(" ({})", service.name())),
+  }
+}
+
+/// A slot's start time as shown to users, with the zone abbreviation when
+/// the center's timezone is known, e.g. "10:30 AM PST on Tuesday March 4".
+pub fn format_slot_time(start_timestamp: &str, tz: Option<Tz>) -> String {
+  let start = match NaiveDateTime::parse_from_str(start_timestamp, "%Y-%m-%dT%H:%M") {
+    Ok(start) => start,
+    Err(_) => return start_timestamp.to_string(),
+  };
+  let formatted = match tz.and_then(|tz| tz.from_local_datetime(&start).earliest()) {
+    Some(start) => start.format("%l:%M %p %Z on %A %B %-d").to_string(),
+    None => start.format("%l:%M %p on %A %B %-d").to_string(),
+  };
+  formatted.trim().to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CentersFormat {
+  Toml,
+  Json,
+}
+
+impl CentersFormat {
+  /// `.json` files are read as JSON, everything else as TOML.
+  pub fn from_path(path: &Path) -> Self {
+    match path.extension() {
+      Some(x) if x == "json" => Self::Json,
+      _ => Self::Toml,
+    }
+  }
+}
+
+/// A named group of centers that can be tracked as one subscription. Members
+/// are listed by short name.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Region {
+  pub name: String,
+  pub centers: Vec<String>,
+}
+
+impl Region {
+  pub fn members<'a>(&self, centers: &'a [Center]) -> Vec<&'a Center> {
+    self.centers.iter().filter_map(|x| resolve_center(centers, x)).collect()
+  }
+
+  /// MarkdownV2 line naming the region and its members for `/status` and
+  /// `/list`.
+  pub fn status_line(&self, centers: &[Center]) -> String {
+    let members = self
+      .members(centers)
+      .iter()
+      .map(|x| x.short_name.as_str())
+      .collect::<Vec<_>>();
+    format!("`{}` {}", escape(&self.name), escape(&members.join(", ")))
+  }
+}
+
+/// Finds the region named `query`.
+pub fn resolve_region<'a>(regions: &'a [Region], query: &str) -> Option<&'a Region> {
+  let query = normalize_name(query);
+  regions.iter().find(|x| normalize_name(&x.name) == query)
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct CentersConfig {
   pub centers: Vec<Center>,
+  #[serde(default)]
+  pub regions: Vec<Region>,
+}
+
+impl CentersConfig {
+  /// Deserializes an unvalidated center list.
+  pub fn from_str(contents: &str, format: CentersFormat) -> Result<Self, String> {
+    match format {
+      CentersFormat::Toml => toml::from_str(contents).map_err(|err| err.to_string()),
+      CentersFormat::Json => serde_json::from_str(contents).map_err(|err| err.to_string()),
+    }
+  }
+
+  /// Serializes the center list for a centers file in `format`.
+  pub fn to_contents(&self, format: CentersFormat) -> Result<String, String> {
+    match format {
+      CentersFormat::Toml => toml::to_string_pretty(self).map_err(|err| err.to_string()),
+      CentersFormat::Json => serde_json::to_string_pretty(self).map_err(|err| err.to_string()),
+    }
+  }
+
+  /// Loads the named main centers file merged with every `*.toml` and
+  /// `*.json` file in `dir`, if it exists. Files in `dir` are merged in
+  /// filename order.
+  pub fn load(main: (&str, &str), dir: &Path) -> Result<Self, String> {
+    let mut sources = Vec::new();
+    let mut errors = Vec::new();
+
+    let (main_name, main) = main;
+    match Self::from_str(main, CentersFormat::from_path(Path::new(main_name))) {
+      Ok(config) => sources.push((main_name.to_string(), config)),
+      Err(err) => errors.push(format!("{}: {}", main_name, err)),
+    }
+
+    if dir.is_dir() {
+      let mut paths = fs::read_dir(dir)
+        .map_err(|err| format!("{}: {}", dir.display(), err))?
+        .filter_map(|entry| entry.ok().map(|x| x.path()))
+        .filter(|path| path.extension().is_some_and(|x| x == "toml" || x == "json"))
+        .collect::<Vec<_>>();
+      paths.sort();
+
+      for path in paths {
+        let name = path.display().to_string();
+        match fs::read_to_string(&path) {
+          Ok(contents) => match Self::from_str(&contents, CentersFormat::from_path(&path)) {
+            Ok(config) => sources.push((name, config)),
+            Err(err) => errors.push(format!("{}: {}", name, err)),
+          },
+          Err(err) => errors.push(format!("{}: {}", name, err)),
+        }
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(errors.join("\n"));
+    }
+
+    Self::merge(sources)
+  }
+
+  /// Combines named center lists in the given order, rejecting center ids
+  /// that are defined more than once.
+  pub fn merge(sources: Vec<(String, CentersConfig)>) -> Result<Self, String> {
+    let mut origins: HashMap<CenterId, String> = HashMap::new();
+    let mut region_origins: HashMap<String, String> = HashMap::new();
+    let mut centers = Vec::new();
+    let mut regions = Vec::new();
+    let mut errors = Vec::new();
+
+    for (name, config) in sources {
+      for region in config.regions {
+        match region_origins.get(&normalize_name(&region.name)) {
+          Some(other) => errors.push(format!(
+            "region `{}` in {} is already defined in {}",
+            region.name, name, other
+          )),
+          None => {
+            region_origins.insert(normalize_name(&region.name), name.clone());
+            regions.push(region);
+          },
+        }
+      }
+      for center in config.centers {
+        match origins.get(&center.id) {
+          Some(other) => errors.push(format!(
+            "center {} (`{}`) in {} is already defined in {}",
+            center.id, center.short_name, name, other
+          )),
+          None => {
+            origins.insert(center.id, name.clone());
+            centers.push(center);
+          },
+        }
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(errors.join("\n"));
+    }
+
+    let config = Self { centers, regions };
+    config.validate(&origins)?;
+    Ok(config)
+  }
+
+  /// Checks the merged centers, naming the file each clashing center came
+  /// from as given by `origins`.
+  fn validate(&self, origins: &HashMap<CenterId, String>) -> Result<(), String> {
+    let describe = |center: &Center| match origins.get(&center.id) {
+      Some(origin) => format!("`{}` ({} in {})", center.short_name, center.id, origin),
+      None => format!("`{}` ({})", center.short_name, center.id),
+    };
+
+    for center in self.centers.iter() {
+      if center.services.is_empty() {
+        return Err(format!("`{}` lists no services", center.short_name));
+      }
+      if let Some(timezone) = &center.timezone {
+        if timezone.parse::<Tz>().is_err() {
+          return Err(format!(
+            "timezone `{}` of `{}` is not an IANA timezone name",
+            timezone, center.short_name
+          ));
+        }
+      }
+      if let Some(url) = &center.booking_url {
+        if !matches!(url.parse::<Uri>(), Ok(uri) if uri.scheme_str() == Some("https") && uri.host().is_some()) {
+          return Err(format!(
+            "booking_url `{}` of `{}` is not a valid https url",
+            url, center.short_name
+          ));
+        }
+      }
+    }
+
+    let mut short_names: HashMap<String, &Center> = HashMap::new();
+    for center in self.centers.iter() {
+      if let Some(other) = short_names.insert(normalize_name(&center.short_name), center) {
+        return Err(format!(
+          "short names {} and {} are not unique",
+          describe(other),
+          describe(center)
+        ));
+      }
+    }
+
+    let mut aliases: HashMap<String, &Center> = HashMap::new();
+    for center in self.centers.iter() {
+      for alias in center.aliases.iter() {
+        let alias = normalize_name(alias);
+        if let Some(other) = short_names.get(&alias).filter(|other| other.id != center.id) {
+          return Err(format!(
+            "alias `{}` of {} collides with the short name of {}",
+            alias,
+            describe(center),
+            describe(other)
+          ));
+        }
+        if let Some(other) = aliases
+          .insert(alias.clone(), center)
+          .filter(|other| other.id != center.id)
+        {
+          return Err(format!(
+            "alias `{}` is used by both {} and {}",
+            alias,
+            describe(other),
+            describe(center)
+          ));
+        }
+      }
+    }
+
+    for region in self.regions.iter() {
+      let name = normalize_name(&region.name);
+      if let Some(other) = short_names.get(&name).or_else(|| aliases.get(&name)) {
+        return Err(format!(
+          "region `{}` collides with the center `{}`",
+          region.name, other.short_name
+        ));
+      }
+      for member in region.centers.iter() {
+        if !short_names.contains_key(&normalize_name(member)) {
+          return Err(format!("region `{}` lists unknown center `{}`", region.name, member));
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Normalizes user supplied or configured names for comparison by trimming,
+/// collapsing interior whitespace and case folding.
+pub fn normalize_name(name: &str) -> String {
+  name
+    .split_whitespace()
+    .collect::<Vec<_>>()
+    .join(" ")
+    .to_lowercase()
+    .replace('ß', "ss")
+    .replace('ς', "σ")
+}
+
+/// Outcome of looking up a center by a possibly partial or misspelled name.
+pub enum CenterMatch<'a> {
+  Found(&'a Center),
+  /// Every center whose short or full name starts with the query.
+  Ambiguous(Vec<&'a Center>),
+  /// No match, with the closest short names as suggestions.
+  Missing(Vec<&'a Center>),
+}
+
+impl<'a> CenterMatch<'a> {
+  pub fn center(&self) -> Option<&'a Center> {
+    match self {
+      Self::Found(center) => Some(center),
+      _ => None,
+    }
+  }
+}
+
+/// Looks up `query` like [`resolve_center`], then as a numeric location id,
+/// then as a unique prefix of a short or full name, and otherwise suggests the
+/// centers whose short or full name is closest.
+pub fn match_center<'a>(centers: &'a [Center], query: &str) -> CenterMatch<'a> {
+  if let Some(center) = resolve_center(centers, query) {
+    return CenterMatch::Found(center);
+  }
+  if let Ok(id) = query.trim().parse::<CenterId>() {
+    return match centers.iter().find(|x| x.id == id) {
+      Some(center) => CenterMatch::Found(center),
+      None => CenterMatch::Missing(Vec::new()),
+    };
+  }
+  let query = normalize_name(query);
+  if query.is_empty() {
+    return CenterMatch::Missing(Vec::new());
+  }
+
+  let prefixed = centers
+    .iter()
+    .filter(|x| normalize_name(&x.short_name).starts_with(&query) || normalize_name(&x.full_name).starts_with(&query))
+    .collect::<Vec<_>>();
+  match prefixed.as_slice() {
+    [center] => CenterMatch::Found(center),
+    [] => {
+      let max_distance = (query.chars().count() / 3).max(2);
+      let mut close = centers
+        .iter()
+        .map(|x| {
+          let distance = edit_distance(&query, &normalize_name(&x.short_name))
+            .min(edit_distance(&query, &normalize_name(&x.full_name)));
+          (distance, x)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+      close.sort_by_key(|(distance, _)| *distance);
+      CenterMatch::Missing(close.into_iter().take(3).map(|(_, x)| x).collect())
+    },
+    _ => CenterMatch::Ambiguous(prefixed),
+  }
+}
+
+/// Levenshtein distance between two names.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b = b.chars().collect::<Vec<_>>();
+  let mut row = (0..=b.len()).collect::<Vec<_>>();
+  for (i, x) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, y) in b.iter().enumerate() {
+      let above = row[j + 1];
+      row[j + 1] = (diagonal + usize::from(x != *y)).min(row[j] + 1).min(above + 1);
+      diagonal = above;
+    }
+  }
+  row[b.len()]
+}
+
+/// Finds the center referred to by `query`, shared by all commands taking a
+/// center argument. Short names take precedence over aliases.
+pub fn resolve_center<'a>(centers: &'a [Center], query: &str) -> Option<&'a Center> {
+  let query = normalize_name(query);
+  centers
+    .iter()
+    .find(|x| normalize_name(&x.short_name) == query)
+    .or_else(|| {
+      centers
+        .iter()
+        .find(|x| x.aliases.iter().any(|alias| normalize_name(alias) == query))
+    })
+}
+
+pub const UNKNOWN_STATE: &str = "Other";
+
+/// Groups centers by state, ordering states alphabetically with centers of
+/// unknown state last, and centers by short name within each state.
+pub fn group_centers_by_state<'a>(centers: impl IntoIterator<Item = &'a Center>) -> Vec<(String, Vec<&'a Center>)> {
+  let mut groups: BTreeMap<(bool, String), Vec<&Center>> = BTreeMap::new();
+
+  for center in centers {
+    let key = center
+      .state()
+      .map_or_else(|| (true, UNKNOWN_STATE.to_string()), |state| (false, state));
+    groups.entry(key).or_default().push(center);
+  }
+
+  groups
+    .into_iter()
+    .map(|((_, state), mut centers)| {
+      centers.sort_by(|a, b| a.short_name.cmp(&b.short_name));
+      (state, centers)
+    })
+    .collect()
+}
+
+/// Renders one MarkdownV2 section per state, each a bold header followed by
+/// its centers.
+pub fn render_center_groups<'a>(
+  centers: impl IntoIterator<Item = &'a Center>,
+  closed: &HashSet<CenterId>,
+) -> Vec<String> {
+  group_centers_by_state(centers)
+    .into_iter()
+    .map(|(state, centers)| {
+      let lines = centers.iter().map(|x| x.status_line(closed)).collect::<Vec<_>>();
+      format!("*{}*\n{}", escape(&state), lines.join("\n"))
+    })
+    .collect()
+}
+
+/// Most characters Telegram accepts in one message.
+pub const MESSAGE_LIMIT: usize = 4096;
+
+/// Packs blank line separated `sections` into as few messages of at most
+/// `limit` characters as possible, splitting sections that don't fit on their
+/// own between lines.
+pub fn paginate(sections: Vec<String>, limit: usize) -> Vec<String> {
+  let mut pages = Vec::new();
+  let mut page = String::new();
+  let mut push = |page: &mut String, text: &str, separator: &str| {
+    if !page.is_empty() && page.chars().count() + separator.len() + text.chars().count() > limit {
+      pages.push(std::mem::take(page));
+    }
+    if !page.is_empty() {
+      page.push_str(separator);
+    }
+    page.push_str(text);
+  };
+
+  for section in sections {
+    if section.chars().count() <= limit {
+      push(&mut page, &section, "\n\n");
+    } else {
+      for (i, line) in section.lines().enumerate() {
+        push(&mut page, line, if i == 0 { "\n\n" } else { "\n" });
+      }
+    }
+  }
+  if !page.is_empty() {
+    pages.push(page);
+  }
+  pages
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -59,163 +892,2343 @@ pub struct CentersConfig {
 pub struct Slot {
   pub location_id: u32,
   pub start_timestamp: String,
+  #[serde(default)]
+  pub end_timestamp: Option<String>,
+  /// The scheduler lists some slots that can't be booked with `active` 0.
+  #[serde(default)]
+  pub active: Option<u32>,
+  /// Length of the appointment in minutes.
+  #[serde(default)]
+  pub duration: Option<u32>,
+  #[serde(default)]
+  pub remote_ind: bool,
 }
 
-type ScheduleSlots = Vec<Slot>;
+impl Slot {
+  pub fn start(&self) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(&self.start_timestamp, "%Y-%m-%dT%H:%M").ok()
+  }
 
-#[derive(Debug, Clone)]
-enum CollectorMessage {
-  RequestSlotsForCenter(CenterId),
-  NotifyUsersOf(CenterId, Vec<Slot>),
-  Stop,
+  pub fn is_bookable(&self) -> bool {
+    self.active != Some(0)
+  }
+
+  /// The slot time as shown in alerts, with its length when known.
+  fn describe(&self, tz: Option<Tz>) -> String {
+    let timeslot = format_slot_time(&self.start_timestamp, tz);
+    match self.duration {
+      Some(duration) => format!("{} ({} min)", timeslot, duration),
+      None => timeslot,
+    }
+  }
 }
 
-pub struct CenterDataCollectorTask {
-  next_collection_time: Option<Instant>,
-  tx: Sender<CollectorMessage>,
+pub type ScheduleSlots = Vec<Slot>;
+
+/// The `slots` starting at least `lead` after `now`, in the center's time.
+/// Slots with a start time that can't be parsed are dropped too.
+pub fn upcoming_slots(slots: &[Slot], now: NaiveDateTime, lead: Duration) -> Vec<Slot> {
+  let earliest = now + chrono::Duration::seconds(lead.as_secs() as i64);
+  slots
+    .iter()
+    .filter(|x| x.start().is_some_and(|start| start >= earliest))
+    .cloned()
+    .collect()
 }
 
-impl CenterDataCollectorTask {
-  pub fn new(http_client: Client<HttpsConnector<HttpConnector>>, bot: AutoSend<Bot>) -> Self {
-    let (tx, rx) = mpsc::channel();
-    CenterDataCollectorTask::spawn_worker_thread(http_client, bot, tx.clone(), rx);
-    Self {
-      next_collection_time: None,
-      tx,
-    }
+/// Splits `slots` into those on open days and those on days `center` is
+/// configured or known to be closed.
+pub async fn split_closed(center: CenterId, slots: ScheduleSlots) -> (ScheduleSlots, ScheduleSlots) {
+  let mut closures = MANAGER.lock().await.as_ref().unwrap().get_closures(center);
+  if let Some(config) = center_lut().get(&center) {
+    closures.extend(config.closures.iter().copied());
   }
+  slots
+    .into_iter()
+    .partition(|slot| !matches!(slot.start(), Some(start) if closures.iter().any(|x| x.contains(start.date()))))
+}
 
-  fn spawn_worker_thread(
-    http_client: Client<HttpsConnector<HttpConnector>>,
-    bot: AutoSend<Bot>,
-    tx: Sender<CollectorMessage>,
-    rx: Receiver<CollectorMessage>,
-  ) {
-    thread::spawn(move || {
-      tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(async {
-          info!("Async Worker Thread Started");
-          loop {
-            while let Ok(msg) = rx.recv() {
-              info!("Message {:?} Received", msg.clone());
-              match msg {
-                CollectorMessage::RequestSlotsForCenter(center) => {
-                  let uri: Uri = format!(
-                    "https://ttp.cbp.dhs.gov/schedulerapi/slots?orderBy=soonest&limit=5&locationId={}",
-                    center
-                  )
-                  .parse()
-                  .unwrap();
-
-                  let resp = http_client.get(uri).await;
-                  if let Ok(resp) = resp {
-                    let data: Result<ScheduleSlots, _> =
-                      serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap());
-                    if let Ok(data) = data {
-                      if data.len() > 0 {
-                        if let Err(err) = tx.send(CollectorMessage::NotifyUsersOf(center, data)) {
-                          warn!("Failed to send channel message {}", err);
-                        }
-                      } else {
-                        info!("No slots avaliable for {}", center);
-                      }
-                    } else {
-                      warn!("Failed to parse data: {}", data.unwrap_err())
-                    }
-                  } else {
-                    warn!("Failed to contact endpoint")
-                  }
-                },
-                CollectorMessage::NotifyUsersOf(center_id, slots) => {
-                  let mut lock = MANAGER.lock().await;
-                  let centers = lock.as_mut().unwrap().get_center_subscribers();
-                  if slots.len() > 0 {
-                    if let Some(users) = centers.get(&center_id) {
-                      for user in users {
-                        let user_data = lock.as_mut().unwrap().get_user_data(*user).await;
-                        if user_data.is_ok() {
-                          if let Some(user_data) = user_data.unwrap() {
-                            for slot in slots.iter() {
-                              let timeslot = NaiveDateTime::parse_from_str(&slot.start_timestamp, "%Y-%m-%dT%H:%M").unwrap();
-                              let arrival = NaiveDate::from_ymd(2023, 2, 1);
-                              let leave = NaiveDate::from_ymd(2023, 3, 1);
-                              if timeslot.date() >= arrival && timeslot.date() <= leave {
-                                if let Err(err) = bot
-                                  .send_message(
-                                    Recipient::Id(ChatId(user_data.chat_id)),
-                                    CENTER_LUT[&slot.location_id].appointment_avaliable_msg(slot),
-                                  )
-                                  .parse_mode(ParseMode::MarkdownV2)
-                                  .await
-                                {
-                                  warn!("Failed to send bot message {}", err);
-                                }     
-                              }
-                            }
-                          }
-                        }
-                      }
-                    } else {
-                      info!("Center {} has no subscribers", center_id);
-                    }
-                  } else {
-                    warn!("Empty slot was messaged!");
-                  }
-                },
-                CollectorMessage::Stop => return,
-              }
-            }
+/// Runs `request` until it succeeds or has failed `attempts` times, waiting
+/// `delay` after the first failure and doubling the wait after each one
+/// after that. `request` is passed the attempt number, starting at 1.
+pub async fn retry<T, F, Fut>(attempts: u32, delay: Duration, mut request: F) -> Result<T, String>
+where
+  F: FnMut(u32) -> Fut,
+  Fut: Future<Output = Result<T, String>>,
+{
+  let mut attempt = 1;
+  loop {
+    match request(attempt).await {
+      Ok(value) => return Ok(value),
+      Err(err) if attempt >= attempts => return Err(format!("{} (after {} attempts)", err, attempt)),
+      Err(_) => {
+        tokio::time::sleep(delay * 2u32.pow(attempt - 1)).await;
+        attempt += 1;
+      },
+    }
+  }
+}
 
-            thread::sleep(Duration::from_secs(1));
-          }
-        });
-    });
+/// Fetches the soonest slots for a center, reusing a recent response from
+/// [`SLOT_CACHE`] so bursts of requests for the same center only hit the
+/// scheduler once.
+pub async fn fetch_slots(http_client: &HttpsClient, api_base: &str, center: CenterId) -> Result<ScheduleSlots, String> {
+  if let Some(slots) = SLOT_CACHE.lock().await.get(&center) {
+    info!(center_id = center, "Using cached slots");
+    return Ok(slots);
   }
+
+  let timer = metrics::FETCH_DURATION.start_timer();
+  let result = retry(FETCH_ATTEMPTS, FETCH_RETRY_DELAY, |attempt| {
+    if attempt > 1 {
+      debug!(center_id = center, attempt, "Retrying slot request");
+    }
+    request_slots(http_client, api_base, center)
+  })
+  .await;
+  timer.observe_duration();
+  let outcome = if result.is_ok() { "success" } else { "failure" };
+  metrics::POLLS.with_label_values(&[&center.to_string(), outcome]).inc();
+
+  let slots = result?;
+  SLOT_CACHE.lock().await.insert(center, slots.clone());
+  Ok(slots)
 }
 
-impl Drop for CenterDataCollectorTask {
-  fn drop(&mut self) {
-    info!("Stopping CenterDataCollectorTask...");
-    if self.tx.send(CollectorMessage::Stop).is_err() {
-      warn!("Failed to send stop command. Worker Thread may not exit nicely.")
+/// `path` on the scheduler api at `api_base`, usually the configured one.
+fn api_url(api_base: &str, path: &str) -> String {
+  format!("{}{}", api_base.trim_end_matches('/'), path)
+}
+
+/// Requests the soonest slots of `center` from the scheduler, bypassing the cache.
+pub async fn request_slots<C: Connect + Clone + Send + Sync + 'static>(
+  http_client: &Client<C>,
+  api_base: &str,
+  center: CenterId,
+) -> Result<ScheduleSlots, String> {
+  let uri: Uri = api_url(
+    api_base,
+    &format!(
+      "/slots?orderBy=soonest&limit={}&locationId={}",
+      CONFIG.slot_limit, center
+    ),
+  )
+  .parse()
+  .map_err(|err| format!("Invalid slots url: {}", err))?;
+
+  let resp = http_client
+    .get(uri)
+    .await
+    .map_err(|err| format!("Failed to contact endpoint: {}", err))?;
+  let body = hyper::body::to_bytes(resp.into_body())
+    .await
+    .map_err(|err| format!("Failed to read response: {}", err))?;
+  let slots: ScheduleSlots = serde_json::from_slice(&body).map_err(|err| format!("Failed to parse data: {}", err))?;
+  Ok(slots.into_iter().filter(Slot::is_bookable).collect())
+}
+
+const LOCATIONS_PATH: &str = "/locations/?serviceName=NEXUS";
+const CENTERS_PATH: &str = "/locations/?temporary=false&inviteOnly=false&operational=true&serviceName=NEXUS";
+const FETCH_ATTEMPTS: u32 = 3;
+const FETCH_RETRY_DELAY: Duration = Duration::from_millis(200);
+const CENTER_STATUS_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Longest the alerts for one poll of a center wait out Telegram flood
+/// control in total before the rest give up on rate limited sends.
+const FLOOD_WAIT_BUDGET: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+  pub id: CenterId,
+  #[serde(default)]
+  pub operational: bool,
+  #[serde(default)]
+  pub name: String,
+  #[serde(default)]
+  pub short_name: String,
+  #[serde(default)]
+  pub address: String,
+  #[serde(default)]
+  pub city: String,
+  #[serde(default)]
+  pub state: String,
+  #[serde(default)]
+  pub postal_code: String,
+  #[serde(default)]
+  pub country_code: String,
+  #[serde(default)]
+  pub phone_number: String,
+  #[serde(default)]
+  pub services: Vec<LocationService>,
+  #[serde(default)]
+  pub tz_data: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LocationService {
+  pub name: String,
+}
+
+impl Location {
+  /// The center this location describes, named by its id when the api has no
+  /// short name for it.
+  pub fn to_center(&self) -> Option<Center> {
+    let full_name = self.name.trim();
+    if full_name.is_empty() {
+      return None;
     }
+    let short_name = match self.short_name.trim() {
+      "" => self.id.to_string(),
+      short_name => short_name.to_string(),
+    };
+    let region = format!("{} {}", self.state.trim(), self.postal_code.trim());
+    let address = [self.address.trim(), self.city.trim(), region.trim()]
+      .into_iter()
+      .filter(|x| !x.is_empty())
+      .collect::<Vec<_>>()
+      .join(", ");
+    let non_empty = |x: &str| Some(x.trim().to_string()).filter(|x| !x.is_empty());
+
+    Some(Center {
+      id: self.id,
+      short_name,
+      full_name: full_name.to_string(),
+      address,
+      country: Country::from_code(&self.country_code).unwrap_or_default(),
+      state: non_empty(&self.state),
+      aliases: Vec::new(),
+      services: self
+        .services
+        .iter()
+        .filter_map(|x| Service::from_filter(&x.name))
+        .collect(),
+      latitude: None,
+      longitude: None,
+      hours: None,
+      phone: non_empty(&self.phone_number),
+      enabled: true,
+      closures: Vec::new(),
+      booking_url: None,
+      timezone: Some(self.tz_data.trim().to_string()).filter(|x| x.parse::<Tz>().is_ok()),
+    })
   }
 }
 
-impl Future for CenterDataCollectorTask {
-  type Output = ();
+/// Requests `path` of the locations api.
+async fn request_locations<C: Connect + Clone + Send + Sync + 'static>(
+  http_client: &Client<C>,
+  api_base: &str,
+  path: &str,
+) -> Result<Vec<Location>, String> {
+  let uri: Uri = api_url(api_base, path)
+    .parse()
+    .map_err(|err| format!("Invalid locations url: {}", err))?;
+  let resp = http_client
+    .get(uri)
+    .await
+    .map_err(|err| format!("Failed to contact endpoint: {}", err))?;
+  let body = hyper::body::to_bytes(resp.into_body())
+    .await
+    .map_err(|err| format!("Failed to read response: {}", err))?;
+  serde_json::from_slice(&body).map_err(|err| format!("Failed to parse data: {}", err))
+}
 
-  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-    if self.next_collection_time.is_none() || Instant::now() >= self.next_collection_time.unwrap() {
-      info!("Starting work!");
-      self.next_collection_time = Some(Instant::now() + Duration::from_secs(15));
-
-      if let Ok(mut lock) = MANAGER.try_lock() {
-        let centers = lock.as_mut().unwrap().get_center_subscribers();
-        info!("Centers to check {:?}", centers);
-        centers.keys().for_each(|&x| {
-          if let Err(err) = self.tx.send(CollectorMessage::RequestSlotsForCenter(x)) {
-            warn!("Failed to queue work message for center id {}: {}", x, err);
-          }
-        });
-      } else {
-        warn!("Failed to acquire lock, trying again shortly");
-        self.next_collection_time = Some(Instant::now() + Duration::from_secs(1));
-      }
-    }
+/// Requests every operational, public enrollment center from the locations api.
+pub async fn fetch_centers<C: Connect + Clone + Send + Sync + 'static>(
+  http_client: &Client<C>,
+  api_base: &str,
+) -> Result<Vec<Center>, String> {
+  Ok(
+    request_locations(http_client, api_base, CENTERS_PATH)
+      .await?
+      .iter()
+      .filter_map(Location::to_center)
+      .collect(),
+  )
+}
 
-    let waker = cx.waker().clone();
-    let when = self.next_collection_time.clone().unwrap();
-    thread::spawn(move || {
-      let dur = when - Instant::now();
-      info!("Sleeping for {} seconds", dur.as_secs());
-      thread::sleep(dur);
-      waker.wake();
-    });
+/// Adds the centers fetched from the locations api to the configured ones.
+/// Configured centers take precedence, and a fetched center whose short name
+/// is already taken is named by its id instead.
+pub fn merge_fetched_centers(configured: &[Center], fetched: Vec<Center>) -> Vec<Center> {
+  let mut centers = configured.to_vec();
+  let mut ids = centers.iter().map(|x| x.id).collect::<HashSet<_>>();
+  let mut names = centers
+    .iter()
+    .flat_map(|x| x.aliases.iter().chain([&x.short_name]).map(|x| normalize_name(x)))
+    .collect::<HashSet<_>>();
+
+  for mut center in fetched {
+    if !ids.insert(center.id) {
+      continue;
+    }
+    if !names.insert(normalize_name(&center.short_name)) {
+      center.short_name = center.id.to_string();
+      names.insert(center.short_name.clone());
+    }
+    centers.push(center);
+  }
+  centers
+}
+
+/// Configured centers the locations API reports as not currently operational.
+pub fn closed_centers(locations: &[Location], centers: &HashMap<CenterId, Center>) -> HashSet<CenterId> {
+  locations
+    .iter()
+    .filter(|x| !x.operational && centers.contains_key(&x.id))
+    .map(|x| x.id)
+    .collect()
+}
+
+/// A message the collector is going to send to a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingNotification {
+  pub chat_id: i64,
+  pub text: String,
+  pub markdown: bool,
+  /// Subscriber the message is for, so their subscriptions can be dropped
+  /// once the chat turns out to be unreachable.
+  #[serde(default)]
+  pub user: Option<UserId>,
+  /// Center and soonest slot start of a slot alert, recorded as the user's
+  /// last alert there once sent.
+  #[serde(default)]
+  pub alert: Option<(CenterId, NaiveDateTime)>,
+}
+
+impl PendingNotification {
+  pub fn plain(chat_id: i64, text: String) -> Self {
+    Self {
+      chat_id,
+      text,
+      markdown: false,
+      user: None,
+      alert: None,
+    }
+  }
+
+  pub fn markdown(chat_id: i64, text: String) -> Self {
+    Self {
+      chat_id,
+      text,
+      markdown: true,
+      user: None,
+      alert: None,
+    }
+  }
+
+  pub fn for_user(self, user: UserId) -> Self {
+    Self {
+      user: Some(user),
+      ..self
+    }
+  }
+
+  pub fn for_slot(self, center: CenterId, start: Option<NaiveDateTime>) -> Self {
+    Self {
+      alert: start.map(|x| (center, x)),
+      ..self
+    }
+  }
+}
+
+/// Whether a send failed because the chat is gone for good, e.g. the user
+/// blocked the bot or deleted the chat, rather than for a reason that may
+/// pass.
+pub fn is_unreachable_chat(err: &RequestError) -> bool {
+  matches!(
+    err,
+    RequestError::Api(
+      ApiError::BotBlocked
+        | ApiError::ChatNotFound
+        | ApiError::UserDeactivated
+        | ApiError::BotKicked
+        | ApiError::BotKickedFromSupergroup
+        | ApiError::CantInitiateConversation
+    )
+  )
+}
+
+/// Sends a message to a user from the collector, only logging it when
+/// running with `--dry-run`. Failures are logged here and returned so callers
+/// can react to chats that are gone.
+async fn notify(bot: &AutoSend<Bot>, notification: &PendingNotification) -> Result<(), RequestError> {
+  let chat_id = notification.chat_id;
+  if CONFIG.dry_run {
+    info!(chat_id, "Dry run, not sending: {}", notification.text);
+    metrics::NOTIFICATIONS.with_label_values(&["suppressed"]).inc();
+    return Ok(());
+  }
+
+  let mut request = bot.send_message(Recipient::Id(ChatId(chat_id)), notification.text.clone());
+  if notification.markdown {
+    request = request.parse_mode(ParseMode::MarkdownV2);
+  }
+  match request.await {
+    Ok(_) => {
+      metrics::NOTIFICATIONS.with_label_values(&["sent"]).inc();
+      Ok(())
+    },
+    Err(err) => {
+      if let RequestError::RetryAfter(_) = err {
+        metrics::FLOOD_WAITS.inc();
+      }
+      metrics::NOTIFICATIONS.with_label_values(&["failed"]).inc();
+      warn!(chat_id, "Failed to send bot message {}", err);
+      Err(err)
+    },
+  }
+}
+
+/// Sends a notification like `notify`, waiting out and retrying rate limited
+/// sends for as long as `budget` allows. The wait is taken off `budget`.
+async fn notify_with_retry(
+  bot: &AutoSend<Bot>,
+  notification: &PendingNotification,
+  budget: &mut Duration,
+) -> Result<(), RequestError> {
+  loop {
+    match notify(bot, notification).await {
+      Err(RequestError::RetryAfter(wait)) if wait <= *budget => {
+        info!(
+          chat_id = notification.chat_id,
+          wait_secs = wait.as_secs(),
+          "Rate limited by Telegram, retrying"
+        );
+        *budget -= wait;
+        tokio::time::sleep(wait).await;
+      },
+      result => return result,
+    }
+  }
+}
+
+/// Builds the alerts for slots each active subscriber of `center_id` hasn't
+/// been told about yet. Slots for users in their quiet hours are held for
+/// the digest sent once their quiet hours end.
+///
+/// Slots outside a user's date window, at snoozed centers or already alerted
+/// are dropped first. Of the rest, users with `earlier_only` only get slots
+/// sooner than the best they have seen at that center, which then becomes
+/// the soonest of those slots, whether alerted now or held for a digest.
+pub async fn slot_notifications(
+  center_id: CenterId,
+  slots: &[Slot],
+  volatile: &[(Slot, u32)],
+) -> Vec<PendingNotification> {
+  if slots.is_empty() {
+    warn!("Empty slot was messaged!");
+    return Vec::new();
+  }
+
+  let lut = center_lut();
+  let known_center = |slot: &Slot| match lut.get(&slot.location_id) {
+    Some(center) => Some(center),
+    None => {
+      warn!(
+        center_id,
+        location_id = slot.location_id,
+        "Skipping slot at an unknown center"
+      );
+      None
+    },
+  };
+  let slots = slots
+    .iter()
+    .filter_map(|slot| Some((known_center(slot)?, slot)))
+    .collect::<Vec<_>>();
+  let volatile = volatile
+    .iter()
+    .filter_map(|(slot, reopen_count)| Some((known_center(slot)?, slot, *reopen_count)))
+    .collect::<Vec<_>>();
+
+  let now = Local::now().naive_local();
+  let today = now.date();
+  // The manager is only held while working on cached data. Storage is taken
+  // before letting go of it, so requests keep their order.
+  let (users, refresh, mut storage) = {
+    let mut lock = MANAGER.lock().await;
+    let manager = lock.as_mut().unwrap();
+    let users = match manager.get_center_subscribers().get(&center_id) {
+      Some(users) => users.clone(),
+      None => {
+        info!(center_id, "Center has no subscribers");
+        return Vec::new();
+      },
+    };
+    (
+      users.clone(),
+      manager.begin_refresh(&users),
+      manager.storage().lock().await,
+    )
+  };
+  let stored = storage.get_many(&refresh.keys).await;
+  drop(storage);
+
+  let mut lock = MANAGER.lock().await;
+  let manager = lock.as_mut().unwrap();
+  let mut writes = match stored
+    .map_err(TrackingError::from)
+    .and_then(|x| manager.finish_refresh(refresh, x))
+  {
+    Ok(writes) => writes,
+    Err(err) => {
+      warn!(center_id, "Failed to refresh subscribers, using cached data: {}", err);
+      Vec::new()
+    },
+  };
+
+  let utc_now = Utc::now();
+  let mut notifications = Vec::new();
+  let mut notified = Vec::new();
+  let mut held = Vec::new();
+  let mut best_seen = Vec::new();
+  for user in users {
+    if let Some(user_data) = manager
+      .cached_user_data(user)
+      .filter(|x| x.is_active(today) && !x.muted && x.notification_mode == NotificationMode::Instant)
+    {
+      let in_window = |center: &Center, slot: &Slot| {
+        slot
+          .start()
+          .is_some_and(|x| user_data.wants_date(x.date(), center.today()))
+      };
+      let new_slots = slots
+        .iter()
+        .filter(|(center, slot)| {
+          center.offers(user_data.service_for(center.id))
+            && in_window(center, slot)
+            && !user_data.is_snoozed(center.id, now)
+            && !manager.was_notified(user, slot)
+        })
+        .filter(|(center, slot)| slot.start().is_some_and(|x| user_data.beats_best_seen(center.id, x)))
+        .collect::<Vec<_>>();
+      if user_data.earlier_only {
+        best_seen.extend(
+          new_slots
+            .iter()
+            .filter_map(|(center, slot)| Some((user, center.id, slot.start()?))),
+        );
+      }
+      if user_data.is_quiet(user_data.clock(utc_now)) {
+        held.extend(new_slots.iter().map(|(center, slot)| {
+          let entry = DigestEntry {
+            center: center.id,
+            start: slot.start_timestamp.clone(),
+          };
+          (user, entry)
+        }));
+        notified.extend(new_slots.into_iter().map(|(_, slot)| (user, (*slot).clone())));
+        continue;
+      }
+      let mut by_center: Vec<(&Center, Vec<&Slot>)> = Vec::new();
+      for (center, slot) in &new_slots {
+        match by_center.iter_mut().find(|(x, _)| x.id == center.id) {
+          Some((_, center_slots)) => center_slots.push(slot),
+          None => by_center.push((center, vec![slot])),
+        }
+      }
+      notifications.extend(by_center.into_iter().map(|(center, center_slots)| {
+        let msg = center.appointment_avaliable_msg(&center_slots, user_data);
+        let soonest = center_slots.iter().filter_map(|x| x.start()).min();
+        PendingNotification::markdown(user_data.chat_for(center.id), msg)
+          .for_user(user)
+          .for_slot(center.id, soonest)
+      }));
+      notified.extend(new_slots.into_iter().map(|(_, slot)| (user, (*slot).clone())));
+      if user_data.volatile {
+        let wanted = |(center, slot, _): &&(&Center, &Slot, u32)| {
+          center.offers(user_data.service_for(center.id))
+            && in_window(center, slot)
+            && !user_data.is_snoozed(center.id, now)
+        };
+        notifications.extend(volatile.iter().filter(wanted).map(|(center, slot, reopen_count)| {
+          let msg = center.volatile_slot_msg(slot, *reopen_count, user_data.service_for(center.id));
+          PendingNotification::markdown(user_data.chat_for(center.id), msg)
+            .for_user(user)
+            .for_slot(center.id, slot.start())
+        }));
+      }
+    }
+  }
+
+  let best_seen = manager.record_best_seen(best_seen);
+  for changes in [
+    manager.user_writes(&best_seen),
+    manager.add_to_digests(held),
+    manager.mark_notified(notified),
+  ] {
+    match changes {
+      Ok(changes) => writes.extend(changes),
+      Err(err) => warn!(center_id, "Failed to record alerted slots: {}", err),
+    }
+  }
+  if writes.is_empty() {
+    return notifications;
+  }
+  let mut storage = manager.storage().lock().await;
+  drop(lock);
+  if let Err(err) = storage.write(writes).await {
+    drop(storage);
+    warn!(center_id, "Failed to save alerted slots: {}", err);
+    MANAGER.lock().await.as_mut().unwrap().mark_unsaved(best_seen);
+  }
+  notifications
+}
+
+/// Daily digest listing the user's tracked centers that had a slot in their
+/// date window on the last poll, with the soonest one seen.
+fn daily_digest_msg(manager: &TrackingManager, user_data: &UserData, now: NaiveDateTime) -> String {
+  let lut = center_lut();
+  let recent = now - chrono::Duration::seconds(CONFIG.poll_interval.as_secs() as i64 * 2);
+  let lines = user_data
+    .tracked_centers()
+    .into_iter()
+    .filter_map(|center| {
+      let availability = manager.get_availability(center).filter(|x| x.observed_at >= recent)?;
+      let start = availability.soonest.start()?;
+      let today = lut.get(&center).map_or(now.date(), Center::today);
+      if !user_data.wants_date(start.date(), today) {
+        return None;
+      }
+      let name = lut
+        .get(&center)
+        .map_or_else(|| center.to_string(), |x| x.full_name.clone());
+      Some(format!(
+        "{}: soonest {}",
+        name,
+        format_slot_time(&availability.soonest.start_timestamp, lut.get(&center).map(Center::tz))
+      ))
+    })
+    .collect::<Vec<_>>();
+  if lines.is_empty() {
+    return "Daily digest: none of your tracked centers have openings in your window right now.".to_string();
+  }
+  format!("Daily digest, centers with openings right now:\n{}", lines.join("\n"))
+}
+
+/// Digest of the slots held during quiet hours that haven't started by
+/// `now`, or `None` when every one of them has.
+fn digest_msg(entries: &[DigestEntry], now: NaiveDateTime) -> Option<String> {
+  let lut = center_lut();
+  let lines = entries
+    .iter()
+    .filter(|x| matches!(NaiveDateTime::parse_from_str(&x.start, "%Y-%m-%dT%H:%M"), Ok(start) if start > now))
+    .map(|x| {
+      let center = lut
+        .get(&x.center)
+        .map_or_else(|| x.center.to_string(), |x| x.full_name.clone());
+      let tz = lut.get(&x.center).map(Center::tz);
+      format!("{}: {}", center, format_slot_time(&x.start, tz))
+    })
+    .collect::<Vec<_>>();
+  if lines.is_empty() {
+    return None;
+  }
+  Some(format!(
+    "While your quiet hours were on, these appointments opened up. Some may already be taken.\n{}",
+    lines.join("\n")
+  ))
+}
+
+/// Splits a user's digest by the chat each center's alerts go to.
+fn digests_by_chat(user_data: &UserData, entries: Vec<DigestEntry>) -> BTreeMap<i64, Vec<DigestEntry>> {
+  let mut chats = BTreeMap::<_, Vec<_>>::new();
+  for entry in entries {
+    chats.entry(user_data.chat_for(entry.center)).or_default().push(entry);
+  }
+  chats
+}
+
+/// Records the slot alerts among `sent` as each user's last alert at their
+/// center, for `/status`.
+async fn record_alerts(sent: &[PendingNotification]) {
+  let alerted = sent
+    .iter()
+    .filter_map(|x| {
+      let (center, slot) = x.alert?;
+      Some((x.user?, center, slot))
+    })
+    .collect::<Vec<_>>();
+  if alerted.is_empty() {
+    return;
+  }
+  let now = Local::now().naive_local();
+  let mut lock = MANAGER.lock().await;
+  if let Err(err) = lock.as_mut().unwrap().record_last_notified(alerted, now).await {
+    warn!("Failed to record last alerts: {}", err);
+  }
+}
+
+/// Sends the alerts for `slots` at `center_id` one by one with `send`, which
+/// may wait out rate limits within the budget it is given, and drops chats
+/// that keep turning out to be unreachable. The manager isn't held during
+/// sends, so commands aren't kept waiting.
+async fn alert_subscribers<F>(
+  center_id: CenterId,
+  slots: &[Slot],
+  volatile: &[(Slot, u32)],
+  unreachable: &mut HashMap<(UserId, i64), u32>,
+  mut send: F,
+) where
+  F: for<'a> FnMut(&'a PendingNotification, &'a mut Duration) -> BoxFuture<'a, Result<(), RequestError>>,
+{
+  let mut flood_budget = FLOOD_WAIT_BUDGET;
+  let mut delivered = Vec::new();
+  for notification in slot_notifications(center_id, slots, volatile).await {
+    let result = send(&notification, &mut flood_budget).await;
+    if result.is_ok() {
+      delivered.push(notification.clone());
+    }
+    let (Some(user), chat_id) = (notification.user, notification.chat_id) else {
+      continue;
+    };
+    match result {
+      Err(err) if is_unreachable_chat(&err) => {
+        let failures = unreachable.entry((user, chat_id)).or_insert(0);
+        *failures += 1;
+        if *failures < CONFIG.unreachable_limit {
+          continue;
+        }
+        unreachable.remove(&(user, chat_id));
+        match MANAGER.lock().await.as_mut().unwrap().remove_chat(user, chat_id).await {
+          Ok(dropped) => info!(
+            user_id = user,
+            chat_id, dropped, "Dropped subscriptions for a chat that can't be reached: {}", err
+          ),
+          Err(err) => warn!(user_id = user, chat_id, "Failed to drop unreachable chat: {}", err),
+        }
+      },
+      Err(_) => {},
+      Ok(()) => {
+        unreachable.remove(&(user, chat_id));
+      },
+    }
+  }
+  record_alerts(&delivered).await;
+}
+
+/// What a drain got through before its deadline.
+struct Drained {
+  /// Notifications handed to Telegram, whether or not it accepted them.
+  attempted: usize,
+  delivered: Vec<PendingNotification>,
+  /// Notifications not attempted, to retry on the next start.
+  remaining: Vec<PendingNotification>,
+}
+
+/// Sends `pending` in order with `send`, which gets the time left until
+/// `deadline` as its rate limit budget. Stops at the deadline or at a rate
+/// limit outlasting it, so every notification is either attempted or left
+/// in `remaining`.
+async fn drain_batch<F, Fut>(pending: Vec<PendingNotification>, deadline: Instant, mut send: F) -> Drained
+where
+  F: FnMut(PendingNotification, Duration) -> Fut,
+  Fut: Future<Output = Result<(), RequestError>>,
+{
+  let mut drained = Drained {
+    attempted: 0,
+    delivered: Vec::new(),
+    remaining: Vec::new(),
+  };
+  let mut pending = pending.into_iter();
+  for notification in pending.by_ref() {
+    let budget = deadline.saturating_duration_since(Instant::now());
+    if budget.is_zero() {
+      drained.remaining.push(notification);
+      break;
+    }
+    match send(notification.clone(), budget).await {
+      Err(RequestError::RetryAfter(_)) => {
+        drained.remaining.push(notification);
+        break;
+      },
+      Ok(()) => drained.delivered.push(notification),
+      Err(_) => {},
+    }
+    drained.attempted += 1;
+  }
+  drained.remaining.extend(pending);
+  drained
+}
+
+/// Sends the alerts still queued for the worker until the drain deadline, and
+/// persists whatever is left so it is retried on the next start. New polls
+/// queued before the stop are skipped.
+async fn drain(bot: &AutoSend<Bot>, rx: &mut UnboundedReceiver<CollectorMessage>) {
+  let deadline = Instant::now() + CONFIG.drain_timeout;
+  let mut pending = Vec::new();
+  while let Ok(msg) = rx.try_recv() {
+    metrics::QUEUE_DEPTH.dec();
+    if let CollectorMessage::NotifyUsersOf(center_id, slots, volatile) = msg {
+      pending.extend(slot_notifications(center_id, &slots, &volatile).await);
+    }
+  }
+
+  let drained = drain_batch(pending, deadline, |notification, mut budget| async move {
+    notify_with_retry(bot, &notification, &mut budget).await
+  })
+  .await;
+  record_alerts(&drained.delivered).await;
+
+  let persisted = drained.remaining.len();
+  if !drained.remaining.is_empty() {
+    if let Err(err) = MANAGER
+      .lock()
+      .await
+      .as_mut()
+      .unwrap()
+      .push_pending_notifications(drained.remaining)
+      .await
+    {
+      warn!("Failed to persist {} notifications for retry: {}", persisted, err);
+    }
+  }
+  info!("Drained {} sends, persisted {} for retry", drained.attempted, persisted);
+}
+
+#[derive(Debug, Clone)]
+enum CollectorMessage {
+  /// Queues the work of a poll cycle for the centers subscribed to now.
+  StartCycle(u64),
+  RequestSlots(Vec<CenterId>, u64),
+  NotifyUsersOf(CenterId, Vec<Slot>, Vec<(Slot, u32)>),
+  PromptInactiveUsers,
+  SendDigests,
+  /// Sends digest mode users their daily summary once `digest_time` passed.
+  SendDailyDigests,
+  CycleFinished(u64),
+  CheckCenterStatus,
+  Stop,
+}
+
+/// Sends `msg` to the collector worker, keeping track of the queue depth.
+fn queue(tx: &UnboundedSender<CollectorMessage>, msg: CollectorMessage) -> Result<(), SendError<CollectorMessage>> {
+  tx.send(msg)?;
+  metrics::QUEUE_DEPTH.inc();
+  Ok(())
+}
+
+pub struct CenterDataCollectorTask {
+  cycle_id: u64,
+  sleep: Pin<Box<Sleep>>,
+  next_status_check_time: Option<Instant>,
+  poll_interval: Duration,
+  tx: UnboundedSender<CollectorMessage>,
+  worker: Option<JoinHandle<()>>,
+}
+
+impl CenterDataCollectorTask {
+  /// Polls the scheduler api at `api_base`, the configured one outside of
+  /// tests.
+  pub fn new(http_client: HttpsClient, bot: AutoSend<Bot>, poll_interval: Duration, api_base: String) -> Self {
+    info!("Polling every {}s", poll_interval.as_secs());
+    let (tx, rx) = mpsc::unbounded_channel();
+    let worker = CenterDataCollectorTask::spawn_worker(http_client, bot, api_base, tx.clone(), rx);
+    Self {
+      cycle_id: 0,
+      sleep: Box::pin(tokio::time::sleep(Duration::ZERO)),
+      next_status_check_time: None,
+      poll_interval,
+      tx,
+      worker: Some(worker),
+    }
+  }
+
+  /// Stops polling and waits for the worker to drain queued notifications.
+  pub async fn shutdown(mut self) {
+    info!("Draining CenterDataCollectorTask...");
+    if queue(&self.tx, CollectorMessage::Stop).is_err() {
+      warn!("Failed to send stop command. Worker may not exit nicely.");
+      return;
+    }
+    if let Some(worker) = self.worker.take() {
+      if worker.await.is_err() {
+        warn!("Collector worker did not exit cleanly");
+      }
+    }
+  }
+
+  fn spawn_worker(
+    http_client: HttpsClient,
+    bot: AutoSend<Bot>,
+    api_base: String,
+    tx: UnboundedSender<CollectorMessage>,
+    mut rx: UnboundedReceiver<CollectorMessage>,
+  ) -> JoinHandle<()> {
+    tokio::spawn(async move {
+      info!("Collector Worker Started");
+      let pending = MANAGER
+        .lock()
+        .await
+        .as_mut()
+        .unwrap()
+        .take_pending_notifications()
+        .await;
+      if !pending.is_empty() {
+        info!(
+          "Retrying {} notifications persisted at the last shutdown",
+          pending.len()
+        );
+        let mut flood_budget = FLOOD_WAIT_BUDGET;
+        let mut delivered = Vec::new();
+        for notification in pending {
+          if notify_with_retry(&bot, &notification, &mut flood_budget).await.is_ok() {
+            delivered.push(notification);
+          }
+        }
+        record_alerts(&delivered).await;
+      }
+      let mut history = SlotHistory::default();
+      let mut breaker = CircuitBreaker::new(CONFIG.poll_interval, CONFIG.breaker_cooldown, CONFIG.breaker_threshold);
+      let mut unreachable: HashMap<(UserId, i64), u32> = HashMap::new();
+      while let Some(msg) = rx.recv().await {
+        metrics::QUEUE_DEPTH.dec();
+        info!("Message {:?} Received", msg.clone());
+        match msg {
+          CollectorMessage::RequestSlots(centers, cycle_id) => {
+            let now = Instant::now();
+            let (centers, skipped): (Vec<_>, Vec<_>) = centers.into_iter().partition(|x| breaker.allows(*x, now));
+            for center in skipped {
+              debug!(center_id = center, cycle_id, state = ?breaker.state(center), "Skipping failing center");
+            }
+            let (http_client, api_base) = (&http_client, api_base.as_str());
+            let mut fetches = stream::iter(centers)
+              .map(|center| async move { (center, fetch_slots(http_client, api_base, center).await) })
+              .buffer_unordered(CONFIG.fetch_concurrency);
+            while let Some((center, result)) = fetches.next().await {
+              match result {
+                Ok(data) => {
+                  if breaker.record_success(center) != BreakerState::Closed {
+                    info!(center_id = center, cycle_id, "Center recovered, circuit closed");
+                  }
+                  let center_now = center_lut()
+                    .get(&center)
+                    .map_or_else(|| Local::now().naive_local(), Center::now);
+                  let upcoming = upcoming_slots(&data, center_now, CONFIG.min_lead_time);
+                  if upcoming.len() < data.len() {
+                    debug!(
+                      center_id = center,
+                      cycle_id,
+                      dropped = data.len() - upcoming.len(),
+                      "Dropped past, imminent or unparseable slots"
+                    );
+                  }
+                  let (data, closed) = split_closed(center, upcoming).await;
+                  if !closed.is_empty() {
+                    metrics::CLOSURE_SLOTS_DROPPED.inc_by(closed.len() as u64);
+                    debug!(
+                      center_id = center,
+                      cycle_id,
+                      dropped = closed.len(),
+                      total = metrics::CLOSURE_SLOTS_DROPPED.get(),
+                      "Dropped slots on closure dates"
+                    );
+                  }
+                  if let Some(config) = center_lut().get(&center) {
+                    metrics::SLOTS_FOUND
+                      .with_label_values(&[&config.short_name])
+                      .inc_by(data.len() as u64);
+                  }
+                  let volatile = history.observe(center, &data, Local::now().naive_local());
+                  if let Err(err) = MANAGER
+                    .lock()
+                    .await
+                    .as_mut()
+                    .unwrap()
+                    .forget_unavailable_slots(center, &data, Local::now().naive_local())
+                    .await
+                  {
+                    warn!(center_id = center, cycle_id, "Failed to update notified slots: {}", err);
+                  }
+                  if let Some(soonest) = data.first() {
+                    MANAGER.lock().await.as_mut().unwrap().record_availability(
+                      center,
+                      Availability {
+                        observed_at: Local::now().naive_local(),
+                        soonest: soonest.clone(),
+                      },
+                    );
+                  }
+                  if !data.is_empty() {
+                    if let Err(err) = queue(&tx, CollectorMessage::NotifyUsersOf(center, data, volatile)) {
+                      warn!(center_id = center, cycle_id, "Failed to send channel message {}", err);
+                    }
+                  } else {
+                    info!(center_id = center, cycle_id, "No slots avaliable");
+                  }
+                },
+                Err(err) => {
+                  let (state, delay) = breaker.record_failure(center, now);
+                  warn!(
+                    center_id = center,
+                    cycle_id,
+                    state = ?state,
+                    retry_in_secs = delay.as_secs(),
+                    "{}",
+                    err
+                  );
+                },
+              }
+            }
+          },
+          CollectorMessage::NotifyUsersOf(center_id, slots, volatile) => {
+            alert_subscribers(center_id, &slots, &volatile, &mut unreachable, |x, budget| {
+              let bot = bot.clone();
+              Box::pin(async move { notify_with_retry(&bot, x, budget).await })
+            })
+            .await;
+          },
+          CollectorMessage::StartCycle(cycle_id) => {
+            let centers = {
+              let mut lock = MANAGER.lock().await;
+              let manager = lock.as_mut().unwrap();
+              metrics::USERS.set(manager.user_count() as i64);
+              let subscribers = manager.get_center_subscribers();
+              metrics::SUBSCRIPTIONS.set(subscribers.values().map(Vec::len).sum::<usize>() as i64);
+              let lut = center_lut();
+              let mut centers = subscribers
+                .keys()
+                .copied()
+                .filter(|x| lut.get(x).is_some_and(|x| x.enabled))
+                .collect::<Vec<_>>();
+              centers.retain(|x| !manager.get_closed_centers().contains(x));
+              centers
+            };
+            info!(cycle_id, "Centers to check {:?}", centers);
+            if !centers.is_empty() {
+              if let Err(err) = queue(&tx, CollectorMessage::RequestSlots(centers, cycle_id)) {
+                warn!(cycle_id, "Failed to queue work message: {}", err);
+              }
+            }
+            if let Err(err) = queue(&tx, CollectorMessage::PromptInactiveUsers) {
+              warn!("Failed to queue inactive user prompt: {}", err);
+            }
+            if let Err(err) = queue(&tx, CollectorMessage::SendDigests) {
+              warn!("Failed to queue quiet hour digests: {}", err);
+            }
+            if Local::now().time() >= CONFIG.digest_time {
+              if let Err(err) = queue(&tx, CollectorMessage::SendDailyDigests) {
+                warn!("Failed to queue daily digests: {}", err);
+              }
+            }
+            if let Err(err) = queue(&tx, CollectorMessage::CycleFinished(cycle_id)) {
+              warn!(cycle_id, "Failed to queue end of cycle: {}", err);
+            }
+          },
+          CollectorMessage::PromptInactiveUsers => {
+            let today = Local::now().naive_local().date();
+            let users = MANAGER.lock().await.as_mut().unwrap().get_newly_inactive_users(today);
+            for (user, user_data) in users {
+              let msg = format!(
+                "Your active period ended on {}, so notifications are paused. Use /activeuntil YYYY-MM-DD to \
+                       resume them or /activeuntil off to stay active indefinitely.",
+                user_data.active_until.unwrap()
+              );
+              let _ = notify(&bot, &PendingNotification::plain(user_data.chat_id, msg)).await;
+              if let Err(err) = MANAGER
+                .lock()
+                .await
+                .as_mut()
+                .unwrap()
+                .update_user_data(user_data.chat_id, user, |x| x.active_until_prompted = true)
+                .await
+              {
+                warn!(user_id = user, "Failed to record inactive prompt: {}", err);
+              }
+            }
+          },
+          CollectorMessage::SendDailyDigests => {
+            let now = Local::now().naive_local();
+            let digests = {
+              let lock = MANAGER.lock().await;
+              let manager = lock.as_ref().unwrap();
+              manager
+                .get_daily_digest_users(now.date())
+                .into_iter()
+                .map(|(user, user_data)| (user, user_data.chat_id, daily_digest_msg(manager, &user_data, now)))
+                .collect::<Vec<_>>()
+            };
+            for (user, chat_id, msg) in digests {
+              info!(user_id = user, "Sending daily digest");
+              let _ = notify(&bot, &PendingNotification::plain(chat_id, msg).for_user(user)).await;
+              if let Err(err) = MANAGER
+                .lock()
+                .await
+                .as_mut()
+                .unwrap()
+                .update_user_data(chat_id, user, |x| x.digest_sent = Some(now.date()))
+                .await
+              {
+                warn!(user_id = user, "Failed to record daily digest: {}", err);
+              }
+            }
+          },
+          CollectorMessage::SendDigests => {
+            let now = Local::now().naive_local();
+            let utc_now = Utc::now();
+            let mut digests = Vec::new();
+            {
+              let mut lock = MANAGER.lock().await;
+              let manager = lock.as_mut().unwrap();
+              for user in manager.digest_users() {
+                let user_data = match manager.cached_user_data(user) {
+                  Some(user_data) if user_data.is_quiet(user_data.clock(utc_now)) => continue,
+                  user_data => user_data.cloned(),
+                };
+                match manager.take_digest(user).await {
+                  Ok(entries) => digests.push((user, user_data, entries)),
+                  Err(err) => warn!(user_id = user, "Failed to take quiet hour digest: {}", err),
+                }
+              }
+            }
+            for (user, user_data, entries) in digests {
+              let Some(user_data) = user_data else {
+                continue;
+              };
+              for (chat_id, entries) in digests_by_chat(&user_data, entries) {
+                if let Some(msg) = digest_msg(&entries, now) {
+                  info!(
+                    user_id = user,
+                    chat_id,
+                    slots = entries.len(),
+                    "Sending quiet hour digest"
+                  );
+                  let _ = notify(&bot, &PendingNotification::plain(chat_id, msg).for_user(user)).await;
+                }
+              }
+            }
+          },
+          CollectorMessage::CycleFinished(cycle_id) => {
+            let mut lock = MANAGER.lock().await;
+            let manager = lock.as_mut().unwrap();
+            manager.save_unsaved().await;
+            if let Err(err) = manager.heartbeat().await {
+              warn!(cycle_id, "Failed to record heartbeat: {}", err);
+            }
+            health::COLLECTOR.mark();
+          },
+          CollectorMessage::CheckCenterStatus => {
+            let locations = match request_locations(&http_client, &api_base, LOCATIONS_PATH).await {
+              Ok(locations) => locations,
+              Err(err) => {
+                warn!("Failed to fetch center status: {}", err);
+                continue;
+              },
+            };
+
+            let lut = center_lut();
+            let mut notifications = Vec::new();
+            let mut lock = MANAGER.lock().await;
+            let manager = lock.as_mut().unwrap();
+            let (newly_closed, reopened) = manager.set_closed_centers(closed_centers(&locations, &lut)).await;
+            let subscribers = newly_closed
+              .iter()
+              .chain(reopened.iter())
+              .filter_map(|x| Some((*x, manager.get_center_subscribers().get(x)?.clone())))
+              .collect::<HashMap<_, _>>();
+
+            let notices = newly_closed
+              .iter()
+              .map(|x| (x, "is temporarily closed, alerts are paused until it reopens"))
+              .chain(reopened.iter().map(|x| (x, "has reopened, alerts will resume")));
+            for (center_id, notice) in notices {
+              // Closed centers are stored, so they may have been dropped from
+              // the known centers since. The stored set no longer has them.
+              let Some(center) = lut.get(center_id) else {
+                info!(center_id, "Dropped unknown center from the closed centers");
+                continue;
+              };
+              info!(center_id, "Center {}", notice);
+              let msg = format!("{} {}.", center.full_name, notice);
+              for user in subscribers.get(center_id).into_iter().flatten() {
+                if let Ok(Some(user_data)) = manager.get_user_data(*user).await {
+                  notifications.push(PendingNotification::plain(user_data.chat_for(*center_id), msg.clone()));
+                }
+              }
+            }
+            drop(lock);
+            for notification in notifications {
+              let _ = notify(&bot, &notification).await;
+            }
+          },
+          CollectorMessage::Stop => {
+            drain(&bot, &mut rx).await;
+            return;
+          },
+        }
+      }
+    })
+  }
+}
+
+impl Drop for CenterDataCollectorTask {
+  fn drop(&mut self) {
+    if self.worker.is_none() {
+      return;
+    }
+    info!("Stopping CenterDataCollectorTask...");
+    if queue(&self.tx, CollectorMessage::Stop).is_err() {
+      warn!("Failed to send stop command. Worker may not exit nicely.")
+    }
+  }
+}
+
+impl Future for CenterDataCollectorTask {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    while self.sleep.as_mut().poll(cx).is_ready() {
+      self.cycle_id += 1;
+      let cycle_id = self.cycle_id;
+      info!(cycle_id, "Starting work!");
+      let now = tokio::time::Instant::now();
+
+      if self.next_status_check_time.is_none() || Instant::now() >= self.next_status_check_time.unwrap() {
+        self.next_status_check_time = Some(Instant::now() + CENTER_STATUS_INTERVAL);
+        if let Err(err) = queue(&self.tx, CollectorMessage::CheckCenterStatus) {
+          warn!("Failed to queue center status check: {}", err);
+        }
+      }
+
+      let when = now + self.poll_interval;
+      self.sleep.as_mut().reset(when);
+      if let Err(err) = queue(&self.tx, CollectorMessage::StartCycle(cycle_id)) {
+        warn!(cycle_id, "Failed to queue poll cycle: {}", err);
+      }
+      info!(
+        "Sleeping for {} seconds",
+        when.saturating_duration_since(tokio::time::Instant::now()).as_secs()
+      );
+    }
 
     Poll::Pending
   }
 }
+
+#[cfg(test)]
+pub mod testing {
+  use super::*;
+
+  /// A center configured like the entries of `centers.toml`, with `extra`
+  /// added to its table.
+  pub fn center_with(id: CenterId, short_name: &str, full_name: &str, extra: &str) -> Center {
+    toml::from_str(&format!(
+      "id = {}\nshort_name = \"{}\"\nfull_name = \"{}\"\naddress = \"\"\n{}",
+      id, short_name, full_name, extra
+    ))
+    .unwrap()
+  }
+
+  pub fn center(id: CenterId, short_name: &str, full_name: &str) -> Center {
+    center_with(id, short_name, full_name, "")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::testing::{center, center_with};
+  use super::*;
+
+  const CENTERS: &str = r#"
+[[centers]]
+id = 5020
+short_name = "blaine"
+full_name = "Blaine Peace Arch Enrollment Center"
+address = ""
+aliases = ["peace arch"]
+
+[[centers]]
+id = 5021
+short_name = "bellingham"
+full_name = "Bellingham Airport"
+address = ""
+
+[[centers]]
+id = 5161
+short_name = "niagara"
+full_name = "Niagara Falls EC"
+address = ""
+
+[[regions]]
+name = "PNW"
+centers = ["blaine", "Bellingham"]
+"#;
+
+  fn fixture() -> CentersConfig {
+    CentersConfig::load(("centers.toml", CENTERS), Path::new("missing")).unwrap()
+  }
+
+  /// The ids a lookup found, with whether it was a single match.
+  fn matched(found: CenterMatch) -> (&'static str, Vec<CenterId>) {
+    match found {
+      CenterMatch::Found(center) => ("found", vec![center.id]),
+      CenterMatch::Ambiguous(centers) => ("ambiguous", centers.iter().map(|x| x.id).collect()),
+      CenterMatch::Missing(centers) => ("missing", centers.iter().map(|x| x.id).collect()),
+    }
+  }
+
+  #[test]
+  fn names_are_normalized() {
+    assert_eq!(normalize_name("  Peace \t ARCH "), "peace arch");
+    assert_eq!(normalize_name("Straße"), "strasse");
+  }
+
+  #[test]
+  fn resolve_center_ignores_case_and_whitespace() {
+    let centers = fixture().centers;
+    for query in ["blaine", "BLAINE", " Blaine  ", "peace   arch", "Peace Arch"] {
+      assert_eq!(resolve_center(&centers, query).map(|x| x.id), Some(5020), "{}", query);
+    }
+    assert!(resolve_center(&centers, "bla").is_none());
+  }
+
+  #[test]
+  fn match_center_accepts_ids_and_unique_prefixes() {
+    let centers = fixture().centers;
+    assert_eq!(matched(match_center(&centers, "5161")), ("found", vec![5161]));
+    assert_eq!(matched(match_center(&centers, " 5021 ")), ("found", vec![5021]));
+    assert_eq!(matched(match_center(&centers, "9999")), ("missing", vec![]));
+    assert_eq!(matched(match_center(&centers, "nia")), ("found", vec![5161]));
+    assert_eq!(matched(match_center(&centers, "Bellingham Air")), ("found", vec![5021]));
+    assert_eq!(matched(match_center(&centers, "b")), ("ambiguous", vec![5020, 5021]));
+  }
+
+  #[test]
+  fn match_center_suggests_close_names() {
+    let centers = fixture().centers;
+    assert_eq!(matched(match_center(&centers, "blane")), ("missing", vec![5020]));
+    assert_eq!(
+      matched(match_center(&centers, "Niagra Falls EC")),
+      ("missing", vec![5161])
+    );
+    assert_eq!(matched(match_center(&centers, "toronto")), ("missing", vec![]));
+    assert_eq!(matched(match_center(&centers, "  ")), ("missing", vec![]));
+  }
+
+  #[test]
+  fn edit_distance_counts_single_character_edits() {
+    assert_eq!(edit_distance("blaine", "blaine"), 0);
+    assert_eq!(edit_distance("blane", "blaine"), 1);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("", "abc"), 3);
+  }
+
+  #[test]
+  fn short_names_must_be_unique_once_normalized() {
+    let duplicate = format!(
+      "{}\n[[centers]]\nid = 1\nshort_name = \" Blaine\"\nfull_name = \"Other\"\naddress = \"\"",
+      CENTERS
+    );
+    let err = CentersConfig::load(("centers.toml", &duplicate), Path::new("missing"))
+      .err()
+      .unwrap();
+    assert!(err.contains("are not unique"), "{}", err);
+
+    let alias = format!(
+      "{}\n[[centers]]\nid = 1\nshort_name = \"other\"\nfull_name = \"Other\"\naddress = \"\"\naliases = [\"NIAGARA\"]",
+      CENTERS
+    );
+    let err = CentersConfig::load(("centers.toml", &alias), Path::new("missing"))
+      .err()
+      .unwrap();
+    assert!(err.contains("collides with the short name"), "{}", err);
+  }
+
+  fn source(name: &str, centers: Vec<Center>, regions: Vec<Region>) -> (String, CentersConfig) {
+    (name.to_string(), CentersConfig { centers, regions })
+  }
+
+  fn region(name: &str, centers: &[&str]) -> Region {
+    Region {
+      name: name.to_string(),
+      centers: centers.iter().map(|x| x.to_string()).collect(),
+    }
+  }
+
+  #[test]
+  fn merged_sources_keep_their_order() {
+    let merged = CentersConfig::merge(vec![
+      source(
+        "centers.toml",
+        vec![center(5161, "niagara", "Niagara Falls EC")],
+        Vec::new(),
+      ),
+      source(
+        "centers.d/a.toml",
+        vec![center(5022, "buffalo", "Buffalo EC")],
+        vec![region("east", &["niagara", "buffalo"])],
+      ),
+      source(
+        "centers.d/b.json",
+        vec![center(5020, "blaine", "Blaine EC")],
+        Vec::new(),
+      ),
+    ])
+    .unwrap();
+    assert_eq!(
+      merged.centers.iter().map(|x| x.id).collect::<Vec<_>>(),
+      [5161, 5022, 5020]
+    );
+    assert_eq!(merged.regions.len(), 1);
+  }
+
+  #[test]
+  fn merging_rejects_every_redefinition() {
+    let err = CentersConfig::merge(vec![
+      source(
+        "centers.toml",
+        vec![center(5161, "niagara", "Niagara Falls EC")],
+        vec![region("East", &["niagara"])],
+      ),
+      source(
+        "centers.d/a.toml",
+        vec![center(5161, "falls", "Niagara Falls")],
+        vec![region(" east ", &[])],
+      ),
+    ])
+    .err()
+    .unwrap();
+    assert_eq!(
+      err,
+      "region ` east ` in centers.d/a.toml is already defined in centers.toml\n\
+       center 5161 (`falls`) in centers.d/a.toml is already defined in centers.toml"
+    );
+  }
+
+  #[test]
+  fn clashing_names_name_their_files() {
+    let err = CentersConfig::merge(vec![
+      source(
+        "centers.toml",
+        vec![center(5161, "niagara", "Niagara Falls EC")],
+        Vec::new(),
+      ),
+      source("centers.d/a.toml", vec![center(1, "Niagara", "Other")], Vec::new()),
+    ])
+    .err()
+    .unwrap();
+    assert_eq!(
+      err,
+      "short names `niagara` (5161 in centers.toml) and `Niagara` (1 in centers.d/a.toml) are not unique"
+    );
+
+    let err = CentersConfig::merge(vec![
+      source(
+        "centers.toml",
+        vec![center(5161, "niagara", "Niagara Falls EC")],
+        Vec::new(),
+      ),
+      source(
+        "centers.d/a.toml",
+        vec![center_with(1, "other", "Other", "aliases = [\"niagara\"]")],
+        Vec::new(),
+      ),
+    ])
+    .err()
+    .unwrap();
+    assert_eq!(
+      err,
+      "alias `niagara` of `other` (1 in centers.d/a.toml) collides with the short name of `niagara` (5161 in \
+       centers.toml)"
+    );
+  }
+
+  #[test]
+  fn bundled_centers_give_the_same_registry_as_toml_and_json() {
+    let bundled = CentersConfig::from_str(include_str!("../centers.toml"), CentersFormat::Toml).unwrap();
+    let registry = |format| {
+      let contents = bundled.to_contents(format).unwrap();
+      let parsed = CentersConfig::from_str(&contents, format).unwrap();
+      let config = CentersConfig::merge(vec![("centers".to_string(), parsed)]).unwrap();
+      serde_json::to_string(&config).unwrap()
+    };
+    let toml = registry(CentersFormat::Toml);
+    assert_eq!(toml, registry(CentersFormat::Json));
+    assert_eq!(toml, serde_json::to_string(&bundled).unwrap());
+  }
+
+  #[test]
+  fn center_files_load_in_filename_order() {
+    let dir = std::env::temp_dir().join(format!("nexus-pls-centers-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+      dir.join("b.toml"),
+      "[[centers]]\nid = 5022\nshort_name = \"buffalo\"\nfull_name = \"Buffalo\"\naddress = \"\"",
+    )
+    .unwrap();
+    fs::write(
+      dir.join("a.json"),
+      r#"{"centers": [{"id": 5060, "short_name": "warroad", "full_name": "Warroad", "address": ""}]}"#,
+    )
+    .unwrap();
+    fs::write(dir.join("notes.txt"), "not a center file").unwrap();
+
+    let loaded = CentersConfig::load(("centers.toml", CENTERS), &dir);
+    fs::remove_dir_all(&dir).unwrap();
+    let ids = loaded.unwrap().centers.iter().map(|x| x.id).collect::<Vec<_>>();
+    assert_eq!(ids, [5020, 5021, 5161, 5060, 5022]);
+  }
+
+  #[test]
+  fn regions_resolve_by_name_to_their_members() {
+    let config = fixture();
+    let region = resolve_region(&config.regions, " pnw ").unwrap();
+    let members = region.members(&config.centers).iter().map(|x| x.id).collect::<Vec<_>>();
+    assert_eq!(members, [5020, 5021]);
+    assert!(resolve_region(&config.regions, "northeast").is_none());
+  }
+
+  #[test]
+  fn regions_must_name_known_centers_and_not_shadow_them() {
+    for (region, expected) in [
+      (
+        "name = \"Peace Arch\"\ncenters = [\"blaine\"]",
+        "collides with the center `blaine`",
+      ),
+      ("name = \"Niagara\"\ncenters = []", "collides with the center `niagara`"),
+      (
+        "name = \"east\"\ncenters = [\"buffalo\"]",
+        "lists unknown center `buffalo`",
+      ),
+    ] {
+      let config = format!("{}\n[[regions]]\n{}", CENTERS, region);
+      let err = CentersConfig::load(("centers.toml", &config), Path::new("missing"))
+        .err()
+        .unwrap();
+      assert!(err.contains(expected), "{}", err);
+    }
+  }
+
+  #[test]
+  fn only_known_closed_locations_pause_polling() {
+    let locations: Vec<Location> = serde_json::from_str(
+      r#"[{"id": 5020, "operational": false}, {"id": 5021, "operational": true},
+          {"id": 9999, "operational": false}]"#,
+    )
+    .unwrap();
+    let lut = fixture().centers.into_iter().map(|x| (x.id, x)).collect();
+    assert_eq!(closed_centers(&locations, &lut), HashSet::from([5020]));
+  }
+
+  /// A center `id` named `short_name`, optionally in `state`.
+  fn listed(id: CenterId, short_name: &str, state: Option<&str>) -> Center {
+    let mut center = center(5161, "niagara", "Niagara Falls EC");
+    center.id = id;
+    center.short_name = short_name.to_string();
+    center.state = state.map(str::to_string);
+    center
+  }
+
+  #[test]
+  fn states_come_from_the_config_or_the_address() {
+    let mut center = center(5161, "niagara", "Niagara Falls EC");
+    center.address = "8115 Birch Bay Square St., BLAINE, WASHINGTON 98230".to_string();
+    assert_eq!(center.state().as_deref(), Some("Washington"));
+    center.state = Some("WA".to_string());
+    assert_eq!(center.state().as_deref(), Some("WA"));
+    center.state = None;
+    center.address = "Somewhere".to_string();
+    assert_eq!(center.state(), None);
+  }
+
+  #[test]
+  fn listings_sort_by_state_then_short_name() {
+    let centers = [
+      listed(1, "warroad", Some("Minnesota")),
+      listed(2, "seattle", Some("Washington")),
+      listed(3, "nowhere", None),
+      listed(4, "blaine", Some("Washington")),
+    ];
+    let groups = group_centers_by_state(&centers)
+      .into_iter()
+      .map(|(state, centers)| (state, centers.iter().map(|x| x.id).collect::<Vec<_>>()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      groups,
+      [
+        ("Minnesota".to_string(), vec![1]),
+        ("Washington".to_string(), vec![4, 2]),
+        (UNKNOWN_STATE.to_string(), vec![3]),
+      ]
+    );
+  }
+
+  #[test]
+  fn large_listings_fit_in_telegram_messages() {
+    let centers = (0..400)
+      .map(|x| {
+        let mut center = listed(x, &format!("center-{:03}", x), Some(&format!("State {}", x % 7)));
+        center.full_name = format!("Enrollment Center Number {} (Terminal {})", x, x % 5);
+        center
+      })
+      .collect::<Vec<_>>();
+    let pages = paginate(render_center_groups(&centers, &HashSet::new()), MESSAGE_LIMIT);
+
+    assert!(pages.len() > 1);
+    assert!(pages.iter().all(|x| x.chars().count() <= MESSAGE_LIMIT));
+    let listed = pages.concat();
+    assert!((0..400).all(|x| listed.matches(&format!("center\\-{:03}`", x)).count() == 1));
+  }
+
+  #[test]
+  fn pages_fill_up_to_the_limit_exactly() {
+    let sections = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(paginate(sections(&["aaaa", "bbbb"]), 10), ["aaaa\n\nbbbb"]);
+    assert_eq!(paginate(sections(&["aaaa", "bbbbb"]), 10), ["aaaa", "bbbbb"]);
+    assert_eq!(paginate(sections(&["aaaa", "bbbbb", "c"]), 10), ["aaaa", "bbbbb\n\nc"]);
+    assert_eq!(paginate(sections(&["ééé", "ééé"]), 8), ["ééé\n\nééé"]);
+    assert!(paginate(Vec::new(), 10).is_empty());
+  }
+
+  #[test]
+  fn sections_too_long_for_one_message_are_split_between_lines() {
+    let section = (0..50).map(|x| format!("line {}", x)).collect::<Vec<_>>().join("\n");
+    let pages = paginate(vec!["short".to_string(), section], 100);
+    assert!(pages.iter().all(|x| x.chars().count() <= 100));
+    assert!(pages[0].starts_with("short\n\nline 0\n"));
+    assert_eq!(pages.concat().matches("line ").count(), 50);
+  }
+
+  #[test]
+  fn centers_offer_nexus_unless_they_list_services() {
+    assert_eq!(center(5161, "niagara", "Niagara Falls EC").services, [Service::Nexus]);
+
+    let center = center_with(
+      5161,
+      "niagara",
+      "Niagara Falls EC",
+      "services = [\"ge\", \"SENTRI\", \"Global Entry\"]",
+    );
+    assert!(center.offers(Service::GlobalEntry));
+    assert!(center.offers(Service::Sentri));
+    assert!(!center.offers(Service::Nexus));
+    assert!(!center.offers(Service::Fast));
+  }
+
+  #[test]
+  fn services_parse_from_user_input() {
+    for (filter, service) in [
+      ("ge", Some(Service::GlobalEntry)),
+      (" Global  Entry ", Some(Service::GlobalEntry)),
+      ("NH", Some(Service::Nexus)),
+      ("nexus", Some(Service::Nexus)),
+      ("Sentri", Some(Service::Sentri)),
+      ("fast", Some(Service::Fast)),
+      ("passport", None),
+      ("", None),
+    ] {
+      assert_eq!(Service::from_filter(filter), service, "{}", filter);
+    }
+  }
+
+  #[test]
+  fn unknown_services_are_rejected() {
+    assert!(toml::from_str::<Center>(
+      "id = 1\nshort_name = \"a\"\nfull_name = \"A\"\naddress = \"\"\nservices = [\"pass\"]"
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn locations_keep_the_services_they_list() {
+    let location: Location = serde_json::from_str(
+      r#"{"id": 5140, "name": "JFK International Global Entry EC", "shortName": "JFK",
+          "services": [{"name": "Global Entry"}, {"name": "Passport"}]}"#,
+    )
+    .unwrap();
+    assert_eq!(location.to_center().unwrap().services, [Service::GlobalEntry]);
+  }
+
+  #[test]
+  fn booking_url_uses_each_services_code() {
+    let center = center(5161, "niagara", "Niagara Falls EC");
+    for (service, code) in [
+      (Service::Nexus, "nh"),
+      (Service::GlobalEntry, "up"),
+      (Service::Sentri, "sh"),
+      (Service::Fast, "fp"),
+    ] {
+      assert_eq!(center.booking_url(service), format!("{}{}", SCHEDULE_LINK, code));
+    }
+  }
+
+  #[test]
+  fn booking_url_prefers_override_then_country() {
+    let canadian = center_with(5161, "niagara", "Niagara Falls EC", "country = \"CA\"");
+    assert_eq!(canadian.booking_url(Service::GlobalEntry), CANADA_SCHEDULE_LINK);
+
+    let custom = center_with(
+      5161,
+      "niagara",
+      "Niagara Falls EC",
+      "booking_url = \"https://example.com/book\"",
+    );
+    assert_eq!(custom.booking_url(Service::Nexus), "https://example.com/book");
+  }
+
+  #[test]
+  fn slots_link_the_requested_service() {
+    let center = center(5020, "blaine", "Blaine EC");
+    let msg = center.slots_msg(&[slot("2099-05-01T09:30")], Service::GlobalEntry);
+    assert!(
+      msg.starts_with("Appointments Avaliable for Blaine EC \\(Global Entry\\)\n"),
+      "{}",
+      msg
+    );
+    assert!(msg.ends_with(&format!(
+      "[Schedule Appointment]({})",
+      center.booking_url(Service::GlobalEntry)
+    )));
+  }
+
+  #[test]
+  fn slots_list_the_soonest_upcoming_times() {
+    let center = center(5161, "niagara", "Niagara Falls EC");
+    let slots = [
+      "2099-01-07T13:00",
+      "2000-01-01T09:00",
+      "2099-01-05T09:30",
+      "2099-01-02T10:00",
+    ]
+    .into_iter()
+    .chain((1..=4).map(|_| "2099-01-06T08:15"))
+    .map(slot)
+    .collect::<Vec<_>>();
+    assert_eq!(
+      center.slots_msg(&slots, Service::Nexus),
+      format!(
+        "Appointments Avaliable for Niagara Falls EC\n\
+         10:00 AM EST on Friday January 2\n\
+         9:30 AM EST on Monday January 5\n\
+         8:15 AM EST on Tuesday January 6\n\
+         8:15 AM EST on Tuesday January 6\n\
+         8:15 AM EST on Tuesday January 6\n\
+         [Schedule Appointment]({})",
+        center.booking_url(Service::Nexus)
+      )
+    );
+  }
+
+  #[test]
+  fn slots_without_upcoming_times_say_so() {
+    let center = center(5161, "niagara", "Niagara Falls EC");
+    let expected = "No appointments found at Niagara Falls EC\\.";
+    assert_eq!(center.slots_msg(&[], Service::Nexus), expected);
+    let stale = [slot("2000-01-01T09:00"), slot("not-a-time")];
+    assert_eq!(center.slots_msg(&stale, Service::Nexus), expected);
+  }
+
+  #[test]
+  fn slot_times_carry_the_zone_in_effect_across_dst() {
+    let tz = Some(chrono_tz::America::Los_Angeles);
+    for (start, formatted) in [
+      ("2024-03-09T10:30", "10:30 AM PST on Saturday March 9"),
+      ("2024-03-11T10:30", "10:30 AM PDT on Monday March 11"),
+      // Skipped by the spring change, so there is no zone to name.
+      ("2024-03-10T02:30", "2:30 AM on Sunday March 10"),
+      // Repeated by the fall change, the first occurrence is meant.
+      ("2024-11-03T01:30", "1:30 AM PDT on Sunday November 3"),
+      ("2024-11-04T09:00", "9:00 AM PST on Monday November 4"),
+    ] {
+      assert_eq!(format_slot_time(start, tz), formatted);
+    }
+    assert_eq!(
+      format_slot_time("2024-03-09T10:30", None),
+      "10:30 AM on Saturday March 9"
+    );
+    assert_eq!(format_slot_time("tomorrow", tz), "tomorrow");
+  }
+
+  #[test]
+  fn unknown_timezones_are_rejected() {
+    let mut center = center(5161, "niagara", "Niagara Falls EC");
+    center.timezone = Some("America/Niagara".to_string());
+    let err = CentersConfig::merge(vec![source("centers.toml", vec![center], Vec::new())])
+      .err()
+      .unwrap();
+    assert_eq!(
+      err,
+      "timezone `America/Niagara` of `niagara` is not an IANA timezone name"
+    );
+  }
+
+  #[test]
+  fn map_urls_encode_unicode_and_ampersands() {
+    let mut center = center(5161, "niagara", "Niagara Falls EC");
+    center.address = "Gare Centrale & Château, Montréal".to_string();
+    let destination = "Gare%20Centrale%20%26%20Ch%C3%A2teau%2C%20Montr%C3%A9al%2C%20USA";
+    assert_eq!(
+      center.directions_url(Some(" Café & Bar, Québec ")).unwrap(),
+      format!(
+        "https://www.google.com/maps/dir/?api=1&destination={}&origin=Caf%C3%A9%20%26%20Bar%2C%20Qu%C3%A9bec",
+        destination
+      )
+    );
+    assert_eq!(
+      center.map_url(),
+      format!("https://www.google.com/maps/search/?api=1&query={}", destination)
+    );
+  }
+
+  #[test]
+  fn directions_prefer_coordinates_and_need_a_destination() {
+    let mut center = center(5020, "blaine", "Blaine EC");
+    center.latitude = Some(49.0);
+    center.longitude = Some(-122.75);
+    assert_eq!(
+      center.directions_url(Some("  ")).unwrap(),
+      "https://www.google.com/maps/dir/?api=1&destination=49%2C%2D122%2E75"
+    );
+
+    center.latitude = None;
+    center.address = " ".to_string();
+    assert_eq!(center.directions_url(Some("Seattle")), None);
+  }
+
+  fn slot(start: &str) -> Slot {
+    serde_json::from_value(serde_json::json!({ "locationId": 5161, "startTimestamp": start })).unwrap()
+  }
+
+  /// Fails on MarkdownV2 reserved characters that aren't escaped, outside of
+  /// the inline links.
+  fn assert_escaped(msg: &str) {
+    let mut text = msg.to_string();
+    while let Some(start) = text.find("](") {
+      let open = text[..start].rfind('[').unwrap();
+      let end = start + text[start..].find(')').unwrap();
+      text.replace_range(open..=end, "");
+    }
+    let mut chars = text.chars();
+    while let Some(x) = chars.next() {
+      match x {
+        '\\' => {
+          chars.next();
+        },
+        '_' | '*' | '[' | ']' | '(' | ')' | '~' | '`' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => {
+          panic!("unescaped `{}` in {}", x, msg)
+        },
+        _ => {},
+      }
+    }
+  }
+
+  /// Slots as the scheduler returned them, plus a field it may add later.
+  const SLOTS: &str = r#"[
+    {"locationId": 5161, "startTimestamp": "2024-05-01T09:30", "endTimestamp": "2024-05-01T09:45",
+     "active": 1, "total": 1, "pending": 0, "conflicts": 0, "duration": 15, "remoteInd": false},
+    {"locationId": 5161, "startTimestamp": "2024-05-01T10:00", "endTimestamp": "2024-05-01T10:15",
+     "active": 0, "total": 1, "pending": 0, "conflicts": 0, "duration": 15, "remoteInd": false,
+     "bookingWindow": "new"},
+    {"locationId": 5161, "startTimestamp": "2024-05-02T08:00"}
+  ]"#;
+
+  #[test]
+  fn slots_parse_the_full_schema() {
+    let slots: ScheduleSlots = serde_json::from_str(SLOTS).unwrap();
+    assert_eq!(slots[0].end_timestamp.as_deref(), Some("2024-05-01T09:45"));
+    assert_eq!(
+      (slots[0].active, slots[0].duration, slots[0].remote_ind),
+      (Some(1), Some(15), false)
+    );
+    assert_eq!((slots[2].active, slots[2].duration), (None, None));
+    assert_eq!(
+      slots.iter().map(Slot::is_bookable).collect::<Vec<_>>(),
+      [true, false, true]
+    );
+  }
+
+  #[test]
+  fn slot_descriptions_include_the_duration() {
+    let slots: ScheduleSlots = serde_json::from_str(SLOTS).unwrap();
+    assert_eq!(slots[0].describe(None), "9:30 AM on Wednesday May 1 (15 min)");
+    assert_eq!(slots[2].describe(None), "8:00 AM on Thursday May 2");
+  }
+
+  fn niagara() -> Center {
+    let mut center = center(5161, "niagara", "Niagara Falls EC");
+    center.address = "2250 WHIRLPOOL ST., NIAGARA FALLS, NEW YORK 14305".to_string();
+    center
+  }
+
+  #[test]
+  fn single_slot_alert_snapshot() {
+    let user_data = UserData {
+      map_link: true,
+      ..Default::default()
+    };
+    let msg = niagara().appointment_avaliable_msg(&[&slot("2024-05-01T09:30")], &user_data);
+    assert_eq!(
+      msg,
+      concat!(
+        "Appointment Avaliable for Niagara Falls EC\n",
+        "[2250 WHIRLPOOL ST\\., NIAGARA FALLS, NEW YORK 14305, USA]",
+        "(https://www.google.com/maps/search/?api=1&query=2250%20WHIRLPOOL%20ST%2E%2C%20NIAGARA%20FALLS%2C%20NEW%20YORK%2014305%2C%20USA)\n",
+        "9:30 AM EDT on Wednesday May 1\n",
+        "[Schedule Appointment](https://ttp.cbp.dhs.gov/schedulerui/schedule-interview/location?lang=en&vo=true&returnUrl=ttp-external&service=nh) \\| [Directions](https://www.google.com/maps/dir/?api=1&destination=2250%20WHIRLPOOL%20ST%2E%2C%20NIAGARA%20FALLS%2C%20NEW%20YORK%2014305%2C%20USA)"
+      )
+    );
+  }
+
+  #[test]
+  fn many_slot_alert_snapshot() {
+    let slots = (1..=7)
+      .map(|x| slot(&format!("2024-05-0{}T09:30", x)))
+      .collect::<Vec<_>>();
+    let msg = niagara().appointment_avaliable_msg(&slots.iter().rev().collect::<Vec<_>>(), &UserData::default());
+    assert_eq!(
+      msg,
+      concat!(
+        "Appointments Avaliable for Niagara Falls EC\n",
+        "2250 WHIRLPOOL ST\\., NIAGARA FALLS, NEW YORK 14305, USA\n",
+        "• 9:30 AM EDT on Wednesday May 1\n",
+        "• 9:30 AM EDT on Thursday May 2\n",
+        "• 9:30 AM EDT on Friday May 3\n",
+        "• 9:30 AM EDT on Saturday May 4\n",
+        "• 9:30 AM EDT on Sunday May 5\n",
+        "…and 2 more times available\n",
+        "[Schedule Appointment](https://ttp.cbp.dhs.gov/schedulerui/schedule-interview/location?lang=en&vo=true&returnUrl=ttp-external&service=nh) \\| [Directions](https://www.google.com/maps/dir/?api=1&destination=2250%20WHIRLPOOL%20ST%2E%2C%20NIAGARA%20FALLS%2C%20NEW%20YORK%2014305%2C%20USA)"
+      )
+    );
+  }
+
+  #[test]
+  fn appointment_messages_escape_names_and_times() {
+    let mut center = center(5161, "niagara", "Niagara Falls EC");
+    center.full_name = "St. Mary's (Port-Huron) Center!".to_string();
+    center.address = "1 Main St., Port Huron".to_string();
+    let user_data = UserData::default();
+    let msg = center.appointment_avaliable_msg(&[&slot("2024-05-01T09:30")], &user_data);
+    assert!(msg.contains("St\\. Mary's \\(Port\\-Huron\\) Center\\!"), "{}", msg);
+    assert!(msg.contains(&format!(
+      "[Schedule Appointment]({})",
+      center.booking_url(Service::Nexus)
+    )));
+    assert_escaped(&msg);
+
+    let slots = [slot("2024-05-02T10:00"), slot("2024-05-01T09:30"), slot("not-a-time.")];
+    let msg = center.appointment_avaliable_msg(&slots.iter().collect::<Vec<_>>(), &user_data);
+    assert_escaped(&msg);
+  }
+
+  fn collector(poll_interval: Duration, api_base: &str) -> CenterDataCollectorTask {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+      .with_native_roots()
+      .https_or_http()
+      .enable_http1()
+      .build();
+    let bot = teloxide::requests::RequesterExt::auto_send(Bot::new("0:test"));
+    CenterDataCollectorTask::new(Client::builder().build(https), bot, poll_interval, api_base.to_string())
+  }
+
+  #[tokio::test]
+  async fn worker_drains_its_queue_and_exits_on_stop() {
+    crate::start_test_manager().await;
+    let mut task = collector(Duration::from_secs(60), &CONFIG.api_base);
+    queue(&task.tx, CollectorMessage::RequestSlots(Vec::new(), 1)).unwrap();
+    // The task never finishes, it only starts a cycle right away.
+    assert!(tokio::time::timeout(Duration::from_millis(100), &mut task)
+      .await
+      .is_err());
+    assert_eq!(task.cycle_id, 1);
+
+    tokio::time::timeout(Duration::from_secs(10), task.shutdown())
+      .await
+      .expect("worker did not exit");
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn cycles_start_once_per_poll_interval() {
+    crate::start_test_manager().await;
+    let mut task = collector(Duration::from_secs(60), &CONFIG.api_base);
+    assert!(futures::poll!(&mut task).is_pending());
+    tokio::time::advance(Duration::from_millis(1)).await;
+    assert!(futures::poll!(&mut task).is_pending());
+    assert_eq!(task.cycle_id, 1);
+
+    for cycle in 2..5 {
+      tokio::time::advance(Duration::from_secs(59)).await;
+      assert!(futures::poll!(&mut task).is_pending());
+      assert_eq!(task.cycle_id, cycle - 1);
+      tokio::time::advance(Duration::from_secs(1)).await;
+      assert!(futures::poll!(&mut task).is_pending());
+      assert_eq!(task.cycle_id, cycle);
+    }
+    task.shutdown().await;
+  }
+
+  /// Subscribes `user` to `center` in the shared test manager.
+  async fn subscribe(user: UserId, center: CenterId) {
+    crate::start_test_manager().await;
+    let mut lock = MANAGER.lock().await;
+    let manager = lock.as_mut().unwrap();
+    manager
+      .track_center(user as i64, user, center, Service::Nexus)
+      .await
+      .unwrap();
+  }
+
+  /// A slot at `location` 9:00 on the day `days` from now. Tests sharing a
+  /// center use different days, so one test's alerts don't hide another's.
+  fn slot_in(location: CenterId, days: i64) -> Slot {
+    let start = (Local::now().naive_local().date() + chrono::Duration::days(days))
+      .and_hms_opt(9, 0, 0)
+      .unwrap();
+    Slot {
+      location_id: location,
+      ..slot(&start.format("%Y-%m-%dT%H:%M").to_string())
+    }
+  }
+
+  /// The alerts among `notifications` for `user`.
+  fn alerts_for(user: UserId, notifications: Vec<PendingNotification>) -> Vec<PendingNotification> {
+    notifications.into_iter().filter(|x| x.user == Some(user)).collect()
+  }
+
+  #[tokio::test]
+  async fn slots_at_unknown_centers_are_skipped() {
+    subscribe(9101, 5161).await;
+    let slots = [slot_in(9999, 30), slot_in(5161, 31)];
+    let alerts = alerts_for(9101, slot_notifications(5161, &slots, &[(slot_in(9999, 32), 3)]).await);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].alert.map(|(center, _)| center), Some(5161));
+
+    let alerts = alerts_for(9101, slot_notifications(5161, &[slot_in(9999, 33)], &[]).await);
+    assert!(alerts.is_empty());
+  }
+
+  /// Runs `retry` against a request failing `failures` times, returning its
+  /// result and when each attempt was made.
+  async fn flaky(failures: u32) -> (Result<u32, String>, Vec<Duration>) {
+    let start = tokio::time::Instant::now();
+    let mut attempts = Vec::new();
+    let result = retry(FETCH_ATTEMPTS, FETCH_RETRY_DELAY, |attempt| {
+      attempts.push(start.elapsed());
+      async move {
+        if attempt <= failures {
+          Err(format!("attempt {} failed", attempt))
+        } else {
+          Ok(attempt)
+        }
+      }
+    })
+    .await;
+    (result, attempts)
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn retry_backs_off_until_a_request_succeeds() {
+    let ms = Duration::from_millis;
+    assert_eq!(flaky(0).await, (Ok(1), vec![ms(0)]));
+    assert_eq!(flaky(2).await, (Ok(3), vec![ms(0), ms(200), ms(600)]));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn retry_gives_up_after_its_attempts() {
+    let (result, attempts) = flaky(5).await;
+    assert_eq!(result, Err("attempt 3 failed (after 3 attempts)".to_string()));
+    assert_eq!(attempts.len(), 3);
+    assert!(attempts[2] < CONFIG.poll_interval);
+  }
+
+  #[tokio::test]
+  async fn slots_at_a_center_are_sent_as_one_message() {
+    subscribe(9301, 5022).await;
+    let latest = slot_in(5022, 62).start().unwrap().date();
+    let mut lock = MANAGER.lock().await;
+    let manager = lock.as_mut().unwrap();
+    manager
+      .update_user_data(9301, 9301, |x| x.latest = Some(latest))
+      .await
+      .unwrap();
+    drop(lock);
+
+    let slots = [
+      slot_in(5022, 62),
+      slot_in(5022, 60),
+      slot_in(5022, 63),
+      slot_in(5022, 61),
+    ];
+    let alerts = alerts_for(9301, slot_notifications(5022, &slots, &[]).await);
+    assert_eq!(alerts.len(), 1);
+    let text = &alerts[0].text;
+    assert!(text.starts_with("Appointments Avaliable for "), "{}", text);
+    assert_eq!(text.matches("• ").count(), 3, "{}", text);
+    assert_eq!(text.matches("[Schedule Appointment]").count(), 1);
+    assert_eq!(alerts[0].alert, Some((5022, slots[1].start().unwrap())));
+  }
+
+  #[tokio::test]
+  async fn earlier_only_users_are_alerted_of_new_earliest_slots() {
+    subscribe(9401, 5027).await;
+    let mut lock = MANAGER.lock().await;
+    let manager = lock.as_mut().unwrap();
+    manager
+      .update_user_data(9401, 9401, |x| x.earlier_only = true)
+      .await
+      .unwrap();
+    drop(lock);
+
+    let alerted = |slots: Vec<PendingNotification>| alerts_for(9401, slots).len();
+    assert_eq!(alerted(slot_notifications(5027, &[slot_in(5027, 72)], &[]).await), 1);
+    assert_eq!(alerted(slot_notifications(5027, &[slot_in(5027, 74)], &[]).await), 0);
+    assert_eq!(alerted(slot_notifications(5027, &[slot_in(5027, 71)], &[]).await), 1);
+
+    let mut lock = MANAGER.lock().await;
+    let user_data = lock.as_mut().unwrap().get_user_data(9401).await.unwrap().unwrap();
+    assert_eq!(user_data.best_seen.get(&5027).copied(), slot_in(5027, 71).start());
+  }
+
+  #[tokio::test]
+  async fn collector_alerts_subscribers_of_slots_from_the_scheduler() {
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    subscribe(9201, 5161).await;
+    let soonest = slot_in(5161, 50).start_timestamp;
+    let slots = serde_json::json!([
+      { "locationId": 5161, "startTimestamp": soonest, "active": 1, "duration": 10 },
+      { "locationId": 5161, "startTimestamp": slot_in(5161, 51).start_timestamp, "active": 1 },
+    ]);
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/slots"))
+      .and(query_param("locationId", "5161"))
+      .and(query_param("limit", CONFIG.slot_limit.to_string()))
+      .respond_with(ResponseTemplate::new(200).set_body_json(slots))
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let task = collector(Duration::from_secs(60), &server.uri());
+    queue(&task.tx, CollectorMessage::RequestSlots(vec![5161], 1)).unwrap();
+    task.shutdown().await;
+
+    let mut lock = MANAGER.lock().await;
+    let user_data = lock.as_mut().unwrap().get_user_data(9201).await.unwrap().unwrap();
+    let alerted = user_data.last_notified.get(&5161).map(|x| x.slot);
+    assert_eq!(alerted, NaiveDateTime::parse_from_str(&soonest, "%Y-%m-%dT%H:%M").ok());
+  }
+
+  #[tokio::test]
+  async fn centers_are_fetched_fetch_concurrency_at_a_time() {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let round_trip = Duration::from_millis(250);
+    let centers = (7001..=7010).collect::<Vec<CenterId>>();
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/slots"))
+      .respond_with(
+        ResponseTemplate::new(200)
+          .set_body_json(serde_json::json!([]))
+          .set_delay(round_trip),
+      )
+      .expect(centers.len() as u64)
+      .mount(&server)
+      .await;
+
+    crate::start_test_manager().await;
+    let task = collector(Duration::from_secs(60), &server.uri());
+    let started = Instant::now();
+    queue(&task.tx, CollectorMessage::RequestSlots(centers.clone(), 1)).unwrap();
+    task.shutdown().await;
+
+    let round_trips = centers.len().div_ceil(CONFIG.fetch_concurrency) as u32;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= round_trip * round_trips, "{:?}", elapsed);
+    assert!(elapsed < round_trip * (round_trips + 1), "{:?}", elapsed);
+  }
+
+  fn batch(len: usize) -> Vec<PendingNotification> {
+    (0..len)
+      .map(|x| PendingNotification::plain(x as i64, format!("alert {}", x)))
+      .collect()
+  }
+
+  fn texts(notifications: &[PendingNotification]) -> Vec<&str> {
+    notifications.iter().map(|x| x.text.as_str()).collect()
+  }
+
+  #[tokio::test]
+  async fn drain_keeps_what_a_rate_limit_stops() {
+    let pending = batch(5);
+    let drained = drain_batch(
+      pending.clone(),
+      Instant::now() + Duration::from_secs(60),
+      |x, _| async move {
+        match x.chat_id {
+          1 => Err(RequestError::Api(ApiError::BotBlocked)),
+          2 => Err(RequestError::RetryAfter(Duration::from_secs(120))),
+          _ => Ok(()),
+        }
+      },
+    )
+    .await;
+
+    assert_eq!(drained.attempted, 2);
+    assert_eq!(texts(&drained.delivered), ["alert 0"]);
+    assert_eq!(texts(&drained.remaining), ["alert 2", "alert 3", "alert 4"]);
+    assert_eq!(drained.attempted + drained.remaining.len(), pending.len());
+  }
+
+  #[tokio::test]
+  async fn drain_past_deadline_persists_everything() {
+    let pending = batch(3);
+    let drained = drain_batch(pending, Instant::now(), |_, _| async { Ok(()) }).await;
+
+    assert_eq!(drained.attempted, 0);
+    assert_eq!(texts(&drained.remaining), ["alert 0", "alert 1", "alert 2"]);
+  }
+
+  #[tokio::test]
+  async fn drain_sends_with_the_time_left_as_budget() {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let drained = drain_batch(batch(2), deadline, |_, budget| async move {
+      assert!(budget <= Duration::from_secs(60) && budget > Duration::from_secs(50));
+      Ok(())
+    })
+    .await;
+
+    assert_eq!(texts(&drained.delivered), ["alert 0", "alert 1"]);
+    assert!(drained.remaining.is_empty());
+  }
+
+  #[tokio::test]
+  async fn commands_are_not_blocked_by_alerts_in_flight() {
+    subscribe(9601, 5025).await;
+    let (started_tx, started) = tokio::sync::oneshot::channel();
+    let mut started_tx = Some(started_tx);
+    let alerting = tokio::spawn(async move {
+      let slots = [slot_in(5025, 80)];
+      alert_subscribers(5025, &slots, &[], &mut HashMap::new(), move |_, _| {
+        if let Some(started_tx) = started_tx.take() {
+          let _ = started_tx.send(());
+        }
+        Box::pin(async {
+          tokio::time::sleep(Duration::from_secs(60)).await;
+          Ok(())
+        })
+      })
+      .await;
+    });
+    started.await.unwrap();
+
+    let tracked = tokio::time::timeout(Duration::from_secs(1), async {
+      let mut lock = MANAGER.lock().await;
+      lock
+        .as_mut()
+        .unwrap()
+        .track_center(9602, 9602, 5025, Service::Nexus)
+        .await
+    });
+    assert!(tracked.await.expect("command waited on the alert being sent").is_ok());
+    alerting.abort();
+  }
+
+  #[test]
+  fn only_gone_chats_are_unreachable() {
+    for err in [ApiError::BotBlocked, ApiError::ChatNotFound, ApiError::BotKicked] {
+      assert!(is_unreachable_chat(&RequestError::Api(err)));
+    }
+    let transient = [
+      RequestError::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset)),
+      RequestError::RetryAfter(Duration::from_secs(3)),
+      RequestError::Api(ApiError::Unknown("Bad Gateway".to_string())),
+      RequestError::MigrateToChatId(-100),
+    ];
+    for err in transient {
+      assert!(!is_unreachable_chat(&err), "{} is not permanent", err);
+    }
+  }
+
+  #[test]
+  fn digests_go_to_each_subscriptions_chat() {
+    let mut user_data = UserData {
+      chat_id: 1,
+      ..UserData::default()
+    };
+    user_data.chats.insert(5161, -100);
+    let entry = |center| DigestEntry {
+      center,
+      start: "2024-05-01T09:30".to_string(),
+    };
+    let chats = digests_by_chat(&user_data, vec![entry(5161), entry(5022), entry(5161)]);
+    assert_eq!(chats.keys().copied().collect::<Vec<_>>(), [-100, 1]);
+    assert_eq!(chats[&-100], [entry(5161), entry(5161)]);
+    assert_eq!(chats[&1], [entry(5022)]);
+  }
+
+  /// Log lines written through the JSON log format.
+  #[derive(Clone, Default)]
+  struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+  impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl CapturedLogs {
+    fn events(&self) -> Vec<serde_json::Value> {
+      let logs = self.0.lock().unwrap();
+      String::from_utf8_lossy(&logs)
+        .lines()
+        .map(|x| serde_json::from_str(x).expect("log line is not json"))
+        .collect()
+    }
+  }
+
+  #[tokio::test]
+  async fn json_logs_carry_ids_as_fields() {
+    use tracing_subscriber::layer::SubscriberExt;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::registry().with(crate::logging::json_layer(move || writer.clone()));
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    subscribe(9801, 5060).await;
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/slots"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+      .mount(&server)
+      .await;
+    let task = collector(Duration::from_secs(60), &server.uri());
+    queue(&task.tx, CollectorMessage::RequestSlots(vec![5060], 4)).unwrap();
+    task.shutdown().await;
+
+    let mut unreachable = HashMap::from([((9801, 9801), CONFIG.unreachable_limit - 1)]);
+    alert_subscribers(5060, &[slot_in(5060, 90)], &[], &mut unreachable, |_, _| {
+      Box::pin(async { Err(RequestError::Api(ApiError::BotBlocked)) })
+    })
+    .await;
+
+    let events = logs.events();
+    let find = |message: &str| {
+      events
+        .iter()
+        .find(|x| x["message"].as_str().is_some_and(|x| x.starts_with(message)))
+        .unwrap_or_else(|| panic!("no `{}` event in {:?}", message, events))
+    };
+    let empty = find("No slots avaliable");
+    assert_eq!(empty["level"], "INFO");
+    assert_eq!(empty["center_id"], 5060);
+    assert_eq!(empty["cycle_id"], 4);
+    assert!(empty["timestamp"].is_string());
+    assert!(empty["target"].as_str().unwrap().starts_with("nexus_pls"));
+    let dropped = find("Dropped subscriptions for a chat that can't be reached");
+    assert_eq!(dropped["user_id"], 9801);
+    assert_eq!(dropped["chat_id"], 9801);
+    assert_eq!(dropped["dropped"], 1);
+  }
+}
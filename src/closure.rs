@@ -0,0 +1,119 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+/// A day or inclusive range of days a center is known to be closed, written as
+/// `2023-02-20` or `2023-02-27..2023-03-03`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Closure {
+  pub start: NaiveDate,
+  pub end: NaiveDate,
+}
+
+impl Closure {
+  pub fn contains(&self, date: NaiveDate) -> bool {
+    self.start <= date && date <= self.end
+  }
+}
+
+impl FromStr for Closure {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parse = |x: &str| {
+      NaiveDate::parse_from_str(x.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("`{}` is not a date in the form YYYY-MM-DD", x.trim()))
+    };
+
+    let (start, end) = match s.split_once("..") {
+      Some((start, end)) => (parse(start)?, parse(end)?),
+      None => {
+        let date = parse(s)?;
+        (date, date)
+      },
+    };
+
+    if end < start {
+      return Err(format!("closure `{}` ends before it starts", s.trim()));
+    }
+
+    Ok(Self { start, end })
+  }
+}
+
+impl TryFrom<String> for Closure {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<Closure> for String {
+  fn from(closure: Closure) -> Self {
+    closure.to_string()
+  }
+}
+
+impl Display for Closure {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.start == self.end {
+      write!(f, "{}", self.start)
+    } else {
+      write!(f, "{}..{}", self.start, self.end)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn date(s: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+  }
+
+  #[test]
+  fn single_days_and_ranges_parse() {
+    let day: Closure = " 2023-02-20 ".parse().unwrap();
+    assert_eq!((day.start, day.end), (date("2023-02-20"), date("2023-02-20")));
+    assert_eq!(day.to_string(), "2023-02-20");
+
+    let range: Closure = "2023-02-27 .. 2023-03-03".parse().unwrap();
+    assert_eq!((range.start, range.end), (date("2023-02-27"), date("2023-03-03")));
+    assert_eq!(range.to_string(), "2023-02-27..2023-03-03");
+    assert_eq!(range.to_string().parse::<Closure>(), Ok(range));
+  }
+
+  #[test]
+  fn ranges_include_both_ends_across_months() {
+    let range: Closure = "2024-02-28..2024-03-01".parse().unwrap();
+    for day in ["2024-02-28", "2024-02-29", "2024-03-01"] {
+      assert!(range.contains(date(day)), "{} is closed", day);
+    }
+    assert!(!range.contains(date("2024-02-27")));
+    assert!(!range.contains(date("2024-03-02")));
+
+    let new_year: Closure = "2023-12-31..2024-01-01".parse().unwrap();
+    assert!(new_year.contains(date("2024-01-01")));
+    assert!(!new_year.contains(date("2023-01-01")));
+  }
+
+  #[test]
+  fn bad_closures_are_rejected() {
+    assert_eq!(
+      "2023-03-03..2023-02-27".parse::<Closure>(),
+      Err("closure `2023-03-03..2023-02-27` ends before it starts".to_string())
+    );
+    assert_eq!(
+      "2023-02-30".parse::<Closure>(),
+      Err("`2023-02-30` is not a date in the form YYYY-MM-DD".to_string())
+    );
+    assert!("2023-02-27..".parse::<Closure>().is_err());
+    assert!("02/27/2023".parse::<Closure>().is_err());
+    assert!(serde_json::from_str::<Closure>("\"2023-03-03..2023-02-27\"").is_err());
+  }
+}
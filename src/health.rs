@@ -0,0 +1,108 @@
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+
+/// When a subsystem last reported success.
+#[derive(Default)]
+pub struct Component {
+  last_success: RwLock<Option<Instant>>,
+}
+
+impl Component {
+  pub fn mark(&self) {
+    *self.last_success.write().unwrap() = Some(Instant::now());
+  }
+
+  pub fn last_success(&self) -> Option<Instant> {
+    *self.last_success.read().unwrap()
+  }
+}
+
+lazy_static! {
+  pub static ref REDIS: Component = Component::default();
+  pub static ref TELEGRAM: Component = Component::default();
+  pub static ref COLLECTOR: Component = Component::default();
+  /// Marked whenever the systemd watchdog can take the manager lock.
+  pub static ref DISPATCHER: Component = Component::default();
+}
+
+/// Whether every component succeeded within its allowed age, with a JSON
+/// breakdown per component. A `max_age` of `None` only requires one success.
+pub fn readiness(now: Instant, components: &[(&str, Option<Instant>, Option<Duration>)]) -> (bool, Value) {
+  let mut ready = true;
+  let mut details = serde_json::Map::new();
+
+  for (name, last_success, max_age) in components {
+    let age = last_success.map(|x| now.saturating_duration_since(x));
+    let ok = match (age, max_age) {
+      (Some(age), Some(max_age)) => age <= *max_age,
+      (Some(_), None) => true,
+      (None, _) => false,
+    };
+    ready &= ok;
+    details.insert(
+      name.to_string(),
+      json!({ "ok": ok, "age_secs": age.map(|x| x.as_secs()) }),
+    );
+  }
+
+  (
+    ready,
+    json!({ "status": if ready { "ready" } else { "not ready" }, "components": details }),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MAX_AGE: Option<Duration> = Some(Duration::from_secs(120));
+
+  fn check(redis: Option<u64>, telegram: Option<u64>, collector: Option<u64>) -> (bool, Value) {
+    let now = Instant::now() + Duration::from_secs(1000);
+    let at = |x: Option<u64>| x.map(|x| now - Duration::from_secs(x));
+    readiness(
+      now,
+      &[
+        ("redis", at(redis), MAX_AGE),
+        ("telegram", at(telegram), None),
+        ("collector", at(collector), MAX_AGE),
+      ],
+    )
+  }
+
+  #[test]
+  fn ready_when_every_component_is_recent() {
+    let (ready, details) = check(Some(5), Some(900), Some(120));
+    assert!(ready);
+    assert_eq!(details["status"], "ready");
+    assert_eq!(details["components"]["redis"], json!({ "ok": true, "age_secs": 5 }));
+  }
+
+  #[test]
+  fn each_failed_component_flips_readiness() {
+    for (checked, failed) in [
+      (check(Some(121), Some(1), Some(1)), "redis"),
+      (check(Some(1), None, Some(1)), "telegram"),
+      (check(Some(1), Some(1), Some(500)), "collector"),
+      (check(Some(1), Some(1), None), "collector"),
+    ] {
+      let (ready, details) = checked;
+      assert!(!ready, "{}", failed);
+      assert_eq!(details["status"], "not ready");
+      for (name, component) in details["components"].as_object().unwrap() {
+        assert_eq!(component["ok"], name != failed, "{}", name);
+      }
+    }
+  }
+
+  #[test]
+  fn components_start_without_a_success() {
+    let component = Component::default();
+    assert!(component.last_success().is_none());
+    component.mark();
+    assert!(component.last_success().is_some());
+  }
+}
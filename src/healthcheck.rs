@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::{storage, CONFIG};
+
+/// The whole check has to finish within this, as container probes kill slow
+/// commands anyway.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks the running instance through its readiness endpoint, or the
+/// heartbeat it writes to storage when no http address is configured. Prints a
+/// one line status and returns the process exit code.
+pub async fn run() -> i32 {
+  let (code, status) = outcome(check(), TIMEOUT).await;
+  println!("{}", status);
+  code
+}
+
+/// The exit code and status line for `check`, which fails once `timeout`
+/// passes.
+async fn outcome(check: impl Future<Output = Result<String, String>>, timeout: Duration) -> (i32, String) {
+  let result = match tokio::time::timeout(timeout, check).await {
+    Ok(result) => result,
+    Err(_) => Err(format!("no answer within {}s", timeout.as_secs())),
+  };
+
+  match result {
+    Ok(status) => (0, format!("healthy: {}", status)),
+    Err(status) => (1, format!("unhealthy: {}", status)),
+  }
+}
+
+async fn check() -> Result<String, String> {
+  match CONFIG.http_listen {
+    Some(address) => check_http(address).await,
+    None => check_heartbeat().await,
+  }
+}
+
+async fn check_http(mut address: SocketAddr) -> Result<String, String> {
+  if address.ip().is_unspecified() {
+    match address {
+      SocketAddr::V4(_) => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+      SocketAddr::V6(_) => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+    }
+  }
+
+  let url = format!("http://{}/health/ready", address);
+  let resp = hyper::Client::new()
+    .get(url.parse().map_err(|err| format!("{}: {}", url, err))?)
+    .await
+    .map_err(|err| format!("{}: {}", url, err))?;
+  let status = resp.status();
+  let body = hyper::body::to_bytes(resp.into_body())
+    .await
+    .map_err(|err| err.to_string())?;
+  let body = String::from_utf8_lossy(&body);
+
+  if status.is_success() {
+    Ok(body.to_string())
+  } else {
+    Err(format!("{} {}", status, body))
+  }
+}
+
+async fn check_heartbeat() -> Result<String, String> {
+  let mut storage = storage::open().await?;
+  let heartbeat = storage.get("heartbeat").await.map_err(|err| err.to_string())?;
+  heartbeat_status(
+    Utc::now().timestamp(),
+    heartbeat.and_then(|x| x.parse().ok()),
+    CONFIG.ready_max_age,
+  )
+}
+
+/// Whether the heartbeat written at the end of every poll round, in unix
+/// seconds, is recent enough.
+pub fn heartbeat_status(now: i64, heartbeat: Option<i64>, max_age: Duration) -> Result<String, String> {
+  let heartbeat = heartbeat.ok_or_else(|| "no heartbeat recorded".to_string())?;
+  let age = now.saturating_sub(heartbeat);
+  if age <= max_age.as_secs() as i64 {
+    Ok(format!("last poll round {}s ago", age))
+  } else {
+    Err(format!("last poll round {}s ago, over {}s", age, max_age.as_secs()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn heartbeats_older_than_the_max_age_are_unhealthy() {
+    let max_age = Duration::from_secs(60);
+    assert_eq!(
+      heartbeat_status(1_000, Some(940), max_age),
+      Ok("last poll round 60s ago".to_string())
+    );
+    assert_eq!(
+      heartbeat_status(1_000, Some(939), max_age),
+      Err("last poll round 61s ago, over 60s".to_string())
+    );
+    assert_eq!(
+      heartbeat_status(1_000, None, max_age),
+      Err("no heartbeat recorded".to_string())
+    );
+    // A heartbeat from a clock slightly ahead is fresh.
+    assert!(heartbeat_status(1_000, Some(1_005), max_age).is_ok());
+  }
+
+  #[tokio::test]
+  async fn exit_code_follows_the_check() {
+    let healthy = outcome(async { Ok("last poll round 3s ago".to_string()) }, TIMEOUT).await;
+    assert_eq!(healthy, (0, "healthy: last poll round 3s ago".to_string()));
+    let unhealthy = outcome(async { Err("no heartbeat recorded".to_string()) }, TIMEOUT).await;
+    assert_eq!(unhealthy, (1, "unhealthy: no heartbeat recorded".to_string()));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn checks_that_hang_time_out() {
+    let hung = outcome(std::future::pending(), TIMEOUT).await;
+    assert_eq!(hung, (1, "unhealthy: no answer within 2s".to_string()));
+  }
+
+  #[tokio::test]
+  async fn readiness_is_read_from_the_http_endpoint() {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/health/ready"))
+      .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+      .mount(&server)
+      .await;
+    assert_eq!(check_http(*server.address()).await, Ok("ok".to_string()));
+
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/health/ready"))
+      .respond_with(ResponseTemplate::new(503).set_body_string("storage unreachable"))
+      .mount(&server)
+      .await;
+    let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, server.address().port()));
+    assert_eq!(
+      check_http(unspecified).await,
+      Err("503 Service Unavailable storage unreachable".to_string())
+    );
+  }
+}
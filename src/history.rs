@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+
+use crate::center::{CenterId, Slot};
+
+/// Number of times a slot has to reopen before it is considered volatile.
+pub const VOLATILE_REOPEN_THRESHOLD: u32 = 2;
+
+#[derive(Debug, Default, Clone)]
+struct SlotRecord {
+  present: bool,
+  reopen_count: u32,
+}
+
+/// Tracks how often individual slots appear and disappear from the scheduler
+/// so frequently reopening slots can be surfaced separately.
+#[derive(Debug, Default)]
+pub struct SlotHistory {
+  slots: HashMap<(CenterId, String), SlotRecord>,
+}
+
+impl SlotHistory {
+  /// Records the slots seen for a center in one poll and returns the ones that
+  /// just reopened and have reopened often enough to count as volatile.
+  pub fn observe(&mut self, center: CenterId, slots: &[Slot], now: NaiveDateTime) -> Vec<(Slot, u32)> {
+    self.slots.retain(|(_, start_timestamp), _| {
+      matches!(NaiveDateTime::parse_from_str(start_timestamp, "%Y-%m-%dT%H:%M"), Ok(start) if start > now)
+    });
+
+    let mut volatile = Vec::new();
+    for slot in slots {
+      let record = self
+        .slots
+        .entry((center, slot.start_timestamp.clone()))
+        .or_insert(SlotRecord {
+          present: true,
+          reopen_count: 0,
+        });
+
+      if !record.present {
+        record.reopen_count += 1;
+        if record.reopen_count >= VOLATILE_REOPEN_THRESHOLD {
+          volatile.push((slot.clone(), record.reopen_count));
+        }
+      }
+    }
+
+    for ((slot_center, start_timestamp), record) in self.slots.iter_mut() {
+      if *slot_center == center {
+        record.present = slots.iter().any(|x| &x.start_timestamp == start_timestamp);
+      }
+    }
+
+    volatile
+  }
+}
@@ -0,0 +1,215 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::json;
+use tracing::info;
+
+use crate::{admin, health, metrics, selftest, CONFIG, MANAGER};
+
+/// How long `/healthz` waits for the tracking manager and redis to answer.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serves the operational endpoints on `address` until the process exits.
+pub async fn serve(address: SocketAddr) -> Result<(), String> {
+  let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+  let server = Server::try_bind(&address)
+    .map_err(|err| format!("Could not bind http server to {}: {}", address, err))?
+    .serve(make_service);
+  info!("Serving http endpoints on {}", address);
+  server.await.map_err(|err| err.to_string())
+}
+
+/// Whether `request` carries `token` as its bearer token.
+pub fn has_bearer(request: &Request<Body>, token: &str) -> bool {
+  request
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| x.strip_prefix("Bearer "))
+    == Some(token)
+}
+
+fn is_authorized(request: &Request<Body>) -> bool {
+  match &CONFIG.metrics_token {
+    Some(token) => has_bearer(request, token),
+    None => true,
+  }
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+  let mut response = Response::new(Body::from(body));
+  *response.status_mut() = status;
+  response
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+  let mut response = respond(status, body);
+  response
+    .headers_mut()
+    .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+  response
+}
+
+/// Healthy when redis answers a ping through the tracking manager and the
+/// collector finished a cycle within `ready_max_age`.
+async fn healthz() -> Response<Body> {
+  let ping = tokio::time::timeout(PING_TIMEOUT, async {
+    match MANAGER.lock().await.as_mut() {
+      Some(manager) => manager.ping().await.map_err(|err| err.to_string()),
+      None => Err("tracking manager not started".to_string()),
+    }
+  })
+  .await
+  .unwrap_or_else(|_| Err("timed out".to_string()));
+
+  let (collector_ok, mut body) = health::readiness(
+    Instant::now(),
+    &[(
+      "collector",
+      health::COLLECTOR.last_success(),
+      Some(CONFIG.ready_max_age),
+    )],
+  );
+  let healthy = collector_ok && ping.is_ok();
+  body["components"]["redis"] = json!({ "ok": ping.is_ok(), "error": ping.err() });
+  body["status"] = if healthy { "healthy" } else { "unhealthy" }.into();
+  let status = if healthy {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  json_response(status, body.to_string())
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let response = match (request.method(), request.uri().path()) {
+    (&Method::GET, "/metrics") if !is_authorized(&request) => respond(StatusCode::UNAUTHORIZED, String::new()),
+    (&Method::GET, "/metrics") => {
+      let mut response = respond(StatusCode::OK, metrics::render());
+      response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+      response
+    },
+    (&Method::GET, "/health/live") => respond(StatusCode::OK, json!({ "status": "live" }).to_string()),
+    (&Method::GET, "/health/ready") => {
+      let max_age = Some(CONFIG.ready_max_age);
+      let (ready, body) = health::readiness(
+        Instant::now(),
+        &[
+          ("redis", health::REDIS.last_success(), max_age),
+          ("telegram", health::TELEGRAM.last_success(), None),
+          ("collector", health::COLLECTOR.last_success(), max_age),
+        ],
+      );
+      let (ready, mut body) = (ready && selftest::passed(), body);
+      body["self_test"] = selftest::summary();
+      if !ready {
+        body["status"] = "not ready".into();
+      }
+      let status = if ready {
+        StatusCode::OK
+      } else {
+        StatusCode::SERVICE_UNAVAILABLE
+      };
+      json_response(status, body.to_string())
+    },
+    (&Method::GET, "/healthz") => healthz().await,
+    (_, path) if path.starts_with("/admin/") => admin::handle(request).await,
+    _ => respond(StatusCode::NOT_FOUND, String::new()),
+  };
+  Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::Value;
+
+  use super::*;
+  use crate::selftest::{self, Check};
+  use crate::TEST_METRICS_TOKEN;
+
+  async fn get(path: &str) -> (StatusCode, Value) {
+    let request = Request::get(path).body(Body::empty()).unwrap();
+    let response = handle(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+  }
+
+  #[tokio::test]
+  async fn ready_endpoint_follows_components_and_self_test() {
+    assert_eq!(get("/health/live").await.0, StatusCode::OK);
+
+    let (status, body) = get("/health/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["components"]["telegram"]["ok"], false);
+
+    health::REDIS.mark();
+    health::TELEGRAM.mark();
+    health::COLLECTOR.mark();
+    let (status, body) = get("/health/ready").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["status"], "ready");
+
+    selftest::record(vec![Check::new("scheduler api", false, Err("timed out".to_string()))]);
+    let (status, body) = get("/health/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "not ready");
+    assert_eq!(body["self_test"][0]["ok"], false);
+    selftest::record(Vec::new());
+  }
+
+  async fn scrape(token: Option<&str>) -> (StatusCode, String) {
+    let mut request = Request::get("/metrics");
+    if let Some(token) = token {
+      request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = handle(request.body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+  }
+
+  #[tokio::test]
+  async fn metrics_need_the_token() {
+    assert_eq!(scrape(None).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(scrape(Some("guess")).await.0, StatusCode::UNAUTHORIZED);
+    let basic = Request::get("/metrics")
+      .header(AUTHORIZATION, format!("Basic {}", TEST_METRICS_TOKEN))
+      .body(Body::empty())
+      .unwrap();
+    assert_eq!(handle(basic).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+  }
+
+  #[tokio::test]
+  async fn metrics_are_scraped_in_the_text_format() {
+    metrics::POLLS.with_label_values(&["niagara", "ok"]).inc();
+    metrics::NOTIFICATIONS.with_label_values(&["sent"]).inc();
+    metrics::FETCH_DURATION.observe(0.2);
+    metrics::USERS.set(3);
+
+    let (status, body) = scrape(Some(TEST_METRICS_TOKEN)).await;
+    assert_eq!(status, StatusCode::OK);
+    for (series, kind) in [
+      ("nexus_polls_total", "counter"),
+      ("nexus_notifications_total", "counter"),
+      ("nexus_slot_fetch_duration_seconds", "histogram"),
+      ("nexus_users", "gauge"),
+    ] {
+      assert!(
+        body.contains(&format!("# TYPE {} {}\n", series, kind)),
+        "{} {} missing from:\n{}",
+        series,
+        kind,
+        body
+      );
+    }
+    assert!(body.contains("nexus_polls_total{center=\"niagara\",result=\"ok\"} "));
+    assert!(body.contains("nexus_slot_fetch_duration_seconds_bucket{le=\"+Inf\"} "));
+  }
+}
@@ -0,0 +1,1993 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::panic;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use center::CentersConfig;
+use chrono::{Local, NaiveDate, NaiveTime, Utc};
+use clap::Parser;
+use lazy_static::lazy_static;
+use teloxide::dispatching::ShutdownToken;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup, MessageKind, ParseMode};
+use teloxide::utils::command::BotCommands;
+use teloxide::utils::markdown::{code_block, escape};
+use teloxide::RequestError;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::cache::TtlCache;
+use crate::center::{
+  fetch_centers, fetch_slots, match_center, merge_fetched_centers, normalize_name, paginate, render_center_groups,
+  resolve_center, resolve_region, service_suffix, split_closed, Center, CenterDataCollectorTask, CenterId, CenterMatch,
+  Country, HttpsClient, Region, ScheduleSlots, Service, MESSAGE_LIMIT,
+};
+use crate::closure::Closure;
+use crate::config::{Cli, CliCommand, Config};
+use crate::polling::Backlog;
+use crate::tracking::{NotificationMode, Stats, TrackingError, TrackingManager, UserData, UserId};
+mod admin;
+mod breaker;
+mod cache;
+pub mod center;
+mod closure;
+mod config;
+mod health;
+mod healthcheck;
+mod history;
+mod http;
+mod logging;
+mod metrics;
+mod polling;
+mod report;
+mod selftest;
+pub mod storage;
+mod systemd;
+pub mod tracking;
+mod webhook;
+
+/// Centers file read when none is configured, before the bundled copy.
+const DEFAULT_CENTERS_PATH: &str = "centers.toml";
+
+lazy_static! {
+  static ref KNOWN_CENTERS: RwLock<Arc<KnownCenters>> = RwLock::new(Arc::new(KnownCenters::new(
+    load_centers().unwrap_or_else(|err| panic!("Invalid centers configuration:\n{}", err)),
+    Vec::new()
+  )));
+  pub static ref MANAGER: Mutex<Option<TrackingManager>> = Mutex::new(None);
+  static ref CLI: Cli = parse_cli();
+  pub static ref CONFIG: Config =
+    Config::load(CLI.config.as_deref(), CLI.run_args()).unwrap_or_else(|err| panic!("Invalid configuration: {}", err));
+  pub static ref SLOT_CACHE: Mutex<TtlCache<CenterId, ScheduleSlots>> =
+    Mutex::new(TtlCache::new(CONFIG.slot_cache_ttl));
+}
+
+/// Arguments parsed instead of the process' own, see [`set_cli_args`].
+static CLI_ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+#[cfg(not(test))]
+fn parse_cli() -> Cli {
+  match CLI_ARGS.get() {
+    Some(args) => Cli::parse_from(args),
+    None => Cli::parse(),
+  }
+}
+
+/// Makes the configuration come from `args` rather than the command line, for
+/// harnesses like the benchmarks whose own arguments aren't for the bot. Has
+/// no effect once the configuration was read.
+pub fn set_cli_args(args: &[&str]) {
+  let _ = CLI_ARGS.set(args.iter().map(|x| x.to_string()).collect());
+}
+
+/// Tests get the test harness' arguments, so they run with the defaults,
+/// except for in memory storage, a scheduler api that can't be reached, the
+/// admin api enabled with [`TEST_ADMIN_TOKEN`], [`TEST_ADMIN_ID`] as the only
+/// bot admin, metrics behind [`TEST_METRICS_TOKEN`] and the extra centers in
+/// `tests/fixtures/centers.d`.
+#[cfg(test)]
+fn parse_cli() -> Cli {
+  Cli::parse_from([
+    "nexus-pls",
+    "run",
+    "--storage-backend",
+    "memory",
+    "--api-base",
+    "http://127.0.0.1:9",
+    "--admin-token",
+    TEST_ADMIN_TOKEN,
+    "--metrics-token",
+    TEST_METRICS_TOKEN,
+    "--admin-ids",
+    "9990",
+    "--centers-dir",
+    concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/centers.d"),
+    "--dry-run",
+  ])
+}
+
+#[cfg(test)]
+pub const TEST_ADMIN_TOKEN: &str = "test-admin-token";
+#[cfg(test)]
+pub const TEST_METRICS_TOKEN: &str = "test-metrics-token";
+#[cfg(test)]
+pub const TEST_ADMIN_ID: UserId = 9990;
+
+/// Starts the shared tracking manager on in memory storage unless an earlier
+/// test already did.
+#[cfg(test)]
+pub async fn start_test_manager() {
+  let mut lock = MANAGER.lock().await;
+  if lock.is_none() {
+    *lock = Some(TrackingManager::new(Box::new(storage::MemoryStorage::default())).await);
+  }
+}
+
+/// The configured centers and regions plus the centers last fetched from the
+/// locations api, replaced as a whole so readers never see a mix.
+struct KnownCenters {
+  configured: Vec<Center>,
+  fetched: Vec<Center>,
+  centers: Arc<Vec<Center>>,
+  lut: Arc<HashMap<CenterId, Center>>,
+  regions: Arc<Vec<Region>>,
+}
+
+impl KnownCenters {
+  fn new(config: CentersConfig, fetched: Vec<Center>) -> Self {
+    let centers = merge_fetched_centers(&config.centers, fetched.clone());
+    Self {
+      configured: config.centers,
+      fetched,
+      lut: Arc::new(centers.iter().map(|x| (x.id, x.clone())).collect()),
+      centers: Arc::new(centers),
+      regions: Arc::new(config.regions),
+    }
+  }
+}
+
+/// Loads the configured centers file, or `centers.toml` if it exists when
+/// none is configured, falling back to the bundled `centers.toml`. Merged
+/// with the files in the centers directory.
+fn load_centers() -> Result<CentersConfig, String> {
+  let path = CONFIG
+    .centers_path
+    .as_deref()
+    .or_else(|| Some(Path::new(DEFAULT_CENTERS_PATH)).filter(|x| x.exists()));
+  match path {
+    Some(path) => {
+      let contents = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+      CentersConfig::load((&path.display().to_string(), &contents), &CONFIG.centers_dir)
+    },
+    None => CentersConfig::load(("centers.toml", include_str!("../centers.toml")), &CONFIG.centers_dir),
+  }
+}
+
+/// The centers currently known, configured ones first.
+pub fn centers() -> Arc<Vec<Center>> {
+  KNOWN_CENTERS.read().unwrap().centers.clone()
+}
+
+/// The centers currently known, by id.
+pub fn center_lut() -> Arc<HashMap<CenterId, Center>> {
+  KNOWN_CENTERS.read().unwrap().lut.clone()
+}
+
+pub fn regions() -> Arc<Vec<Region>> {
+  KNOWN_CENTERS.read().unwrap().regions.clone()
+}
+
+/// Replaces the known centers with the configured ones plus those listed by
+/// the locations api, returning how many came from the api. Subscribers are
+/// reindexed before the tracking lock is released, so polls never pair the
+/// new centers with the old index.
+async fn refresh_centers(client: &HttpsClient) -> Result<usize, String> {
+  let fetched = fetch_centers(client, &CONFIG.api_base).await?;
+  let mut lock = MANAGER.lock().await;
+  let known = {
+    let mut known = KNOWN_CENTERS.write().unwrap();
+    let config = CentersConfig {
+      centers: known.configured.clone(),
+      regions: known.regions.to_vec(),
+    };
+    *known = Arc::new(KnownCenters::new(config, fetched));
+    known.clone()
+  };
+  if let Some(manager) = lock.as_mut() {
+    manager.rebuild_subscriber_index();
+  }
+  Ok(known.centers.len() - known.configured.len())
+}
+
+/// Reads the centers files again, keeping the centers last fetched from the
+/// locations api, and reindexes subscribers like [`refresh_centers`]. The
+/// current centers stay when the files are invalid.
+async fn reload_centers() -> Result<(usize, usize), String> {
+  let config = load_centers()?;
+  let mut lock = MANAGER.lock().await;
+  let known = {
+    let mut known = KNOWN_CENTERS.write().unwrap();
+    *known = Arc::new(KnownCenters::new(config, known.fetched.clone()));
+    known.clone()
+  };
+  if let Some(manager) = lock.as_mut() {
+    manager.rebuild_subscriber_index();
+  }
+  Ok((known.configured.len(), known.regions.len()))
+}
+
+/// Reports panics through tracing so they end up in the same log stream.
+fn log_panic(info: &panic::PanicHookInfo) {
+  let message = info
+    .payload()
+    .downcast_ref::<&str>()
+    .map(|x| x.to_string())
+    .or_else(|| info.payload().downcast_ref::<String>().cloned())
+    .unwrap_or_default();
+  let location = info.location().map(|x| x.to_string()).unwrap_or_default();
+  error!(location = location.as_str(), "Panicked: {}", message);
+  report::report("panic", &format!("{} at {}", message, location));
+}
+
+/// Runs the subcommand given on the command line, by default the bot itself.
+pub async fn run() {
+  lazy_static::initialize(&CLI);
+  lazy_static::initialize(&polling::STARTED_AT);
+  if let Some(CliCommand::PrintConfig { defaults, .. }) = &CLI.command {
+    if *defaults {
+      println!("{}", Config::default().annotated());
+    } else {
+      println!("{}", CONFIG.annotated());
+    }
+    return;
+  }
+  if let Some(CliCommand::Healthcheck { .. }) = &CLI.command {
+    std::process::exit(healthcheck::run().await);
+  }
+
+  lazy_static::initialize(&CONFIG);
+  logging::init(CONFIG.log_format);
+  panic::set_hook(Box::new(log_panic));
+  info!("Starting Nexus Pls");
+  logging::spawn_signal_handler();
+
+  if let Some(url) = &CONFIG.error_report_url {
+    report::start(url.clone());
+  }
+
+  info!("Configuring Https Client");
+  let https = hyper_rustls::HttpsConnectorBuilder::new()
+    .with_native_roots()
+    // Plain http is only ever used when api_base points at a local mock.
+    .https_or_http()
+    .enable_http1()
+    .build();
+  let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+
+  info!("Configuring Telegram Bot");
+  if env::var("TELOXIDE_TOKEN").is_err() {
+    panic!("Could not parse or find Bot token TELOXIDE_TOKEN");
+  }
+  let bot = Bot::from_env().auto_send();
+  info!("Telegram Bot Configured");
+
+  info!("Running Self Test");
+  let checks = selftest::run(&client, &bot).await;
+  let can_start = selftest::can_start(&checks, CONFIG.allow_degraded);
+  selftest::record(checks);
+  if !can_start {
+    error!("Self test failed, refusing to start");
+    std::process::exit(1);
+  } else if !selftest::passed() {
+    warn!("Self test failed, starting degraded");
+  }
+
+  lazy_static::initialize(&KNOWN_CENTERS);
+  match refresh_centers(&client).await {
+    Ok(fetched) => info!("Added {} centers from the locations api", fetched),
+    Err(err) => warn!("Could not fetch centers, using the configured list only: {}", err),
+  }
+
+  {
+    info!("Configuring Tracking Manager");
+    let mut lock = MANAGER.lock().await;
+    match storage::open().await {
+      Ok(storage) => *lock = Some(TrackingManager::new(storage).await),
+      Err(err) => {
+        error!("{}", err);
+        std::process::exit(1);
+      },
+    }
+    info!("Finished Configuring Tracking Manager");
+  }
+  systemd::ready();
+
+  let handler = dptree::entry()
+    .branch(Update::filter_message().filter_command::<Command>().endpoint(answer))
+    .branch(Update::filter_callback_query().endpoint(answer_callback));
+  let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+    .dependencies(dptree::deps![client.clone()])
+    .default_handler(|update| async move { polling::complete(update.id).await })
+    .build();
+  spawn_shutdown_handler(dispatcher.shutdown_token());
+
+  let listener = match &CONFIG.webhook_url {
+    Some(url) => {
+      info!("Registering Webhook");
+      webhook::register(&client, bot.inner().token(), url, CONFIG.webhook_secret.as_deref())
+        .await
+        .unwrap_or_else(|err| panic!("Could not register webhook: {}", err));
+      Some(
+        webhook::listen(url, CONFIG.webhook_listen, CONFIG.webhook_secret.clone())
+          .unwrap_or_else(|err| panic!("{}", err)),
+      )
+    },
+    None => None,
+  };
+
+  if let Some(address) = CONFIG.http_listen {
+    tokio::spawn(async move {
+      if let Err(err) = http::serve(address).await {
+        warn!("Http server failed: {}", err);
+      }
+    });
+  }
+
+  info!("Starting Async Jobs");
+  let dispatch = async {
+    match listener {
+      Some(listener) => {
+        dispatcher
+          .dispatch_with_listener(
+            listener,
+            LoggingErrorHandler::with_custom_text("Webhook listener error"),
+          )
+          .await
+      },
+      None => {
+        dispatcher
+          .dispatch_with_listener(
+            polling::listen(bot.clone()),
+            LoggingErrorHandler::with_custom_text("Polling error"),
+          )
+          .await
+      },
+    }
+  };
+  let mut collector = CenterDataCollectorTask::new(client, bot.clone(), CONFIG.poll_interval, CONFIG.api_base.clone());
+  systemd::spawn_watchdog();
+  tokio::select! {
+    _ = &mut collector => {},
+    _ = dispatch => {}
+  };
+  systemd::stopping();
+  collector.shutdown().await;
+  let unsaved = MANAGER.lock().await.as_mut().unwrap().save_unsaved().await;
+  if unsaved > 0 {
+    warn!("Could not save {} users changed while storage was down", unsaved);
+  }
+
+  if CONFIG.webhook_url.is_some() {
+    info!("Removing Webhook");
+    if let Err(err) = bot.delete_webhook().await {
+      warn!("Could not remove webhook: {}", err);
+    }
+  }
+  info!("Exiting, Goodbye!");
+}
+
+/// Stops the dispatcher on SIGINT or SIGTERM, letting running commands finish
+/// before the collector is drained.
+fn spawn_shutdown_handler(token: ShutdownToken) {
+  let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+    Ok(signals) => Some(signals),
+    Err(err) => {
+      warn!("Could not listen for SIGTERM: {}", err);
+      None
+    },
+  };
+
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down"),
+        Some(_) = async { terminate.as_mut()?.recv().await } => info!("SIGTERM received, shutting down"),
+      }
+      match token.shutdown() {
+        Ok(stopped) => stopped.await,
+        Err(_) => info!("Already shutting down"),
+      }
+    }
+  });
+}
+
+#[derive(BotCommands, Clone)]
+#[command(rename = "lowercase", description = "These commands are supported:")]
+enum Command {
+  #[command(description = "display this text.")]
+  Help,
+  #[command(description = "list centers to track, optionally only those in canada or usa.")]
+  List(String),
+  #[command(
+    description = "begins to track a center on your behalf, optionally for ge, sentri or fast instead of nexus."
+  )]
+  Track(String),
+  #[command(description = "stops tracking a center on your behalf.")]
+  UnTrack(String),
+  #[command(description = "stops tracking every center and region on your behalf.")]
+  UnTrackAll,
+  #[command(description = "lists the status of your tracked centers and when each last alerted you.")]
+  Status,
+  #[command(description = "include the center address as a map link in notifications (on/off).")]
+  MapLink(String),
+  #[command(description = "alert separately when a frequently reopening slot appears (on/off).")]
+  Volatile(String),
+  #[command(description = "only notify you until a date (YYYY-MM-DD), or off.")]
+  ActiveUntil(String),
+  #[command(description = "shows details about a center.")]
+  Info(String),
+  #[command(
+    description = "checks a center for available appointments right now, optionally for a service (ge, sentri, fast)."
+  )]
+  Slots(String),
+  #[command(description = "shows the soonest available appointment at a center.")]
+  Next(String),
+  #[command(description = "pauses notifications while keeping your tracked centers.")]
+  Mute,
+  #[command(description = "resumes notifications paused with /mute.")]
+  Unmute,
+  #[command(description = "pauses alerts for one tracked center for a while, e.g. /snooze YUL 2d.")]
+  Snooze(String),
+  #[command(description = "resumes alerts for a center paused with /snooze.")]
+  Unsnooze(String),
+  #[command(description = "only notify you about slots between two dates (YYYY-MM-DD YYYY-MM-DD), or clear.")]
+  Window(String),
+  #[command(description = "holds notifications between two times (HH:MM HH:MM [UTC offset]), or off.")]
+  QuietHours(String),
+  #[command(
+    description = "only alert for slots earlier than the soonest seen (on), before a date (YYYY-MM-DD), or off."
+  )]
+  Threshold(String),
+  #[command(description = "get alerts as slots open (instant) or one summary a day (digest).")]
+  Mode(String),
+  #[command(description = "sets the starting point for directions in notifications, or off.")]
+  Home(String),
+  #[command(description = "shows the configuration the bot is running with (admin only).")]
+  Config,
+  #[command(description = "marks a center closed on a date or range, e.g. 2023-02-20..2023-02-24 (admin only).")]
+  AddClosure(String),
+  #[command(description = "shows the bot version and startup self test results.")]
+  Version,
+  #[command(description = "sets the log level, optionally for one module, for 15 minutes, or reset (admin only).")]
+  LogLevel(String),
+  #[command(description = "reloads the center list from the locations api (admin only).")]
+  RefreshCenters,
+  #[command(description = "reloads the centers file and centers directory (admin only).")]
+  Reload,
+  #[command(description = "shows users, subscriptions and polled centers (admin only).")]
+  Stats,
+}
+
+fn sender_id(message: &Message) -> Option<UserId> {
+  if let MessageKind::Common(message) = &message.kind {
+    message.from.as_ref().map(|from_user| from_user.id.0)
+  } else {
+    None
+  }
+}
+
+fn sender_is_admin(message: &Message) -> bool {
+  matches!(sender_id(message), Some(user) if CONFIG.is_admin(user))
+}
+
+/// Most buttons attached to a `/list` reply, Telegram rejects much larger
+/// keyboards.
+const LIST_BUTTON_LIMIT: usize = 100;
+
+fn tracking_button(center: &Center, tracking: bool) -> InlineKeyboardButton {
+  if tracking {
+    InlineKeyboardButton::callback(
+      format!("Untrack {}", center.short_name),
+      format!("untrack:{}", center.id),
+    )
+  } else {
+    InlineKeyboardButton::callback(format!("Track {}", center.short_name), format!("track:{}", center.id))
+  }
+}
+
+fn tracking_keyboard(center: &Center, tracking: bool) -> InlineKeyboardMarkup {
+  InlineKeyboardMarkup::new(vec![vec![tracking_button(center, tracking)]])
+}
+
+/// Track or untrack buttons for every center of a `/list` reply, two to a row.
+fn list_keyboard(centers: &[&Center], tracked: &[CenterId]) -> InlineKeyboardMarkup {
+  InlineKeyboardMarkup::new(
+    centers
+      .chunks(2)
+      .map(|row| {
+        row
+          .iter()
+          .map(|x| tracking_button(x, tracked.contains(&x.id)))
+          .collect::<Vec<_>>()
+      })
+      .collect::<Vec<_>>(),
+  )
+}
+
+/// `markup` with the button for `center` flipped to match `tracking`, leaving
+/// the buttons of other centers alone.
+fn toggle_button(markup: &InlineKeyboardMarkup, center: &Center, tracking: bool) -> InlineKeyboardMarkup {
+  let ids = [format!("track:{}", center.id), format!("untrack:{}", center.id)];
+  InlineKeyboardMarkup::new(markup.inline_keyboard.iter().map(|row| {
+    row
+      .iter()
+      .map(|button| match &button.kind {
+        InlineKeyboardButtonKind::CallbackData(data) if ids.contains(data) => tracking_button(center, tracking),
+        _ => button.clone(),
+      })
+      .collect::<Vec<_>>()
+  }))
+}
+
+/// Splits a trailing service such as `ge` off a `/track` query, unless the
+/// whole query already names a center.
+fn split_service<'a>(centers: &[Center], query: &'a str) -> (&'a str, Service) {
+  if resolve_center(centers, query).is_none() {
+    if let Some((rest, service)) = query.trim().rsplit_once(' ') {
+      if let Some(service) = Service::from_filter(service) {
+        return (rest, service);
+      }
+    }
+  }
+  (query, Service::default())
+}
+
+/// Looks up the center a command refers to, leaving exact region names to
+/// the commands that accept regions.
+fn lookup_center<'a>(centers: &'a [Center], query: &str) -> CenterMatch<'a> {
+  match resolve_center(centers, query) {
+    Some(center) => CenterMatch::Found(center),
+    None if resolve_region(&regions(), query).is_some() => CenterMatch::Missing(Vec::new()),
+    None => match_center(centers, query),
+  }
+}
+
+/// Explains why `query` didn't pick out a single center.
+fn center_lookup_msg(query: &str, found: &CenterMatch) -> String {
+  match found {
+    CenterMatch::Ambiguous(candidates) => format!(
+      "\"{}\" matches several centers, please use one of: {}",
+      query.trim(),
+      candidates
+        .iter()
+        .map(|x| format!("{} ({})", x.short_name, x.full_name))
+        .collect::<Vec<_>>()
+        .join(", ")
+    ),
+    CenterMatch::Missing(close) if !close.is_empty() => format!(
+      "Could not find center \"{}\". Did you mean {}?",
+      query.trim(),
+      close
+        .iter()
+        .map(|x| x.short_name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    ),
+    _ => center_not_found_msg(query),
+  }
+}
+
+fn center_not_found_msg(query: &str) -> String {
+  format!(
+    "Could not find center \"{}\". Use /list to see the short names of all centers.",
+    query.trim()
+  )
+}
+
+fn parse_toggle(value: &str) -> Option<bool> {
+  match value.trim().to_lowercase().as_str() {
+    "on" => Some(true),
+    "off" => Some(false),
+    _ => None,
+  }
+}
+
+/// Longest a center can be snoozed for.
+const MAX_SNOOZE_DAYS: i64 = 30;
+
+/// Parses a snooze length such as `30m`, `6h` or `2d`.
+fn parse_snooze(value: &str) -> Option<chrono::Duration> {
+  let (amount, unit) = value.split_at_checked(value.len().checked_sub(1)?)?;
+  let amount = amount.parse::<i64>().ok().filter(|x| *x > 0)?;
+  let duration = match unit {
+    "m" => chrono::Duration::minutes(amount),
+    "h" => chrono::Duration::hours(amount),
+    "d" => chrono::Duration::days(amount),
+    _ => return None,
+  };
+  Some(duration).filter(|x| *x <= chrono::Duration::days(MAX_SNOOZE_DAYS))
+}
+
+/// Parses a UTC offset such as `+5`, `-03:30` or `UTC+1` into minutes.
+fn parse_utc_offset(value: &str) -> Option<i32> {
+  let value = value
+    .strip_prefix("UTC")
+    .or_else(|| value.strip_prefix("utc"))
+    .unwrap_or(value);
+  let (sign, rest) = match value.split_at_checked(1)? {
+    ("+", rest) => (1, rest),
+    ("-", rest) => (-1, rest),
+    _ => return None,
+  };
+  let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+  let hours = hours.parse::<i32>().ok().filter(|x| *x <= 14)?;
+  let minutes = minutes.parse::<i32>().ok().filter(|x| *x < 60)?;
+  Some(sign * (hours * 60 + minutes))
+}
+
+fn describe_utc_offset(offset: Option<i32>) -> String {
+  match offset {
+    Some(offset) => format!(
+      "UTC{}{:02}:{:02}",
+      if offset < 0 { '-' } else { '+' },
+      offset.abs() / 60,
+      offset.abs() % 60
+    ),
+    None => "the bot's local time".to_string(),
+  }
+}
+
+/// What to tell the user about a failed tracking change. Storage failures are
+/// logged rather than shown.
+fn tracking_error_msg(err: &TrackingError) -> String {
+  if err.is_internal() {
+    warn!("Tracking change failed: {}", err);
+  }
+  err.user_message()
+}
+
+/// Sends MarkdownV2 `pages` one after another, returning the last message.
+async fn send_pages(bot: &AutoSend<Bot>, chat: ChatId, pages: Vec<String>) -> Result<Message, RequestError> {
+  let mut pages = pages.into_iter().peekable();
+  loop {
+    let sent = bot
+      .send_message(chat, pages.next().unwrap_or_default())
+      .parse_mode(ParseMode::MarkdownV2)
+      .await?;
+    if pages.peek().is_none() {
+      return Ok(sent);
+    }
+  }
+}
+
+/// MarkdownV2 sections of the `/stats` reply, with subscriptions per center
+/// as a fixed width table.
+fn stats_sections(stats: &Stats) -> Vec<String> {
+  let lut = center_lut();
+  let mut summary = vec![
+    format!("Users: {} ({} active)", stats.users, stats.active_users),
+    format!("Polled centers: {}", stats.polled_centers),
+  ];
+  if let Some(keys) = stats.storage_keys {
+    summary.push(format!("Redis keys: {}", keys));
+  }
+  let mut sections = vec![format!("*Stats*\n{}", escape(&summary.join("\n")))];
+
+  let rows = stats
+    .centers
+    .iter()
+    .map(|(center, users, soonest)| {
+      let name = lut
+        .get(center)
+        .map_or_else(|| center.to_string(), |x| x.short_name.clone());
+      let soonest = soonest.as_ref().map_or("-", |x| x.start_timestamp.as_str());
+      format!("{:<16} {:>5}  {}", name, users, soonest)
+    })
+    .collect::<Vec<_>>();
+  // Pages are split between lines, so each chunk of rows gets its own block.
+  for chunk in rows.chunks(40) {
+    let mut table = vec![format!("{:<16} {:>5}  {}", "Center", "Users", "Soonest")];
+    table.extend(chunk.iter().cloned());
+    sections.push(code_block(&table.join("\n")));
+  }
+  sections
+}
+
+/// The command a message invokes, without the leading slash or bot name.
+fn command_name(message: &Message) -> String {
+  let command = message
+    .text()
+    .and_then(|x| x.split_whitespace().next())
+    .unwrap_or_default();
+  let command = command.trim_start_matches('/');
+  command.split('@').next().unwrap_or_default().to_lowercase()
+}
+
+/// Handles a command, acknowledging ones sent while the bot was down and
+/// refusing to act on ones older than the configured maximum age.
+async fn answer(
+  bot: AutoSend<Bot>,
+  message: Message,
+  command: Command,
+  update: Update,
+  client: HttpsClient,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  metrics::COMMANDS.with_label_values(&[&command_name(&message)]).inc();
+  let result = answer_backlogged(bot, message, command, &client).await;
+  // A failed reply isn't retried after a restart either, the user sees the
+  // error and can send the command again.
+  polling::complete(update.id).await;
+  result
+}
+
+async fn answer_backlogged(
+  bot: AutoSend<Bot>,
+  message: Message,
+  command: Command,
+  client: &HttpsClient,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  match polling::backlog(message.date, *polling::STARTED_AT, Utc::now(), CONFIG.max_update_age) {
+    Backlog::TooOld => {
+      bot
+        .send_message(
+          message.chat.id,
+          format!(
+            "Your message \"{}\" arrived while the bot was unavailable and is too old to act on, please send it again.",
+            message.text().unwrap_or_default()
+          ),
+        )
+        .await?;
+    },
+    Backlog::Late => {
+      bot
+        .send_message(
+          message.chat.id,
+          format!(
+            "Processing your earlier message \"{}\"…",
+            message.text().unwrap_or_default()
+          ),
+        )
+        .await?;
+      run_command(bot, message, command, client).await?;
+    },
+    Backlog::Current => run_command(bot, message, command, client).await?,
+  }
+  Ok(())
+}
+
+async fn run_command(
+  bot: AutoSend<Bot>,
+  message: Message,
+  command: Command,
+  client: &HttpsClient,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  match command {
+    Command::Help => {
+      bot
+        .send_message(message.chat.id, Command::descriptions().to_string())
+        .await?
+    },
+    Command::List(filter) => {
+      let all = centers();
+      let country = Country::from_filter(&filter);
+      let service = Service::from_filter(&filter).filter(|_| country.is_none());
+      let state = normalize_name(&filter);
+      let in_state = |x: &Center| x.state().is_some_and(|x| normalize_name(&x) == state);
+      let matches = |x: &Center| match (country, service) {
+        (Some(country), _) => x.country == country,
+        (None, Some(service)) => x.offers(service),
+        (None, None) => in_state(x),
+      };
+      let centers = all
+        .iter()
+        .filter(|x| x.enabled && (filter.trim().is_empty() || matches(x)))
+        .collect::<Vec<_>>();
+      let (closed, tracked) = {
+        let mut lock = MANAGER.lock().await;
+        let manager = lock.as_mut().unwrap();
+        let tracked = match sender_id(&message) {
+          Some(user) => Some(
+            manager
+              .get_user_data(user)
+              .await
+              .ok()
+              .flatten()
+              .map_or_else(Vec::new, |x| x.subscriptions.clone()),
+          ),
+          None => None,
+        };
+        (manager.get_closed_centers().clone(), tracked)
+      };
+      let mut sections = render_center_groups(centers.iter().copied(), &closed);
+      let regions = regions();
+      if filter.trim().is_empty() && !regions.is_empty() {
+        let lines = regions.iter().map(|x| x.status_line(&all)).collect::<Vec<_>>();
+        sections.push(format!("*Regions*\n{}", lines.join("\n")));
+      }
+
+      if sections.is_empty() {
+        bot
+          .send_message(
+            message.chat.id,
+            "Usage: /list, /list canada, /list usa, /list <service> or /list <state or province>".to_string(),
+          )
+          .await?
+      } else {
+        let pages = paginate(sections, MESSAGE_LIMIT);
+        match tracked {
+          Some(tracked) if pages.len() == 1 && centers.len() <= LIST_BUTTON_LIMIT => {
+            bot
+              .send_message(message.chat.id, pages[0].clone())
+              .parse_mode(ParseMode::MarkdownV2)
+              .reply_markup(list_keyboard(&centers, &tracked))
+              .await?
+          },
+          _ => send_pages(&bot, message.chat.id, pages).await?,
+        }
+      }
+    },
+    Command::Track(query) => {
+      let user = sender_id(&message);
+      let all = centers();
+      let (query, service) = split_service(&all, &query);
+      let found = lookup_center(&all, query);
+      let center = found.center();
+      let regions = regions();
+
+      match (center, user) {
+        (None, Some(_)) if service != Service::Nexus && resolve_region(&regions, query).is_some() => {
+          bot
+            .send_message(message.chat.id, "Regions can only be tracked for NEXUS.".to_string())
+            .await?
+        },
+        (None, Some(user)) if resolve_region(&regions, query).is_some() => {
+          let region = resolve_region(&regions, query).unwrap();
+          let reply = match MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .track_region(message.chat.id.0, user, &region.name)
+            .await
+          {
+            Ok(_) => format!("Now tracking every center in {} on your behalf", region.name),
+            Err(err) => tracking_error_msg(&err),
+          };
+          bot.send_message(message.chat.id, reply).await?
+        },
+        (None, _) => {
+          bot
+            .send_message(message.chat.id, center_lookup_msg(query, &found))
+            .await?
+        },
+        (Some(center), _) if !center.enabled => bot.send_message(message.chat.id, center.disabled_msg()).await?,
+        (Some(center), _) if !center.offers(service) => {
+          bot
+            .send_message(
+              message.chat.id,
+              format!("{} does not offer {} appointments.", center.full_name, service.name()),
+            )
+            .await?
+        },
+        (_, None) => {
+          bot
+            .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+            .await?
+        },
+        (Some(center), Some(user)) => {
+          if let Err(err) = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .track_center(message.chat.id.0, user, center.id, service)
+            .await
+          {
+            bot.send_message(message.chat.id, tracking_error_msg(&err)).await?
+          } else {
+            bot
+              .send_message(
+                message.chat.id,
+                format!(
+                  "Now tracking {} for {} on your behalf",
+                  center.full_name,
+                  service.name()
+                ),
+              )
+              .await?
+          }
+        },
+      }
+    },
+    Command::UnTrack(query) => {
+      let user = sender_id(&message);
+      let all = centers();
+      let found = lookup_center(&all, &query);
+      let center = found.center();
+      let regions = regions();
+
+      match (center, user) {
+        (None, Some(user)) if resolve_region(&regions, &query).is_some() => {
+          let region = resolve_region(&regions, &query).unwrap();
+          let reply = match MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .untrack_region(user, &region.name)
+            .await
+          {
+            Ok(_) => format!("Stopped tracking {} on your behalf", region.name),
+            Err(err) => tracking_error_msg(&err),
+          };
+          bot.send_message(message.chat.id, reply).await?
+        },
+        (None, _) => {
+          bot
+            .send_message(message.chat.id, center_lookup_msg(&query, &found))
+            .await?
+        },
+        (_, None) => {
+          bot
+            .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+            .await?
+        },
+        (Some(center), Some(user)) => {
+          if let Err(err) = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .untrack_center(user, center.id)
+            .await
+          {
+            bot.send_message(message.chat.id, tracking_error_msg(&err)).await?
+          } else {
+            bot
+              .send_message(
+                message.chat.id,
+                format!("Stopped tracking {} on your behalf", center.full_name),
+              )
+              .await?
+          }
+        },
+      }
+    },
+    Command::UnTrackAll => {
+      let reply = match sender_id(&message) {
+        Some(user) => match MANAGER.lock().await.as_mut().unwrap().untrack_all(user).await {
+          Ok(1) => "Stopped tracking 1 center on your behalf".to_string(),
+          Ok(removed) => format!("Stopped tracking {} centers on your behalf", removed),
+          Err(TrackingError::NoSubscriptions) => "Nothing to clear, you are not tracking any centers.".to_string(),
+          Err(err) => tracking_error_msg(&err),
+        },
+        None => "Could not understand who sent this?".to_string(),
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Status => {
+      let user = sender_id(&message);
+
+      if let Some(user) = user {
+        let (closed, user_data) = {
+          let mut lock = MANAGER.lock().await;
+          let manager = lock.as_mut().unwrap();
+          let closed = manager.get_closed_centers().clone();
+          (closed, manager.get_user_data(user).await.map(|x| x.cloned()))
+        };
+        if let Ok(user_data) = user_data {
+          let list = user_data.as_ref();
+          let mut center_list = list
+            .map_or(&Vec::new(), |u| &u.subscriptions)
+            .iter()
+            .filter_map(|x| center_lut().get(x).cloned())
+            .map(|x| {
+              let service = list.map_or(Service::Nexus, |u| u.service_for(x.id));
+              let snoozed = list
+                .and_then(|u| u.snoozed.get(&x.id))
+                .filter(|until| **until > Local::now().naive_local())
+                .map_or_else(String::new, |until| {
+                  escape(&format!(" (snoozed until {})", until.format("%b %-d %H:%M")))
+                });
+              let last_notified = match list.and_then(|u| u.last_notified.get(&x.id)) {
+                Some(last) => format!(
+                  "last alert {}, slot {}",
+                  last.at.format("%b %-d %H:%M"),
+                  last.slot.format("%b %-d %H:%M")
+                ),
+                None => "last alert never".to_string(),
+              };
+              format!(
+                "{}{}{}\n  {}",
+                x.status_line(&closed),
+                service_suffix(service),
+                snoozed,
+                escape(&last_notified)
+              )
+            })
+            .collect::<Vec<_>>();
+          center_list.sort();
+
+          let regions = regions();
+          center_list.extend(
+            list
+              .map_or(&Vec::new(), |u| &u.regions)
+              .iter()
+              .filter_map(|x| resolve_region(&regions, x))
+              .map(|x| format!("Region {}", x.status_line(&centers()))),
+          );
+
+          if center_list.is_empty() {
+            center_list.push("None".to_string());
+          }
+
+          let mut sections = vec![format!("Your Tracked Centers\n{}", center_list.join("\n"))];
+          if let Some(user_data) = list.filter(|u| u.quiet_hours.is_some()) {
+            let (start, end) = user_data.quiet_hours.unwrap();
+            sections.push(escape(&format!(
+              "Quiet hours {} to {}, {}",
+              start.format("%H:%M"),
+              end.format("%H:%M"),
+              describe_utc_offset(user_data.utc_offset)
+            )));
+          }
+          if list.is_some_and(|u| u.notification_mode == NotificationMode::Digest) {
+            sections.push(escape(&format!(
+              "Daily digest at {}, use /mode instant for alerts as slots open",
+              CONFIG.digest_time.format("%H:%M")
+            )));
+          }
+          if list.is_some_and(|u| u.earlier_only) {
+            sections.push(escape(
+              "Only alerting for slots sooner than the soonest seen, use /threshold off for every new slot",
+            ));
+          }
+          if list.is_some_and(|u| u.muted) {
+            sections.push("Notifications are muted, use /unmute to resume them".to_string());
+          }
+          if let Some(window) = list.and_then(|u| u.window_description()) {
+            sections.push(escape(&window));
+          }
+          if let Some(until) = list.and_then(|u| u.active_until) {
+            let today = Local::now().naive_local().date();
+            let note = if today <= until {
+              format!("Active until {}", until)
+            } else {
+              format!("Notifications paused since {}", until)
+            };
+            sections.push(escape(&note));
+          }
+
+          send_pages(&bot, message.chat.id, paginate(sections, MESSAGE_LIMIT)).await?
+        } else {
+          bot
+            .send_message(message.chat.id, "Failed to get user tracking subscriptions".to_string())
+            .await?
+        }
+      } else {
+        bot
+          .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+          .await?
+      }
+    },
+    Command::MapLink(value) => {
+      set_toggle(
+        &bot,
+        &message,
+        &value,
+        "maplink",
+        (
+          "Notifications will include a map link",
+          "Notifications will no longer include a map link",
+        ),
+        |user_data, enabled| user_data.map_link = enabled,
+      )
+      .await?
+    },
+    Command::Volatile(value) => {
+      set_toggle(
+        &bot,
+        &message,
+        &value,
+        "volatile",
+        (
+          "You will be alerted when a frequently reopening slot appears",
+          "You will no longer receive frequently reopening slot alerts",
+        ),
+        |user_data, enabled| user_data.volatile = enabled,
+      )
+      .await?
+    },
+    Command::Mute => {
+      apply_toggle(
+        &bot,
+        &message,
+        true,
+        (
+          "Notifications are paused, your tracked centers are kept. Use /unmute to resume them",
+          "",
+        ),
+        |user_data, muted| user_data.muted = muted,
+      )
+      .await?
+    },
+    Command::Unmute => {
+      apply_toggle(
+        &bot,
+        &message,
+        false,
+        ("", "Notifications resumed"),
+        |user_data, muted| user_data.muted = muted,
+      )
+      .await?
+    },
+    Command::Window(value) => {
+      let user = sender_id(&message);
+      let window = if value.trim() == "clear" {
+        Ok(None)
+      } else {
+        let dates = value
+          .split_whitespace()
+          .map(|x| NaiveDate::parse_from_str(x, "%Y-%m-%d"))
+          .collect::<Vec<_>>();
+        match dates.as_slice() {
+          [Ok(earliest), Ok(latest)] if earliest > latest => {
+            Err("The start of the window has to be before its end.".to_string())
+          },
+          [Ok(earliest), Ok(latest)] => Ok(Some((*earliest, *latest))),
+          [_, _] => Err("Dates have to be written as YYYY-MM-DD, e.g. /window 2024-03-01 2024-04-15".to_string()),
+          _ => Err("Usage: /window YYYY-MM-DD YYYY-MM-DD or /window clear".to_string()),
+        }
+      };
+
+      match (window, user) {
+        (Err(err), _) => bot.send_message(message.chat.id, err).await?,
+        (_, None) => {
+          bot
+            .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+            .await?
+        },
+        (Ok(window), Some(user)) => {
+          if let Err(err) = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .update_user_data(message.chat.id.0, user, |user_data| {
+              user_data.earliest = window.map(|(earliest, _)| earliest);
+              user_data.latest = window.map(|(_, latest)| latest);
+            })
+            .await
+          {
+            bot.send_message(message.chat.id, tracking_error_msg(&err)).await?
+          } else if let Some((earliest, latest)) = window {
+            bot
+              .send_message(
+                message.chat.id,
+                format!("You will only be notified about slots from {} to {}", earliest, latest),
+              )
+              .await?
+          } else {
+            bot
+              .send_message(
+                message.chat.id,
+                "You will be notified about slots on any date".to_string(),
+              )
+              .await?
+          }
+        },
+      }
+    },
+    Command::ActiveUntil(value) => {
+      let user = sender_id(&message);
+      let today = Local::now().naive_local().date();
+      let until = match value.trim() {
+        "off" => Ok(None),
+        value => match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+          Ok(date) if date < today => Err("That date is already in the past.".to_string()),
+          Ok(date) => Ok(Some(date)),
+          Err(_) => Err("Usage: /activeuntil YYYY-MM-DD or /activeuntil off".to_string()),
+        },
+      };
+
+      match (until, user) {
+        (Err(err), _) => bot.send_message(message.chat.id, err).await?,
+        (_, None) => {
+          bot
+            .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+            .await?
+        },
+        (Ok(until), Some(user)) => {
+          if let Err(err) = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .update_user_data(message.chat.id.0, user, |user_data| {
+              user_data.active_until = until;
+              user_data.active_until_prompted = false;
+            })
+            .await
+          {
+            bot.send_message(message.chat.id, tracking_error_msg(&err)).await?
+          } else if let Some(until) = until {
+            bot
+              .send_message(message.chat.id, format!("You will be notified until {}", until))
+              .await?
+          } else {
+            bot
+              .send_message(message.chat.id, "You will be notified indefinitely".to_string())
+              .await?
+          }
+        },
+      }
+    },
+    Command::Info(query) => {
+      let user = sender_id(&message);
+      let all = centers();
+      let found = lookup_center(&all, &query);
+
+      if let Some(center) = found.center() {
+        let mut lock = MANAGER.lock().await;
+        let manager = lock.as_mut().unwrap();
+        let tracking = match user {
+          Some(user) => matches!(
+            manager.get_user_data(user).await,
+            Ok(Some(user_data)) if user_data.subscriptions.contains(&center.id)
+          ),
+          None => false,
+        };
+        let subscribers = manager.get_center_subscribers().get(&center.id).map_or(0, Vec::len);
+        let msg = center.info_msg(
+          manager.get_closed_centers().contains(&center.id),
+          subscribers,
+          manager.get_availability(center.id),
+          tracking,
+        );
+        drop(lock);
+
+        let request = bot.send_message(message.chat.id, msg).parse_mode(ParseMode::MarkdownV2);
+        if user.is_some() && (center.enabled || tracking) {
+          request.reply_markup(tracking_keyboard(center, tracking)).await?
+        } else {
+          request.await?
+        }
+      } else {
+        bot
+          .send_message(message.chat.id, center_lookup_msg(&query, &found))
+          .await?
+      }
+    },
+    Command::Slots(query) => {
+      let all = centers();
+      let (query, service) = split_service(&all, &query);
+      let found = lookup_center(&all, query);
+      let reply = match found.center() {
+        Some(center) if !center.offers(service) => escape(&format!(
+          "{} does not offer {} appointments.",
+          center.full_name,
+          service.name()
+        )),
+        Some(center) => match fetch_slots(client, &CONFIG.api_base, center.id).await {
+          Ok(slots) => center.slots_msg(&split_closed(center.id, slots).await.0, service),
+          Err(err) => {
+            warn!(center_id = center.id, "Failed to fetch slots on demand: {}", err);
+            escape(&format!(
+              "Could not reach the scheduler for {}, please try again later.",
+              center.full_name
+            ))
+          },
+        },
+        None => escape(&center_lookup_msg(query, &found)),
+      };
+      bot
+        .send_message(message.chat.id, reply)
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?
+    },
+    Command::Next(query) => {
+      let all = centers();
+      let found = lookup_center(&all, &query);
+      let reply = match found.center() {
+        Some(center) => match fetch_slots(client, &CONFIG.api_base, center.id).await {
+          Ok(slots) => {
+            let soonest = split_closed(center.id, slots)
+              .await
+              .0
+              .into_iter()
+              .min_by(|a, b| a.start_timestamp.cmp(&b.start_timestamp));
+            let user_data = match sender_id(&message) {
+              Some(user) => {
+                let mut lock = MANAGER.lock().await;
+                let manager = lock.as_mut().unwrap();
+                manager.get_user_data(user).await.ok().flatten().cloned()
+              },
+              None => None,
+            }
+            .unwrap_or_default();
+            match soonest {
+              Some(slot) => center.appointment_avaliable_msg(&[&slot], &user_data),
+              None => center.slots_msg(&[], user_data.service_for(center.id)),
+            }
+          },
+          Err(err) => {
+            warn!(center_id = center.id, "Failed to fetch slots on demand: {}", err);
+            escape(&format!(
+              "Could not reach the scheduler for {}, please try again later.",
+              center.full_name
+            ))
+          },
+        },
+        None => escape(&center_lookup_msg(&query, &found)),
+      };
+      bot
+        .send_message(message.chat.id, reply)
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?
+    },
+    Command::QuietHours(value) => {
+      let user = sender_id(&message);
+      let quiet_hours = if value.trim() == "off" {
+        Ok(None)
+      } else {
+        let args = value.split_whitespace().collect::<Vec<_>>();
+        let offset = match args.get(2).map(|x| parse_utc_offset(x)) {
+          None => Ok(None),
+          Some(Some(offset)) => Ok(Some(offset)),
+          Some(None) => Err("The UTC offset has to look like +2, -05:00 or UTC+1".to_string()),
+        };
+        let times = args
+          .iter()
+          .take(2)
+          .map(|x| NaiveTime::parse_from_str(x, "%H:%M"))
+          .collect::<Vec<_>>();
+        match (times.as_slice(), offset) {
+          (_, _) if args.len() > 3 => Err("Usage: /quiethours HH:MM HH:MM [UTC offset] or /quiethours off".to_string()),
+          ([Ok(start), Ok(end)], _) if start == end => {
+            Err("Quiet hours have to start and end at different times.".to_string())
+          },
+          ([Ok(_), Ok(_)], Err(err)) => Err(err),
+          ([Ok(start), Ok(end)], Ok(offset)) => Ok(Some((*start, *end, offset))),
+          ([_, _], _) => Err("Times have to be written as HH:MM, e.g. /quiethours 22:00 07:00 +1".to_string()),
+          _ => Err("Usage: /quiethours HH:MM HH:MM [UTC offset] or /quiethours off".to_string()),
+        }
+      };
+
+      let reply = match (quiet_hours, user) {
+        (Err(err), _) => err,
+        (_, None) => "Could not understand who sent this?".to_string(),
+        (Ok(quiet_hours), Some(user)) => {
+          let result = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .update_user_data(message.chat.id.0, user, |user_data| {
+              user_data.quiet_hours = quiet_hours.map(|(start, end, _)| (start, end));
+              user_data.utc_offset = quiet_hours.and_then(|(_, _, offset)| offset);
+            })
+            .await;
+          match (result, quiet_hours) {
+            (Err(err), _) => tracking_error_msg(&err),
+            (Ok(_), Some((start, end, offset))) => format!(
+              "Notifications will be held from {} to {}, {}. Slots found in the meantime are sent as one digest \
+               when quiet hours end.",
+              start.format("%H:%M"),
+              end.format("%H:%M"),
+              describe_utc_offset(offset)
+            ),
+            (Ok(_), None) => "Quiet hours are off".to_string(),
+          }
+        },
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Home(location) => {
+      let user = sender_id(&message);
+      let home = match location.trim() {
+        "" => Err("Usage: /home <address or city> or /home off"),
+        "off" => Ok(None),
+        location => Ok(Some(location.to_string())),
+      };
+
+      match (home, user) {
+        (Err(err), _) => bot.send_message(message.chat.id, err.to_string()).await?,
+        (_, None) => {
+          bot
+            .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+            .await?
+        },
+        (Ok(home), Some(user)) => {
+          let reply = match &home {
+            Some(home) => format!("Directions in notifications will start from {}", home),
+            None => "Directions in notifications will start from your current location".to_string(),
+          };
+          if let Err(err) = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .update_user_data(message.chat.id.0, user, |user_data| user_data.home = home)
+            .await
+          {
+            bot.send_message(message.chat.id, tracking_error_msg(&err)).await?
+          } else {
+            bot.send_message(message.chat.id, reply).await?
+          }
+        },
+      }
+    },
+    Command::Snooze(value) => {
+      let user = sender_id(&message);
+      let all = centers();
+      let (query, length) = value.trim().rsplit_once(' ').unwrap_or((value.trim(), ""));
+      let found = lookup_center(&all, query);
+      let reply = match (found.center(), parse_snooze(length), user) {
+        (_, None, _) => format!(
+          "Usage: /snooze <center> <length>, e.g. /snooze YUL 6h. Lengths are minutes (m), hours (h) or days (d), up \
+           to {} days.",
+          MAX_SNOOZE_DAYS
+        ),
+        (None, _, _) => center_lookup_msg(query, &found),
+        (_, _, None) => "Could not understand who sent this?".to_string(),
+        (Some(center), Some(length), Some(user)) => {
+          let until = Local::now().naive_local() + length;
+          let result = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .snooze_center(user, center.id, until)
+            .await;
+          match result {
+            Ok(_) => format!(
+              "No alerts for {} until {}. Use /unsnooze {} to resume them sooner",
+              center.full_name,
+              until.format("%b %-d %H:%M"),
+              center.short_name
+            ),
+            Err(err) => tracking_error_msg(&err),
+          }
+        },
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Unsnooze(query) => {
+      let user = sender_id(&message);
+      let all = centers();
+      let found = lookup_center(&all, &query);
+      let reply = match (found.center(), user) {
+        (None, _) => center_lookup_msg(&query, &found),
+        (_, None) => "Could not understand who sent this?".to_string(),
+        (Some(center), Some(user)) => {
+          let result = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .unsnooze_center(user, center.id)
+            .await;
+          match result {
+            Ok(_) => format!("Alerts for {} resumed", center.full_name),
+            Err(err) => tracking_error_msg(&err),
+          }
+        },
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Threshold(value) => {
+      let user = sender_id(&message);
+      let threshold = match value.trim() {
+        "on" => Ok(Some(None)),
+        "off" => Ok(None),
+        date => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+          .map(|x| Some(Some(x)))
+          .map_err(|_| "Usage: /threshold on, /threshold YYYY-MM-DD or /threshold off"),
+      };
+      let reply = match (threshold, user) {
+        (Err(err), _) => err.to_string(),
+        (_, None) => "Could not understand who sent this?".to_string(),
+        (Ok(threshold), Some(user)) => {
+          let result = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .update_user_data(message.chat.id.0, user, |user_data| {
+              user_data.earlier_only = threshold.is_some();
+              user_data.best_seen.clear();
+              if let Some(Some(date)) = threshold {
+                let before = date.and_hms(0, 0, 0);
+                user_data.best_seen = user_data.tracked_centers().into_iter().map(|x| (x, before)).collect();
+              }
+            })
+            .await;
+          match (result, threshold) {
+            (Err(err), _) => tracking_error_msg(&err),
+            (Ok(_), Some(None)) => "You will only be alerted when a center has a slot sooner than the soonest one \
+                                    you were alerted about there"
+              .to_string(),
+            (Ok(_), Some(Some(date))) => format!(
+              "You will only be alerted about slots before {}, and after each alert only about even sooner ones",
+              date
+            ),
+            (Ok(_), None) => "You will be alerted about every new slot again".to_string(),
+          }
+        },
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Mode(value) => {
+      let user = sender_id(&message);
+      let reply = match (NotificationMode::parse(&value), user) {
+        (None, _) => "Usage: /mode instant or /mode digest".to_string(),
+        (_, None) => "Could not understand who sent this?".to_string(),
+        (Some(mode), Some(user)) => {
+          let result = MANAGER
+            .lock()
+            .await
+            .as_mut()
+            .unwrap()
+            .update_user_data(message.chat.id.0, user, |user_data| user_data.notification_mode = mode)
+            .await;
+          match (result, mode) {
+            (Err(err), _) => tracking_error_msg(&err),
+            (Ok(_), NotificationMode::Instant) => "You will be notified as soon as a slot opens".to_string(),
+            (Ok(_), NotificationMode::Digest) => format!(
+              "You will get one summary of your centers with openings every day at {}, in the bot's local time",
+              CONFIG.digest_time.format("%H:%M")
+            ),
+          }
+        },
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Config => {
+      if sender_is_admin(&message) {
+        bot
+          .send_message(
+            message.chat.id,
+            format!("{}\nLoaded centers: {}", *CONFIG, centers().len()),
+          )
+          .await?
+      } else {
+        bot
+          .send_message(
+            message.chat.id,
+            "This command is only available to bot admins.".to_string(),
+          )
+          .await?
+      }
+    },
+    Command::AddClosure(args) => {
+      let reply = if !sender_is_admin(&message) {
+        "This command is only available to bot admins.".to_string()
+      } else if let Some((query, closure)) = args.trim().rsplit_once(' ') {
+        let all = centers();
+        let found = lookup_center(&all, query);
+        match (found.center(), closure.parse::<Closure>()) {
+          (Some(center), Ok(closure)) => {
+            let mut lock = MANAGER.lock().await;
+            match lock.as_mut().unwrap().add_closure(center.id, closure).await {
+              Ok(_) => format!("Slots at {} on {} will be ignored.", center.short_name, closure),
+              Err(err) => {
+                warn!("Failed to add closure: {}", err);
+                "Failed to save the closure, please try again later.".to_string()
+              },
+            }
+          },
+          (None, _) => center_lookup_msg(query, &found),
+          (_, Err(err)) => err,
+        }
+      } else {
+        "Usage: /addclosure <center> <YYYY-MM-DD or YYYY-MM-DD..YYYY-MM-DD>".to_string()
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Version => {
+      bot
+        .send_message(
+          message.chat.id,
+          format!(
+            "nexus-pls {}\n{}",
+            env!("CARGO_PKG_VERSION"),
+            selftest::report(sender_is_admin(&message))
+          ),
+        )
+        .await?
+    },
+    Command::LogLevel(args) => {
+      let mut args = args.split_whitespace();
+      let reply = match (sender_is_admin(&message), args.next(), args.next()) {
+        (false, _, _) => Err("This command is only available to bot admins.".to_string()),
+        (true, Some("reset"), None) => logging::reset(),
+        (true, Some(level), target) if args.next().is_none() => {
+          logging::directives(level, target).and_then(|x| logging::set_filter(&x))
+        },
+        _ => Err("Usage: /loglevel <trace|debug|info|warn|error> [target], or /loglevel reset".to_string()),
+      };
+      let reply = match reply {
+        Ok(filter) => {
+          info!("Log filter changed to {} by admin", filter);
+          format!("Log filter is now `{}`", filter)
+        },
+        Err(err) => err,
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Stats => {
+      if sender_is_admin(&message) {
+        let stats = MANAGER.lock().await.as_mut().unwrap().stats().await;
+        send_pages(&bot, message.chat.id, paginate(stats_sections(&stats), MESSAGE_LIMIT)).await?
+      } else {
+        bot
+          .send_message(
+            message.chat.id,
+            "This command is only available to bot admins.".to_string(),
+          )
+          .await?
+      }
+    },
+    Command::RefreshCenters => {
+      let reply = if !sender_is_admin(&message) {
+        "This command is only available to bot admins.".to_string()
+      } else {
+        match refresh_centers(client).await {
+          Ok(fetched) => {
+            info!("Centers refreshed by admin, {} from the locations api", fetched);
+            format!(
+              "Loaded {} centers, {} from the locations api.",
+              centers().len(),
+              fetched
+            )
+          },
+          Err(err) => {
+            warn!("Failed to refresh centers: {}", err);
+            format!(
+              "Could not reach the locations api, keeping the current centers: {}",
+              err
+            )
+          },
+        }
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+    Command::Reload => {
+      let reply = if !sender_is_admin(&message) {
+        "This command is only available to bot admins.".to_string()
+      } else {
+        match reload_centers().await {
+          Ok((centers, regions)) => {
+            info!(centers, regions, "Centers reloaded by admin");
+            format!("Loaded {} configured centers and {} regions.", centers, regions)
+          },
+          Err(err) => {
+            warn!("Failed to reload centers: {}", err);
+            format!("The centers files are invalid, keeping the current centers:\n{}", err)
+          },
+        }
+      };
+      bot.send_message(message.chat.id, reply).await?
+    },
+  };
+
+  Ok(())
+}
+
+async fn answer_callback(
+  bot: AutoSend<Bot>,
+  query: CallbackQuery,
+  update: Update,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let result = run_callback(bot, query).await;
+  polling::complete(update.id).await;
+  result
+}
+
+async fn run_callback(bot: AutoSend<Bot>, query: CallbackQuery) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let action = query
+    .data
+    .as_deref()
+    .and_then(|x| x.split_once(':'))
+    .and_then(|(action, id)| Some((action, center_lut().get(&id.parse::<CenterId>().ok()?)?.clone())));
+  let user = query.from.id.0;
+  let chat_id = query.message.as_ref().map_or(user as i64, |x| x.chat.id.0);
+
+  let (text, tracking) = match action {
+    Some(("track", center)) if !center.enabled => (center.disabled_msg(), None),
+    Some(("track", center)) => {
+      let result = MANAGER
+        .lock()
+        .await
+        .as_mut()
+        .unwrap()
+        .track_center(chat_id, user, center.id, Service::Nexus)
+        .await;
+      match result {
+        Ok(()) => (
+          format!("Now tracking {} on your behalf", center.full_name),
+          Some((center, true)),
+        ),
+        Err(err) => (tracking_error_msg(&err), None),
+      }
+    },
+    Some(("untrack", center)) => {
+      let result = MANAGER
+        .lock()
+        .await
+        .as_mut()
+        .unwrap()
+        .untrack_center(user, center.id)
+        .await;
+      match result {
+        Ok(()) => (
+          format!("Stopped tracking {} on your behalf", center.full_name),
+          Some((center, false)),
+        ),
+        Err(err) => (tracking_error_msg(&err), None),
+      }
+    },
+    _ => ("This button is no longer valid".to_string(), None),
+  };
+
+  bot.answer_callback_query(query.id).text(text).await?;
+  if let (Some((center, tracking)), Some(message)) = (tracking, query.message) {
+    let markup = match message.reply_markup() {
+      Some(markup) => toggle_button(markup, &center, tracking),
+      None => tracking_keyboard(&center, tracking),
+    };
+    bot
+      .edit_message_reply_markup(message.chat.id, message.id)
+      .reply_markup(markup)
+      .await?;
+  }
+
+  Ok(())
+}
+
+async fn set_toggle<F>(
+  bot: &AutoSend<Bot>,
+  message: &Message,
+  value: &str,
+  command: &str,
+  (on_reply, off_reply): (&str, &str),
+  update: F,
+) -> Result<Message, Box<dyn Error + Send + Sync>>
+where
+  F: FnOnce(&mut UserData, bool),
+{
+  match parse_toggle(value) {
+    Some(enabled) => apply_toggle(bot, message, enabled, (on_reply, off_reply), update).await,
+    None => Ok(
+      bot
+        .send_message(message.chat.id, format!("Usage: /{0} on or /{0} off", command))
+        .await?,
+    ),
+  }
+}
+
+/// Turns one of the sender's settings on or off and confirms the change.
+async fn apply_toggle<F>(
+  bot: &AutoSend<Bot>,
+  message: &Message,
+  enabled: bool,
+  (on_reply, off_reply): (&str, &str),
+  update: F,
+) -> Result<Message, Box<dyn Error + Send + Sync>>
+where
+  F: FnOnce(&mut UserData, bool),
+{
+  let sent = match sender_id(message) {
+    None => {
+      bot
+        .send_message(message.chat.id, "Could not understand who sent this?".to_string())
+        .await?
+    },
+    Some(user) => {
+      if let Err(err) = MANAGER
+        .lock()
+        .await
+        .as_mut()
+        .unwrap()
+        .update_user_data(message.chat.id.0, user, |user_data| update(user_data, enabled))
+        .await
+      {
+        bot.send_message(message.chat.id, tracking_error_msg(&err)).await?
+      } else {
+        let reply = if enabled { on_reply } else { off_reply };
+        bot.send_message(message.chat.id, reply.to_string()).await?
+      }
+    },
+  };
+
+  Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::center::testing::center;
+
+  #[test]
+  fn track_queries_may_end_in_a_service() {
+    let mut blaine = center(5020, "blaine", "Blaine Peace Arch");
+    blaine.aliases = vec!["peace arch".to_string()];
+    let centers = [blaine, center(5030, "fast", "Fast Lane EC")];
+
+    assert_eq!(split_service(&centers, "blaine ge"), ("blaine", Service::GlobalEntry));
+    assert_eq!(
+      split_service(&centers, "peace arch sentri"),
+      ("peace arch", Service::Sentri)
+    );
+    assert_eq!(split_service(&centers, "peace arch"), ("peace arch", Service::Nexus));
+    assert_eq!(
+      split_service(&centers, "blaine passport"),
+      ("blaine passport", Service::Nexus)
+    );
+    assert_eq!(split_service(&centers, "fast"), ("fast", Service::Nexus));
+  }
+
+  #[test]
+  fn centers_can_be_looked_up_by_location_id() {
+    let centers = [
+      center(5020, "blaine", "Blaine Peace Arch"),
+      center(5161, "niagara", "Niagara Falls EC"),
+    ];
+    let found = |query| lookup_center(&centers, query).center().map(|x| x.id);
+
+    assert_eq!(found("5020"), Some(5020));
+    assert_eq!(found(" 5161 "), Some(5161));
+    assert_eq!(found("Niagara"), Some(5161));
+    assert_eq!(found("5022"), None);
+    assert_eq!(
+      center_lookup_msg("5022", &lookup_center(&centers, "5022")),
+      center_not_found_msg("5022")
+    );
+  }
+
+  #[test]
+  fn region_names_are_left_to_region_commands() {
+    let all = centers();
+    let region = &regions()[0];
+    assert!(matches!(lookup_center(&all, &region.name), CenterMatch::Missing(close) if close.is_empty()));
+  }
+
+  #[test]
+  fn snooze_lengths_parse_with_a_unit() {
+    assert_eq!(parse_snooze("30m"), Some(chrono::Duration::minutes(30)));
+    assert_eq!(parse_snooze("6h"), Some(chrono::Duration::hours(6)));
+    assert_eq!(parse_snooze("2d"), Some(chrono::Duration::days(2)));
+    assert_eq!(parse_snooze("30d"), Some(chrono::Duration::days(MAX_SNOOZE_DAYS)));
+    for value in ["31d", "0h", "-1h", "1.5h", "h", "", "2w", "6", "6H", "2dé"] {
+      assert_eq!(parse_snooze(value), None, "{}", value);
+    }
+  }
+
+  #[test]
+  fn lookup_messages_name_the_candidates() {
+    let blaine = center(5020, "blaine", "Blaine Peace Arch");
+    let bellingham = center(5021, "bellingham", "Bellingham Airport");
+
+    assert_eq!(
+      center_lookup_msg(" b ", &CenterMatch::Ambiguous(vec![&blaine, &bellingham])),
+      "\"b\" matches several centers, please use one of: blaine (Blaine Peace Arch), bellingham (Bellingham Airport)"
+    );
+    assert_eq!(
+      center_lookup_msg("blane", &CenterMatch::Missing(vec![&blaine])),
+      "Could not find center \"blane\". Did you mean blaine?"
+    );
+    assert_eq!(
+      center_lookup_msg("nowhere", &CenterMatch::Missing(Vec::new())),
+      center_not_found_msg("nowhere")
+    );
+  }
+
+  fn message_json(user: UserId, text: &str) -> serde_json::Value {
+    serde_json::json!({
+      "message_id": 1,
+      "date": Utc::now().timestamp(),
+      "chat": { "id": user, "type": "private", "first_name": "Test" },
+      "from": { "id": user, "is_bot": false, "first_name": "Test" },
+      "text": text,
+    })
+  }
+
+  /// Runs `text` as a command `user` sent in their private chat, returning
+  /// the texts of the messages the bot replied with.
+  async fn replies(user: UserId, text: &str) -> Vec<String> {
+    use wiremock::matchers::{method, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    start_test_manager().await;
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+      .and(path_regex("/SendMessage$"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+        "ok": true,
+        "result": message_json(user, "reply"),
+      })))
+      .mount(&server)
+      .await;
+    let bot = Bot::new("0:test")
+      .set_api_url(server.uri().parse().unwrap())
+      .auto_send();
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+      .with_native_roots()
+      .https_or_http()
+      .enable_http1()
+      .build();
+
+    let message: Message = serde_json::from_value(message_json(user, text)).unwrap();
+    let command = Command::parse(text, "nexus_pls_bot").unwrap();
+    run_command(bot, message, command, &hyper::Client::builder().build(https))
+      .await
+      .unwrap();
+    server
+      .received_requests()
+      .await
+      .unwrap()
+      .iter()
+      .map(|x| {
+        let body = serde_json::from_slice::<serde_json::Value>(&x.body).unwrap();
+        body["text"].as_str().unwrap().to_string()
+      })
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn slots_are_only_checked_for_offered_services() {
+    assert_eq!(
+      replies(9902, "/slots niagara fast").await,
+      ["Niagara Falls EC does not offer FAST appointments\\."]
+    );
+  }
+
+  #[tokio::test]
+  async fn stats_are_only_shown_to_admins() {
+    assert_eq!(
+      replies(9901, "/stats").await,
+      ["This command is only available to bot admins."]
+    );
+    let stats = replies(TEST_ADMIN_ID, "/stats").await;
+    assert!(stats[0].starts_with("*Stats*\nUsers: "), "{:?}", stats);
+  }
+
+  #[tokio::test]
+  async fn disabled_centers_are_hidden_from_new_tracking() {
+    let testville = center_lut()[&5999].clone();
+    assert!(!testville.enabled);
+
+    let list = replies(9701, "/list").await.concat();
+    assert!(list.contains("Niagara"), "{}", list);
+    assert!(!list.contains("Testville"), "{}", list);
+    assert_eq!(replies(9701, "/track testville").await, [testville.disabled_msg()]);
+    let mut lock = MANAGER.lock().await;
+    assert!(lock.as_mut().unwrap().get_user_data(9701).await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn existing_subscriptions_to_disabled_centers_remain() {
+    start_test_manager().await;
+    MANAGER
+      .lock()
+      .await
+      .as_mut()
+      .unwrap()
+      .track_center(9702, 9702, 5999, Service::Nexus)
+      .await
+      .unwrap();
+
+    let status = replies(9702, "/status").await.concat();
+    assert!(status.contains("Testville Enrollment Center"), "{}", status);
+    assert!(status.contains("_\\(disabled\\)_"), "{}", status);
+    assert_eq!(
+      replies(9702, "/untrack testville").await,
+      ["Stopped tracking Testville Enrollment Center on your behalf"]
+    );
+    let mut lock = MANAGER.lock().await;
+    let user_data = lock.as_mut().unwrap().get_user_data(9702).await.unwrap().cloned();
+    assert!(user_data.is_none_or(|x| x.subscriptions.is_empty()));
+  }
+}
@@ -0,0 +1,181 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::config::LogFormat;
+
+/// Filter used when `RUST_LOG` is not set.
+const DEFAULT_FILTER: &str = "info";
+/// How long a changed filter stays in effect before reverting.
+pub const REVERT_AFTER: Duration = Duration::from_secs(15 * 60);
+/// Levels SIGUSR1 steps through, after which the startup filter is restored.
+const SIGNAL_LEVELS: [&str; 2] = ["debug", "trace"];
+
+lazy_static! {
+  static ref HANDLE: Mutex<Option<reload::Handle<EnvFilter, Registry>>> = Mutex::new(None);
+  static ref BASE_FILTER: String = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+}
+
+/// Bumped on every change so a pending revert only applies to its own change.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static SIGNAL_STEP: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the global subscriber with a filter that can be changed at runtime.
+pub fn init(format: LogFormat) {
+  let filter = EnvFilter::try_new(BASE_FILTER.as_str()).unwrap_or_else(|err| {
+    eprintln!("Invalid RUST_LOG `{}`, using {}: {}", *BASE_FILTER, DEFAULT_FILTER, err);
+    EnvFilter::new(DEFAULT_FILTER)
+  });
+  let (filter, handle) = reload::Layer::new(filter);
+  let registry = tracing_subscriber::registry().with(filter);
+  match format {
+    LogFormat::Pretty => registry.with(fmt::layer()).init(),
+    LogFormat::Json => registry.with(json_layer(std::io::stdout)).init(),
+  }
+  *HANDLE.lock().unwrap() = Some(handle);
+}
+
+/// One JSON object per line, with the fields of events and their spans at
+/// the top level so Loki can query them directly.
+pub fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+  W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+  fmt::layer().json().flatten_event(true).with_writer(writer)
+}
+
+/// The filter directives for `level`, either for everything or only for
+/// `target` on top of the startup filter.
+pub fn directives(level: &str, target: Option<&str>) -> Result<String, String> {
+  let level = LevelFilter::from_str(level).map_err(|_| format!("Unknown log level `{}`", level))?;
+  let level = level.to_string().to_lowercase();
+  Ok(match target {
+    Some(target) => format!("{},{}={}", *BASE_FILTER, target, level),
+    None => level,
+  })
+}
+
+/// Replaces the active filter, returning the new effective filter. Unless
+/// it is the startup filter, it reverts after `REVERT_AFTER`.
+pub fn set_filter(directives: &str) -> Result<String, String> {
+  let filter = EnvFilter::try_new(directives).map_err(|err| format!("Invalid filter `{}`: {}", directives, err))?;
+  let effective = reload_filter(filter)?;
+  let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+
+  if directives != BASE_FILTER.as_str() {
+    tokio::spawn(async move {
+      tokio::time::sleep(REVERT_AFTER).await;
+      if GENERATION.load(Ordering::SeqCst) == generation {
+        match reset() {
+          Ok(filter) => info!("Log filter reverted to {}", filter),
+          Err(err) => warn!("Could not revert log filter: {}", err),
+        }
+      }
+    });
+  }
+  Ok(effective)
+}
+
+/// Restores the filter the bot started with.
+pub fn reset() -> Result<String, String> {
+  SIGNAL_STEP.store(0, Ordering::SeqCst);
+  set_filter(BASE_FILTER.as_str())
+}
+
+fn reload_filter(filter: EnvFilter) -> Result<String, String> {
+  let handle = HANDLE.lock().unwrap();
+  let handle = handle
+    .as_ref()
+    .ok_or_else(|| "Logging is not initialized".to_string())?;
+  handle.reload(filter).map_err(|err| err.to_string())?;
+  handle.with_current(|x| x.to_string()).map_err(|err| err.to_string())
+}
+
+/// Steps the log level through debug and trace on every SIGUSR1, then back to
+/// the startup filter.
+pub fn spawn_signal_handler() {
+  let mut signals = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+    Ok(signals) => signals,
+    Err(err) => {
+      warn!("Could not listen for SIGUSR1: {}", err);
+      return;
+    },
+  };
+
+  tokio::spawn(async move {
+    while signals.recv().await.is_some() {
+      let step = SIGNAL_STEP.load(Ordering::SeqCst);
+      let result = match SIGNAL_LEVELS.get(step) {
+        Some(level) => set_filter(level).inspect(|_| SIGNAL_STEP.store(step + 1, Ordering::SeqCst)),
+        None => reset(),
+      };
+      match result {
+        Ok(filter) => info!("Log filter changed to {} by SIGUSR1", filter),
+        Err(err) => warn!("Could not change log filter: {}", err),
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn directives_set_a_global_or_scoped_level() {
+    assert_eq!(directives("DEBUG", None), Ok("debug".to_string()));
+    assert_eq!(directives("off", None), Ok("off".to_string()));
+    assert_eq!(
+      directives("warn", Some("nexus_pls::center")),
+      Ok(format!("{},nexus_pls::center=warn", *BASE_FILTER))
+    );
+    assert_eq!(directives("loud", None), Err("Unknown log level `loud`".to_string()));
+  }
+
+  fn current() -> String {
+    let handle = HANDLE.lock().unwrap();
+    handle.as_ref().unwrap().with_current(|x| x.to_string()).unwrap()
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn changed_filters_revert_unless_changed_again() {
+    let (layer, handle) = reload::Layer::new(EnvFilter::new(BASE_FILTER.as_str()));
+    let _subscriber = tracing_subscriber::registry().with(layer);
+    *HANDLE.lock().unwrap() = Some(handle);
+    let base = EnvFilter::new(BASE_FILTER.as_str()).to_string();
+
+    assert!(set_filter("nexus_pls=loud").unwrap_err().starts_with("Invalid filter"));
+    assert_eq!(current(), base);
+
+    assert_eq!(set_filter("debug"), Ok("debug".to_string()));
+    tokio::time::sleep(REVERT_AFTER - Duration::from_secs(1)).await;
+    assert_eq!(current(), "debug");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(current(), base);
+
+    // Only the latest change reverts, a full period after it was made.
+    set_filter("debug").unwrap();
+    tokio::time::sleep(REVERT_AFTER / 2).await;
+    set_filter("trace").unwrap();
+    tokio::time::sleep(REVERT_AFTER / 2 + Duration::from_secs(1)).await;
+    assert_eq!(current(), "trace");
+    tokio::time::sleep(REVERT_AFTER / 2).await;
+    assert_eq!(current(), base);
+
+    SIGNAL_STEP.store(2, Ordering::SeqCst);
+    set_filter("trace").unwrap();
+    assert_eq!(reset(), Ok(base.clone()));
+    assert_eq!(SIGNAL_STEP.load(Ordering::SeqCst), 0);
+    assert_eq!(current(), base);
+  }
+}
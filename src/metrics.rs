@@ -0,0 +1,60 @@
+use lazy_static::lazy_static;
+use prometheus::{
+  register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, Histogram,
+  IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+  pub static ref POLLS: IntCounterVec = register_int_counter_vec!(
+    "nexus_polls_total",
+    "Slot fetches per center by result",
+    &["center", "result"]
+  )
+  .unwrap();
+  pub static ref FETCH_DURATION: Histogram = register_histogram!(
+    "nexus_slot_fetch_duration_seconds",
+    "Time taken to fetch slots from the scheduler"
+  )
+  .unwrap();
+  pub static ref NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+    "nexus_notifications_total",
+    "Messages sent to users by the collector by outcome (sent, suppressed or failed)",
+    &["outcome"]
+  )
+  .unwrap();
+  pub static ref SLOTS_FOUND: IntCounterVec = register_int_counter_vec!(
+    "nexus_slots_found_total",
+    "Open slots returned by the scheduler per center short name",
+    &["center"]
+  )
+  .unwrap();
+  pub static ref COMMANDS: IntCounterVec =
+    register_int_counter_vec!("nexus_commands_total", "Bot commands received by command", &["command"]).unwrap();
+  pub static ref CLOSURE_SLOTS_DROPPED: IntCounter = register_int_counter!(
+    "nexus_closure_slots_dropped_total",
+    "Slots ignored because they fall on a closure date"
+  )
+  .unwrap();
+  pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+    "nexus_collector_queue_depth",
+    "Messages waiting for the collector worker"
+  )
+  .unwrap();
+  pub static ref USERS: IntGauge = register_int_gauge!("nexus_users", "Known users").unwrap();
+  pub static ref SUBSCRIPTIONS: IntGauge =
+    register_int_gauge!("nexus_subscriptions", "Tracked centers summed over active users").unwrap();
+  pub static ref REDIS_ERRORS: IntCounter =
+    register_int_counter!("nexus_redis_errors_total", "Failed redis requests").unwrap();
+  pub static ref FLOOD_WAITS: IntCounter = register_int_counter!(
+    "nexus_telegram_flood_waits_total",
+    "Telegram requests rejected with retry after"
+  )
+  .unwrap();
+}
+
+/// All registered metrics in the Prometheus text format.
+pub fn render() -> String {
+  let mut buffer = Vec::new();
+  TextEncoder::new().encode(&prometheus::gather(), &mut buffer).unwrap();
+  String::from_utf8(buffer).unwrap()
+}
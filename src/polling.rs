@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{self, UnboundedSender};
+use lazy_static::lazy_static;
+use teloxide::dispatching::stop_token::{AsyncStopFlag, AsyncStopToken};
+use teloxide::dispatching::update_listeners::{StatefulListener, UpdateListener};
+use teloxide::payloads::GetUpdatesSetters;
+use teloxide::prelude::*;
+use teloxide::types::Update;
+use tracing::{info, warn};
+
+use crate::webhook::{stop_token_of, stream_of, UpdateStream};
+use crate::MANAGER;
+
+/// Seconds Telegram holds a `getUpdates` request open waiting for updates.
+const POLL_TIMEOUT: u32 = 10;
+/// Delay before polling again after a failed request.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+lazy_static! {
+  /// Messages sent before this were sent while the bot was down.
+  pub static ref STARTED_AT: DateTime<Utc> = Utc::now();
+}
+
+/// How a message relates to the time the bot was unavailable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backlog {
+  Current,
+  /// Sent while the bot was down, but recent enough to act on.
+  Late,
+  /// Sent longer than the allowed age ago.
+  TooOld,
+}
+
+pub fn backlog(sent: DateTime<Utc>, started: DateTime<Utc>, now: DateTime<Utc>, max_age: Duration) -> Backlog {
+  let age = now.signed_duration_since(sent).to_std().unwrap_or_default();
+  if age > max_age {
+    Backlog::TooOld
+  } else if sent < started {
+    Backlog::Late
+  } else {
+    Backlog::Current
+  }
+}
+
+/// Long polls for updates starting at the offset saved in redis. Updates are
+/// journaled until [`complete`] is called for them, and ones left over from
+/// the last run are handed out again first.
+pub fn listen(bot: AutoSend<Bot>) -> impl UpdateListener<Infallible> {
+  let (tx, rx) = mpsc::unbounded();
+  let (stop_token, stop_flag) = AsyncStopToken::new_pair();
+  tokio::spawn(poll(bot, tx, stop_flag));
+
+  StatefulListener::new(
+    (rx, stop_token),
+    stream_of as for<'a> fn(&'a mut (UpdateStream, AsyncStopToken)) -> &'a mut UpdateStream,
+    stop_token_of as for<'a> fn(&'a mut (UpdateStream, AsyncStopToken)) -> AsyncStopToken,
+  )
+}
+
+async fn poll(bot: AutoSend<Bot>, tx: UnboundedSender<Result<Update, Infallible>>, stop_flag: AsyncStopFlag) {
+  let (mut offset, journaled) = {
+    let mut lock = MANAGER.lock().await;
+    let manager = lock.as_mut().unwrap();
+    (manager.get_update_offset().await, manager.journaled_updates().await)
+  };
+  if !journaled.is_empty() {
+    info!("Resuming {} updates left unfinished by the last run", journaled.len());
+  }
+  let mut replayed = journaled.iter().map(|x| x.id).collect::<HashSet<_>>();
+  for update in journaled {
+    let _ = tx.unbounded_send(Ok(update));
+  }
+
+  tokio::pin!(stop_flag);
+  loop {
+    let mut request = bot.get_updates().timeout(POLL_TIMEOUT);
+    if let Some(offset) = offset {
+      request = request.offset(offset);
+    }
+
+    let updates = tokio::select! {
+      _ = &mut stop_flag => break,
+      updates = request => updates,
+    };
+    let updates = match updates {
+      Ok(updates) => updates,
+      Err(err) => {
+        warn!("Failed to get updates: {}", err);
+        tokio::time::sleep(RETRY_DELAY).await;
+        continue;
+      },
+    };
+    let next_offset = match updates.iter().map(|x| x.id).max() {
+      Some(last) => last + 1,
+      None => continue,
+    };
+    let updates = updates
+      .into_iter()
+      .filter(|x| !replayed.remove(&x.id))
+      .collect::<Vec<_>>();
+
+    if let Err(err) = MANAGER
+      .lock()
+      .await
+      .as_mut()
+      .unwrap()
+      .record_updates(&updates, next_offset)
+      .await
+    {
+      warn!("Failed to journal updates: {}", err);
+    }
+    offset = Some(next_offset);
+    for update in updates {
+      if tx.unbounded_send(Ok(update)).is_err() {
+        return;
+      }
+    }
+  }
+}
+
+/// Removes a handled update from the journal so it isn't processed again
+/// after a restart.
+pub async fn complete(update: i32) {
+  if let Err(err) = MANAGER.lock().await.as_mut().unwrap().complete_update(update).await {
+    warn!("Failed to remove update {} from the journal: {}", update, err);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+  use futures::StreamExt;
+  use teloxide::dispatching::stop_token::StopToken;
+  use wiremock::matchers::{body_partial_json, method, path_regex};
+  use wiremock::{Mock, MockServer, ResponseTemplate};
+
+  use super::*;
+  use crate::storage::{Storage, Write};
+
+  fn at(secs: i64) -> DateTime<Utc> {
+    Utc.timestamp(1_700_000_000 + secs, 0)
+  }
+
+  #[test]
+  fn messages_are_late_until_they_are_too_old() {
+    let max_age = Duration::from_secs(60);
+    let started = at(0);
+    assert_eq!(backlog(at(5), started, at(10), max_age), Backlog::Current);
+    assert_eq!(backlog(at(-30), started, at(10), max_age), Backlog::Late);
+    assert_eq!(backlog(at(-50), started, at(10), max_age), Backlog::Late);
+    assert_eq!(backlog(at(-51), started, at(10), max_age), Backlog::TooOld);
+    // Age is measured from now, so current messages can be too old as well.
+    assert_eq!(backlog(at(5), started, at(100), max_age), Backlog::TooOld);
+    // Clock skew making a message look sent in the future isn't too old.
+    assert_eq!(backlog(at(20), started, at(10), max_age), Backlog::Current);
+  }
+
+  fn update(id: i32) -> serde_json::Value {
+    serde_json::json!({
+      "update_id": id,
+      "message": {
+        "message_id": id,
+        "date": 1_700_000_000,
+        "chat": { "id": 9701, "type": "private", "first_name": "Test" },
+        "from": { "id": 9701, "is_bot": false, "first_name": "Test" },
+        "text": "/list",
+      },
+    })
+  }
+
+  #[tokio::test]
+  async fn polling_resumes_from_the_saved_offset_after_the_journal() {
+    crate::start_test_manager().await;
+    {
+      let mut lock = MANAGER.lock().await;
+      let manager = lock.as_mut().unwrap();
+      let journaled = serde_json::from_value::<Update>(update(100)).unwrap();
+      manager.record_updates(&[journaled], 101).await.unwrap();
+      manager
+        .storage()
+        .write(vec![Write::HashSet(
+          "update_journal".to_string(),
+          "99".to_string(),
+          "not an update".to_string(),
+        )])
+        .await
+        .unwrap();
+    }
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+      .and(path_regex("/GetUpdates$"))
+      .and(body_partial_json(serde_json::json!({ "offset": 101 })))
+      .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+        "ok": true,
+        "result": [update(100), update(101)],
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+    // Nothing else arrives while the test runs.
+    Mock::given(method("POST"))
+      .and(path_regex("/GetUpdates$"))
+      .and(body_partial_json(serde_json::json!({ "offset": 102 })))
+      .respond_with(
+        ResponseTemplate::new(200)
+          .set_body_json(serde_json::json!({ "ok": true, "result": [] }))
+          .set_delay(Duration::from_secs(60)),
+      )
+      .mount(&server)
+      .await;
+
+    let bot = Bot::new("0:test")
+      .set_api_url(server.uri().parse().unwrap())
+      .auto_send();
+    let (tx, mut rx) = mpsc::unbounded();
+    let (stop_token, stop_flag) = AsyncStopToken::new_pair();
+    let task = tokio::spawn(poll(bot, tx, stop_flag));
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+      let update = tokio::time::timeout(Duration::from_secs(10), rx.next())
+        .await
+        .expect("no update received")
+        .unwrap()
+        .unwrap();
+      received.push(update.id);
+    }
+    // The journaled update is handed out first and not again when Telegram
+    // returns it.
+    assert_eq!(received, [100, 101]);
+    stop_token.stop();
+    tokio::time::timeout(Duration::from_secs(10), task)
+      .await
+      .expect("polling did not stop")
+      .unwrap();
+    assert!(rx.next().await.is_none());
+
+    let mut lock = MANAGER.lock().await;
+    let manager = lock.as_mut().unwrap();
+    assert_eq!(manager.get_update_offset().await, Some(102));
+    // The unparsable entry was dropped while the rest wait to be completed.
+    let mut journal = manager
+      .storage()
+      .hash_entries("update_journal")
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|(id, _)| id)
+      .collect::<Vec<_>>();
+    journal.sort();
+    assert_eq!(journal, ["100", "101"]);
+    drop(lock);
+
+    complete(100).await;
+    complete(101).await;
+    let mut lock = MANAGER.lock().await;
+    assert!(lock.as_mut().unwrap().journaled_updates().await.is_empty());
+  }
+}
@@ -0,0 +1,162 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request};
+use lazy_static::lazy_static;
+use serde_json::json;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::warn;
+
+/// How long an event with the same fingerprint is suppressed after a report.
+const DEDUP_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Most reports sent within [`RATE_WINDOW`], regardless of fingerprint.
+const RATE_LIMIT: usize = 10;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+  static ref SENDER: Mutex<Option<UnboundedSender<(String, String)>>> = Mutex::new(None);
+}
+
+/// Identifies an event independent of the numbers in its message, so the same
+/// failure for different ids or timestamps is only reported once.
+pub fn fingerprint(kind: &str, message: &str) -> String {
+  let mut hasher = DefaultHasher::new();
+  kind.hash(&mut hasher);
+  message
+    .chars()
+    .filter(|x| !x.is_ascii_digit())
+    .collect::<String>()
+    .hash(&mut hasher);
+  format!("{:016x}", hasher.finish())
+}
+
+/// Decides which reports are delivered, deduplicating by fingerprint and
+/// capping the overall rate.
+#[derive(Default)]
+pub struct ReportLimiter {
+  last_seen: HashMap<String, Instant>,
+  recent: Vec<Instant>,
+}
+
+impl ReportLimiter {
+  pub fn allow(&mut self, fingerprint: &str, now: Instant) -> bool {
+    self
+      .last_seen
+      .retain(|_, seen| now.saturating_duration_since(*seen) < DEDUP_WINDOW);
+    self
+      .recent
+      .retain(|sent| now.saturating_duration_since(*sent) < RATE_WINDOW);
+
+    if self.last_seen.contains_key(fingerprint) || self.recent.len() >= RATE_LIMIT {
+      return false;
+    }
+
+    self.last_seen.insert(fingerprint.to_string(), now);
+    self.recent.push(now);
+    true
+  }
+}
+
+/// Queues an event for the error reporting sink, if one is configured. Never
+/// blocks, so it is safe to call from panic hooks and hot paths.
+pub fn report(kind: &str, message: &str) {
+  if let Some(sender) = SENDER.lock().ok().as_ref().and_then(|x| x.as_ref()) {
+    let _ = sender.send((kind.to_string(), message.to_string()));
+  }
+}
+
+/// Starts delivering reported events to `url` in the background.
+pub fn start(url: String) {
+  let (tx, rx) = mpsc::unbounded_channel();
+  *SENDER.lock().unwrap() = Some(tx);
+  tokio::spawn(deliver(url, rx));
+}
+
+async fn deliver(url: String, mut rx: UnboundedReceiver<(String, String)>) {
+  let https = hyper_rustls::HttpsConnectorBuilder::new()
+    .with_native_roots()
+    .https_or_http()
+    .enable_http1()
+    .build();
+  let client = hyper::Client::builder().build::<_, Body>(https);
+  let mut limiter = ReportLimiter::default();
+
+  while let Some((kind, message)) = rx.recv().await {
+    let fingerprint = fingerprint(&kind, &message);
+    if !limiter.allow(&fingerprint, Instant::now()) {
+      continue;
+    }
+
+    let text = format!("nexus-pls {}: {}", kind, message);
+    let body = json!({
+      "text": text,
+      "content": text,
+      "kind": kind,
+      "message": message,
+      "fingerprint": [fingerprint],
+    });
+    let request = match Request::post(&url)
+      .header("content-type", "application/json")
+      .body(Body::from(body.to_string()))
+    {
+      Ok(request) => request,
+      Err(err) => {
+        warn!("Invalid error report request: {}", err);
+        continue;
+      },
+    };
+
+    match client.request(request).await {
+      Ok(resp) if !resp.status().is_success() => warn!("Error report was rejected with {}", resp.status()),
+      Ok(_) => {},
+      Err(err) => warn!("Failed to deliver error report: {}", err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fingerprints_ignore_digits() {
+    assert_eq!(
+      fingerprint("panic", "Failed to notify user 1234 at 2024-05-01"),
+      fingerprint("panic", "Failed to notify user 98 at 2023-12-31")
+    );
+    assert_ne!(
+      fingerprint("panic", "Failed to notify user 1234"),
+      fingerprint("error", "Failed to notify user 1234")
+    );
+    assert_ne!(
+      fingerprint("panic", "Failed to notify user"),
+      fingerprint("panic", "Failed to parse slots")
+    );
+  }
+
+  #[test]
+  fn repeats_are_suppressed_for_the_dedup_window() {
+    let mut limiter = ReportLimiter::default();
+    let start = Instant::now();
+    assert!(limiter.allow("a", start));
+    assert!(!limiter.allow("a", start + Duration::from_secs(1)));
+    assert!(limiter.allow("b", start + Duration::from_secs(1)));
+    assert!(!limiter.allow("a", start + DEDUP_WINDOW - Duration::from_secs(1)));
+    assert!(limiter.allow("a", start + DEDUP_WINDOW));
+  }
+
+  #[test]
+  fn reports_are_capped_per_rate_window() {
+    let mut limiter = ReportLimiter::default();
+    let start = Instant::now();
+    for x in 0..RATE_LIMIT {
+      assert!(limiter.allow(&x.to_string(), start));
+    }
+    assert!(!limiter.allow("over", start + Duration::from_secs(1)));
+    // A dropped report doesn't count against the cap or start its dedup window.
+    assert!(limiter.allow("over", start + RATE_WINDOW));
+  }
+}
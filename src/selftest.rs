@@ -0,0 +1,230 @@
+use std::sync::RwLock;
+
+use chrono::Utc;
+use hyper::client::connect::Connect;
+use hyper::Client;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::{json, Value};
+use teloxide::requests::{Request, Requester};
+use tracing::{error, info};
+
+use crate::center::{request_slots, CenterId, CentersConfig};
+use crate::health;
+use crate::storage::{self, Storage};
+use crate::CONFIG;
+
+/// Outcome of one startup check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+  pub name: &'static str,
+  /// The bot can't work at all when a critical check fails.
+  pub critical: bool,
+  pub ok: bool,
+  pub detail: String,
+}
+
+impl Check {
+  pub fn new(name: &'static str, critical: bool, result: Result<String, String>) -> Self {
+    let (ok, detail) = match result {
+      Ok(detail) => (true, detail),
+      Err(detail) => (false, detail),
+    };
+    Self {
+      name,
+      critical,
+      ok,
+      detail,
+    }
+  }
+
+  fn log(&self) {
+    if self.ok {
+      info!("Self test {} passed: {}", self.name, self.detail);
+    } else {
+      error!("Self test {} failed: {}", self.name, self.detail);
+    }
+  }
+}
+
+lazy_static! {
+  static ref RESULTS: RwLock<Vec<Check>> = RwLock::new(Vec::new());
+}
+
+pub fn check_centers(centers: &Result<CentersConfig, String>) -> Result<String, String> {
+  match centers {
+    Ok(centers) if centers.centers.iter().any(|x| x.enabled) => Ok(format!(
+      "{} centers, {} regions",
+      centers.centers.len(),
+      centers.regions.len()
+    )),
+    Ok(_) => Err("no enabled centers".to_string()),
+    Err(err) => Err(err.clone()),
+  }
+}
+
+/// Pings storage and makes sure a value written can be read back.
+pub async fn check_storage(storage: &mut dyn Storage) -> Result<String, String> {
+  storage.ping().await.map_err(|err| format!("ping failed: {}", err))?;
+
+  let written = Utc::now().timestamp_millis().to_string();
+  storage
+    .set("self_test", written.clone())
+    .await
+    .map_err(|err| format!("write failed: {}", err))?;
+  let read = storage
+    .get("self_test")
+    .await
+    .map_err(|err| format!("read failed: {}", err))?;
+  let _ = storage.delete("self_test").await;
+
+  if read.as_deref() == Some(written.as_str()) {
+    Ok("round trip ok".to_string())
+  } else {
+    Err(format!("wrote {} but read {:?}", written, read))
+  }
+}
+
+pub async fn check_telegram<R: Requester>(bot: &R) -> Result<String, String> {
+  let me = bot.get_me().send().await.map_err(|err| err.to_string())?;
+  Ok(format!("authorized as @{}", me.user.username.unwrap_or_default()))
+}
+
+pub async fn check_scheduler<C: Connect + Clone + Send + Sync + 'static>(
+  http_client: &Client<C>,
+  center: CenterId,
+) -> Result<String, String> {
+  let slots = request_slots(http_client, &CONFIG.api_base, center).await?;
+  Ok(format!("{} slots at center {}", slots.len(), center))
+}
+
+/// Checks the centers file, storage, Telegram and the scheduler API.
+pub async fn run<C: Connect + Clone + Send + Sync + 'static, R: Requester>(
+  http_client: &Client<C>,
+  bot: &R,
+) -> Vec<Check> {
+  let centers = crate::load_centers();
+  let center = centers
+    .as_ref()
+    .ok()
+    .and_then(|x| x.centers.iter().find(|x| x.enabled))
+    .map(|x| x.id);
+
+  let storage = async {
+    let mut storage = storage::open().await?;
+    check_storage(storage.as_mut()).await
+  };
+  let telegram = check_telegram(bot).await;
+  if telegram.is_ok() {
+    health::TELEGRAM.mark();
+  }
+  let scheduler = match center {
+    Some(center) => check_scheduler(http_client, center).await,
+    None => Err("no enabled center to query".to_string()),
+  };
+
+  vec![
+    Check::new("centers", true, check_centers(&centers)),
+    Check::new("storage", true, storage.await),
+    Check::new("telegram", true, telegram),
+    Check::new("scheduler", false, scheduler),
+  ]
+}
+
+/// Records and logs the results of the startup checks.
+pub fn record(checks: Vec<Check>) {
+  checks.iter().for_each(Check::log);
+  *RESULTS.write().unwrap() = checks;
+}
+
+/// Whether the bot may start with `checks`. Failed non critical checks are
+/// tolerated when running degraded.
+pub fn can_start(checks: &[Check], allow_degraded: bool) -> bool {
+  checks.iter().all(|x| x.ok || (allow_degraded && !x.critical))
+}
+
+pub fn passed() -> bool {
+  RESULTS.read().unwrap().iter().all(|x| x.ok)
+}
+
+pub fn summary() -> Value {
+  json!(*RESULTS.read().unwrap())
+}
+
+/// One line per check, with failure details only when `details` is set.
+pub fn report(details: bool) -> String {
+  RESULTS
+    .read()
+    .unwrap()
+    .iter()
+    .map(|x| match (x.ok, details) {
+      (true, _) => format!("{}: ok", x.name),
+      (false, true) => format!("{}: FAILED ({})", x.name, x.detail),
+      (false, false) => format!("{}: FAILED", x.name),
+    })
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::center::testing::center;
+  use crate::storage::testing::TestStorage;
+  use crate::storage::MemoryStorage;
+
+  #[tokio::test]
+  async fn storage_must_round_trip_a_value() {
+    let mut storage = MemoryStorage::default();
+    assert_eq!(check_storage(&mut storage).await, Ok("round trip ok".to_string()));
+    assert_eq!(storage.get("self_test").await.unwrap(), None);
+
+    let mut storage = TestStorage::default();
+    storage.set_down(true);
+    let err = check_storage(&mut storage).await.unwrap_err();
+    assert!(err.starts_with("ping failed: "), "{}", err);
+  }
+
+  #[test]
+  fn centers_need_one_enabled() {
+    let mut closed = center(5161, "niagara", "Niagara Falls EC");
+    closed.enabled = false;
+    let config = |centers| {
+      Ok(CentersConfig {
+        centers,
+        regions: Vec::new(),
+      })
+    };
+
+    assert_eq!(
+      check_centers(&config(vec![closed.clone(), center(5022, "buffalo", "Buffalo EC")])),
+      Ok("2 centers, 0 regions".to_string())
+    );
+    assert_eq!(
+      check_centers(&config(vec![closed])),
+      Err("no enabled centers".to_string())
+    );
+    assert_eq!(
+      check_centers(&Err("centers.toml: missing field `id`".to_string())),
+      Err("centers.toml: missing field `id`".to_string())
+    );
+  }
+
+  fn check(name: &'static str, critical: bool, ok: bool) -> Check {
+    Check::new(name, critical, if ok { Ok(String::new()) } else { Err(String::new()) })
+  }
+
+  #[test]
+  fn only_non_critical_failures_are_tolerated_when_degraded() {
+    let passing = [check("storage", true, true), check("scheduler", false, true)];
+    assert!(can_start(&passing, false));
+
+    let scheduler_down = [check("storage", true, true), check("scheduler", false, false)];
+    assert!(!can_start(&scheduler_down, false));
+    assert!(can_start(&scheduler_down, true));
+
+    let storage_down = [check("storage", true, false), check("scheduler", false, true)];
+    assert!(!can_start(&storage_down, false));
+    assert!(!can_start(&storage_down, true));
+  }
+}
@@ -0,0 +1,884 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use redis::aio::Connection;
+use redis::{AsyncCommands, Client, ErrorKind, RedisError, RedisResult};
+use rusqlite::OptionalExtension;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::config::{redact_url, StorageBackend};
+use crate::{health, metrics, report, CONFIG};
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+  #[error("redis request failed: {0}")]
+  Redis(#[from] RedisError),
+  #[error("sqlite request failed: {0}")]
+  Sqlite(#[from] rusqlite::Error),
+  #[error("sqlite request did not finish: {0}")]
+  Blocking(#[from] tokio::task::JoinError),
+}
+
+/// One write of a batch applied with [`Storage::write`].
+#[derive(Debug, Clone)]
+pub enum Write {
+  Set(String, String),
+  Delete(String),
+  /// Sets `field` of the hash at `key`.
+  HashSet(String, String, String),
+  /// Removes `field` from the hash at `key`.
+  HashDelete(String, String),
+}
+
+/// Where the tracking manager keeps its data: string values by key, plus the
+/// few sets, hashes and capped lists it needs.
+#[async_trait]
+pub trait Storage: Send {
+  async fn get(&mut self, key: &str) -> Result<Option<String>, StorageError>;
+
+  async fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError>;
+
+  /// Applies every write or none of them.
+  async fn write(&mut self, writes: Vec<Write>) -> Result<(), StorageError>;
+
+  async fn set(&mut self, key: &str, value: String) -> Result<(), StorageError> {
+    self.write(vec![Write::Set(key.to_string(), value)]).await
+  }
+
+  async fn delete(&mut self, key: &str) -> Result<(), StorageError> {
+    self.write(vec![Write::Delete(key.to_string())]).await
+  }
+
+  async fn add_members(&mut self, key: &str, members: Vec<String>) -> Result<(), StorageError>;
+
+  async fn remove_member(&mut self, key: &str, member: &str) -> Result<(), StorageError>;
+
+  async fn members(&mut self, key: &str) -> Result<Vec<String>, StorageError>;
+
+  async fn hash_delete(&mut self, key: &str, field: &str) -> Result<(), StorageError> {
+    self
+      .write(vec![Write::HashDelete(key.to_string(), field.to_string())])
+      .await
+  }
+
+  /// Every field of the hash at `key` with its value.
+  async fn hash_entries(&mut self, key: &str) -> Result<Vec<(String, String)>, StorageError>;
+
+  /// Adds `value` to the front of the list at `key`, keeping its first `len`
+  /// entries.
+  async fn push_capped(&mut self, key: &str, value: String, len: usize) -> Result<(), StorageError>;
+
+  async fn ping(&mut self) -> Result<(), StorageError>;
+
+  /// Number of keys stored, for `/stats`.
+  async fn key_count(&mut self) -> Result<usize, StorageError>;
+}
+
+/// Storage shared between the tracking manager and requests made without
+/// holding it, locked for one request at a time.
+#[derive(Clone)]
+pub struct SharedStorage(Arc<tokio::sync::Mutex<Box<dyn Storage>>>);
+
+impl SharedStorage {
+  pub fn new(storage: Box<dyn Storage>) -> Self {
+    Self(Arc::new(tokio::sync::Mutex::new(storage)))
+  }
+
+  /// Holds the storage for several requests, e.g. so a write queued while the
+  /// manager was locked lands before any made after it is released.
+  pub async fn lock(&self) -> tokio::sync::OwnedMutexGuard<Box<dyn Storage>> {
+    self.0.clone().lock_owned().await
+  }
+}
+
+#[async_trait]
+impl Storage for SharedStorage {
+  async fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+    self.0.lock().await.get(key).await
+  }
+
+  async fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+    self.0.lock().await.get_many(keys).await
+  }
+
+  async fn write(&mut self, writes: Vec<Write>) -> Result<(), StorageError> {
+    self.0.lock().await.write(writes).await
+  }
+
+  async fn add_members(&mut self, key: &str, members: Vec<String>) -> Result<(), StorageError> {
+    self.0.lock().await.add_members(key, members).await
+  }
+
+  async fn remove_member(&mut self, key: &str, member: &str) -> Result<(), StorageError> {
+    self.0.lock().await.remove_member(key, member).await
+  }
+
+  async fn members(&mut self, key: &str) -> Result<Vec<String>, StorageError> {
+    self.0.lock().await.members(key).await
+  }
+
+  async fn hash_entries(&mut self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+    self.0.lock().await.hash_entries(key).await
+  }
+
+  async fn push_capped(&mut self, key: &str, value: String, len: usize) -> Result<(), StorageError> {
+    self.0.lock().await.push_capped(key, value, len).await
+  }
+
+  async fn ping(&mut self) -> Result<(), StorageError> {
+    self.0.lock().await.ping().await
+  }
+
+  async fn key_count(&mut self) -> Result<usize, StorageError> {
+    self.0.lock().await.key_count().await
+  }
+}
+
+/// Opens the configured storage backend.
+pub async fn open() -> Result<Box<dyn Storage>, String> {
+  match CONFIG.storage_backend {
+    StorageBackend::Redis => {
+      let client = Client::open(CONFIG.redis_addr.as_str())
+        .map_err(|err| format!("Invalid redis url {}: {}", redact_url(&CONFIG.redis_addr), err))?;
+      Ok(Box::new(RedisStorage::connect(client).await?))
+    },
+    StorageBackend::Sqlite => Ok(Box::new(SqliteStorage::open(&CONFIG.sqlite_path)?)),
+    StorageBackend::Memory => {
+      warn!("Using in memory storage, nothing is kept across restarts");
+      Ok(Box::new(MemoryStorage::default()))
+    },
+  }
+}
+
+/// Attempts made to connect to redis on startup before giving up.
+const CONNECT_ATTEMPTS: u32 = 10;
+const CONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const CONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Delay after failed connection attempt `attempt`, counting from 1.
+fn connect_delay(attempt: u32) -> Duration {
+  CONNECT_BASE_DELAY
+    .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    .min(CONNECT_MAX_DELAY)
+}
+
+/// Connects to redis, retrying with exponential backoff so a redis that is
+/// still starting up doesn't stop the bot.
+async fn connect(client: &Client) -> Result<Connection, String> {
+  let mut attempt = 1;
+  loop {
+    match client.get_async_connection().await {
+      Ok(connection) => return Ok(connection),
+      Err(err) if attempt < CONNECT_ATTEMPTS => {
+        let delay = connect_delay(attempt);
+        warn!(
+          "Could not connect to redis (attempt {}/{}), retrying in {:?}: {}",
+          attempt, CONNECT_ATTEMPTS, delay, err
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+      },
+      Err(err) => {
+        return Err(format!(
+          "Could not connect to redis after {} attempts: {}",
+          CONNECT_ATTEMPTS, err
+        ))
+      },
+    }
+  }
+}
+
+/// Whether the last storage request failed, to report outage transitions once.
+static STORAGE_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Counts failed storage requests in the metrics and records successful ones
+/// for the readiness check.
+fn count_error<T>(result: Result<T, StorageError>) -> Result<T, StorageError> {
+  match &result {
+    Err(err) => {
+      metrics::REDIS_ERRORS.inc();
+      if !STORAGE_DOWN.swap(true, Ordering::Relaxed) {
+        report::report("storage outage", &err.to_string());
+      }
+    },
+    Ok(_) => {
+      health::REDIS.mark();
+      if STORAGE_DOWN.swap(false, Ordering::Relaxed) {
+        report::report("storage recovered", "Storage requests are succeeding again");
+      }
+    },
+  }
+  result
+}
+
+pub struct RedisStorage {
+  client: Client,
+  connection: Connection,
+  /// Set when a request failed because the connection is gone, so a new one
+  /// is made before the next request.
+  reconnect_needed: bool,
+}
+
+impl RedisStorage {
+  pub async fn connect(client: Client) -> Result<Self, String> {
+    Ok(Self {
+      connection: connect(&client).await?,
+      client,
+      reconnect_needed: false,
+    })
+  }
+
+  /// Replaces the connection after a request found it dropped. A failed
+  /// attempt is retried before the next request.
+  async fn reconnect_if_needed(&mut self) {
+    if !self.reconnect_needed {
+      return;
+    }
+    match self.client.get_async_connection().await {
+      Ok(connection) => {
+        info!("Reconnected to redis");
+        self.connection = connection;
+        self.reconnect_needed = false;
+      },
+      Err(err) => warn!("Could not reconnect to redis: {}", err),
+    }
+  }
+
+  /// Runs a request on a live connection, counting its outcome. Type errors
+  /// are what redis gives for values of the wrong type and don't count as
+  /// failures.
+  async fn run<T, F>(&mut self, request: F) -> Result<T, StorageError>
+  where
+    F: for<'a> FnOnce(&'a mut Connection) -> futures::future::BoxFuture<'a, RedisResult<T>>,
+  {
+    self.reconnect_if_needed().await;
+    let result = request(&mut self.connection).await;
+    if let Err(err) = &result {
+      if err.is_connection_dropped() || err.is_connection_refusal() || err.is_io_error() {
+        self.reconnect_needed = true;
+      }
+      if err.kind() == ErrorKind::TypeError {
+        health::REDIS.mark();
+        return result.map_err(StorageError::from);
+      }
+    }
+    count_error(result.map_err(StorageError::from))
+  }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+  async fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+    let key = key.to_string();
+    self.run(|x| Box::pin(async move { x.get(key).await })).await
+  }
+
+  async fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+    if keys.is_empty() {
+      return Ok(Vec::new());
+    }
+    let keys = keys.to_vec();
+    self
+      .run(|x| Box::pin(async move { redis::cmd("MGET").arg(keys).query_async(x).await }))
+      .await
+  }
+
+  async fn write(&mut self, writes: Vec<Write>) -> Result<(), StorageError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for write in writes {
+      match write {
+        Write::Set(key, value) => pipe.set(key, value).ignore(),
+        Write::Delete(key) => pipe.del(key).ignore(),
+        Write::HashSet(key, field, value) => pipe.hset(key, field, value).ignore(),
+        Write::HashDelete(key, field) => pipe.hdel(key, field).ignore(),
+      };
+    }
+    self
+      .run(|x| Box::pin(async move { pipe.query_async::<_, ()>(x).await }))
+      .await
+  }
+
+  async fn add_members(&mut self, key: &str, members: Vec<String>) -> Result<(), StorageError> {
+    if members.is_empty() {
+      return Ok(());
+    }
+    let key = key.to_string();
+    self.run(|x| Box::pin(async move { x.sadd(key, members).await })).await
+  }
+
+  async fn remove_member(&mut self, key: &str, member: &str) -> Result<(), StorageError> {
+    let (key, member) = (key.to_string(), member.to_string());
+    self.run(|x| Box::pin(async move { x.srem(key, member).await })).await
+  }
+
+  async fn members(&mut self, key: &str) -> Result<Vec<String>, StorageError> {
+    let key = key.to_string();
+    self.run(|x| Box::pin(async move { x.smembers(key).await })).await
+  }
+
+  async fn hash_entries(&mut self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+    let key = key.to_string();
+    let entries: HashMap<String, String> = self.run(|x| Box::pin(async move { x.hgetall(key).await })).await?;
+    Ok(entries.into_iter().collect())
+  }
+
+  async fn push_capped(&mut self, key: &str, value: String, len: usize) -> Result<(), StorageError> {
+    let key = key.to_string();
+    self
+      .run(|x| {
+        Box::pin(async move {
+          redis::pipe()
+            .lpush(&key, value)
+            .ltrim(&key, 0, len as isize - 1)
+            .query_async::<_, ()>(x)
+            .await
+        })
+      })
+      .await
+  }
+
+  async fn ping(&mut self) -> Result<(), StorageError> {
+    self
+      .run(|x| Box::pin(async move { redis::cmd("PING").query_async::<_, ()>(x).await }))
+      .await
+  }
+
+  async fn key_count(&mut self) -> Result<usize, StorageError> {
+    self
+      .run(|x| Box::pin(async move { redis::cmd("DBSIZE").query_async(x).await }))
+      .await
+  }
+}
+
+/// Tables the sqlite backend keeps redis' values, sets, hashes and lists in.
+const SQLITE_SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS kv (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS sets (key TEXT NOT NULL, member TEXT NOT NULL, PRIMARY KEY (key, member));
+  CREATE TABLE IF NOT EXISTS hashes (key TEXT NOT NULL, field TEXT NOT NULL, value TEXT NOT NULL,
+    PRIMARY KEY (key, field));
+  CREATE TABLE IF NOT EXISTS lists (id INTEGER PRIMARY KEY AUTOINCREMENT, key TEXT NOT NULL, value TEXT NOT NULL);
+";
+
+/// Storage in a single sqlite file, for deployments without a redis server.
+/// Requests run on the blocking thread pool.
+pub struct SqliteStorage {
+  connection: Arc<Mutex<rusqlite::Connection>>,
+}
+
+impl SqliteStorage {
+  pub fn open(path: &Path) -> Result<Self, String> {
+    let connection = rusqlite::Connection::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    connection
+      .execute_batch(SQLITE_SCHEMA)
+      .map_err(|err| format!("{}: {}", path.display(), err))?;
+    info!("Using sqlite storage at {}", path.display());
+    Ok(Self {
+      connection: Arc::new(Mutex::new(connection)),
+    })
+  }
+
+  async fn run<T, F>(&self, request: F) -> Result<T, StorageError>
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+  {
+    let connection = self.connection.clone();
+    let result = tokio::task::spawn_blocking(move || request(&mut connection.lock().unwrap())).await;
+    count_error(
+      result
+        .map_err(StorageError::from)
+        .and_then(|x| x.map_err(StorageError::from)),
+    )
+  }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+  async fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+    let key = key.to_string();
+    self
+      .run(move |x| {
+        x.query_row("SELECT value FROM kv WHERE key = ?1", [key], |row| row.get(0))
+          .optional()
+      })
+      .await
+  }
+
+  async fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+    let keys = keys.to_vec();
+    self
+      .run(move |x| {
+        let mut statement = x.prepare_cached("SELECT value FROM kv WHERE key = ?1")?;
+        keys
+          .iter()
+          .map(|key| statement.query_row([key], |row| row.get(0)).optional())
+          .collect()
+      })
+      .await
+  }
+
+  async fn write(&mut self, writes: Vec<Write>) -> Result<(), StorageError> {
+    self
+      .run(move |x| {
+        let transaction = x.transaction()?;
+        for write in writes {
+          match write {
+            Write::Set(key, value) => transaction.execute(
+              "INSERT INTO kv (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET value = ?2",
+              [key, value],
+            )?,
+            Write::Delete(key) => transaction.execute("DELETE FROM kv WHERE key = ?1", [key])?,
+            Write::HashSet(key, field, value) => transaction.execute(
+              "INSERT INTO hashes (key, field, value) VALUES (?1, ?2, ?3)
+               ON CONFLICT (key, field) DO UPDATE SET value = ?3",
+              [key, field, value],
+            )?,
+            Write::HashDelete(key, field) => {
+              transaction.execute("DELETE FROM hashes WHERE key = ?1 AND field = ?2", [key, field])?
+            },
+          };
+        }
+        transaction.commit()
+      })
+      .await
+  }
+
+  async fn add_members(&mut self, key: &str, members: Vec<String>) -> Result<(), StorageError> {
+    let key = key.to_string();
+    self
+      .run(move |x| {
+        let transaction = x.transaction()?;
+        for member in members {
+          transaction.execute(
+            "INSERT INTO sets (key, member) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+            [&key, &member],
+          )?;
+        }
+        transaction.commit()
+      })
+      .await
+  }
+
+  async fn remove_member(&mut self, key: &str, member: &str) -> Result<(), StorageError> {
+    let (key, member) = (key.to_string(), member.to_string());
+    self
+      .run(move |x| x.execute("DELETE FROM sets WHERE key = ?1 AND member = ?2", [key, member]))
+      .await
+      .map(|_| ())
+  }
+
+  async fn members(&mut self, key: &str) -> Result<Vec<String>, StorageError> {
+    let key = key.to_string();
+    self
+      .run(move |x| {
+        let mut statement = x.prepare_cached("SELECT member FROM sets WHERE key = ?1")?;
+        let members = statement.query_map([key], |row| row.get(0))?;
+        members.collect()
+      })
+      .await
+  }
+
+  async fn hash_entries(&mut self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+    let key = key.to_string();
+    self
+      .run(move |x| {
+        let mut statement = x.prepare_cached("SELECT field, value FROM hashes WHERE key = ?1")?;
+        let values = statement.query_map([key], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        values.collect()
+      })
+      .await
+  }
+
+  async fn push_capped(&mut self, key: &str, value: String, len: usize) -> Result<(), StorageError> {
+    let key = key.to_string();
+    self
+      .run(move |x| {
+        let transaction = x.transaction()?;
+        transaction.execute("INSERT INTO lists (key, value) VALUES (?1, ?2)", [&key, &value])?;
+        transaction.execute(
+          "DELETE FROM lists WHERE key = ?1 AND id NOT IN
+           (SELECT id FROM lists WHERE key = ?1 ORDER BY id DESC LIMIT ?2)",
+          rusqlite::params![key, len as i64],
+        )?;
+        transaction.commit()
+      })
+      .await
+  }
+
+  async fn ping(&mut self) -> Result<(), StorageError> {
+    self.run(|x| x.query_row("SELECT 1", [], |_| Ok(()))).await
+  }
+
+  async fn key_count(&mut self) -> Result<usize, StorageError> {
+    self
+      .run(|x| {
+        x.query_row(
+          "SELECT (SELECT COUNT(*) FROM kv) + (SELECT COUNT(DISTINCT key) FROM sets)
+             + (SELECT COUNT(DISTINCT key) FROM hashes)
+             + (SELECT COUNT(DISTINCT key) FROM lists)",
+          [],
+          |row| row.get::<_, i64>(0),
+        )
+      })
+      .await
+      .map(|x| x as usize)
+  }
+}
+
+/// Storage that lives only as long as the process, for trying the bot out.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+  values: HashMap<String, String>,
+  sets: HashMap<String, BTreeSet<String>>,
+  hashes: HashMap<String, BTreeMap<String, String>>,
+  lists: HashMap<String, Vec<String>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+  async fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+    Ok(self.values.get(key).cloned())
+  }
+
+  async fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+    Ok(keys.iter().map(|x| self.values.get(x).cloned()).collect())
+  }
+
+  async fn write(&mut self, writes: Vec<Write>) -> Result<(), StorageError> {
+    for write in writes {
+      match write {
+        Write::Set(key, value) => {
+          self.values.insert(key, value);
+        },
+        Write::Delete(key) => {
+          self.values.remove(&key);
+        },
+        Write::HashSet(key, field, value) => {
+          self.hashes.entry(key).or_default().insert(field, value);
+        },
+        Write::HashDelete(key, field) => {
+          if let Some(hash) = self.hashes.get_mut(&key) {
+            hash.remove(&field);
+            if hash.is_empty() {
+              self.hashes.remove(&key);
+            }
+          }
+        },
+      }
+    }
+    Ok(())
+  }
+
+  async fn add_members(&mut self, key: &str, members: Vec<String>) -> Result<(), StorageError> {
+    self.sets.entry(key.to_string()).or_default().extend(members);
+    Ok(())
+  }
+
+  async fn remove_member(&mut self, key: &str, member: &str) -> Result<(), StorageError> {
+    if let Some(set) = self.sets.get_mut(key) {
+      set.remove(member);
+      if set.is_empty() {
+        self.sets.remove(key);
+      }
+    }
+    Ok(())
+  }
+
+  async fn members(&mut self, key: &str) -> Result<Vec<String>, StorageError> {
+    Ok(
+      self
+        .sets
+        .get(key)
+        .map_or_else(Vec::new, |x| x.iter().cloned().collect()),
+    )
+  }
+
+  async fn hash_entries(&mut self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+    Ok(
+      self
+        .hashes
+        .get(key)
+        .map_or_else(Vec::new, |x| x.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+    )
+  }
+
+  async fn push_capped(&mut self, key: &str, value: String, len: usize) -> Result<(), StorageError> {
+    let list = self.lists.entry(key.to_string()).or_default();
+    list.insert(0, value);
+    list.truncate(len);
+    Ok(())
+  }
+
+  async fn ping(&mut self) -> Result<(), StorageError> {
+    Ok(())
+  }
+
+  async fn key_count(&mut self) -> Result<usize, StorageError> {
+    Ok(self.values.len() + self.sets.len() + self.hashes.len() + self.lists.len())
+  }
+}
+
+/// Storage for tests that can be taken down to simulate an outage.
+#[cfg(test)]
+pub mod testing {
+  use super::*;
+
+  /// In memory storage whose requests all fail while it is down. Clones share
+  /// the same data, so a test can inspect what a manager saved.
+  #[derive(Clone, Default)]
+  pub struct TestStorage {
+    inner: Arc<tokio::sync::Mutex<MemoryStorage>>,
+    down: Arc<AtomicBool>,
+  }
+
+  impl TestStorage {
+    pub fn set_down(&self, down: bool) {
+      self.down.store(down, Ordering::Relaxed);
+    }
+
+    async fn inner(&self) -> Result<tokio::sync::MutexGuard<'_, MemoryStorage>, StorageError> {
+      if self.down.load(Ordering::Relaxed) {
+        return Err(RedisError::from((ErrorKind::IoError, "storage is down")).into());
+      }
+      Ok(self.inner.lock().await)
+    }
+  }
+
+  #[async_trait]
+  impl Storage for TestStorage {
+    async fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+      self.inner().await?.get(key).await
+    }
+
+    async fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+      self.inner().await?.get_many(keys).await
+    }
+
+    async fn write(&mut self, writes: Vec<Write>) -> Result<(), StorageError> {
+      self.inner().await?.write(writes).await
+    }
+
+    async fn add_members(&mut self, key: &str, members: Vec<String>) -> Result<(), StorageError> {
+      self.inner().await?.add_members(key, members).await
+    }
+
+    async fn remove_member(&mut self, key: &str, member: &str) -> Result<(), StorageError> {
+      self.inner().await?.remove_member(key, member).await
+    }
+
+    async fn members(&mut self, key: &str) -> Result<Vec<String>, StorageError> {
+      self.inner().await?.members(key).await
+    }
+
+    async fn hash_entries(&mut self, key: &str) -> Result<Vec<(String, String)>, StorageError> {
+      self.inner().await?.hash_entries(key).await
+    }
+
+    async fn push_capped(&mut self, key: &str, value: String, len: usize) -> Result<(), StorageError> {
+      self.inner().await?.push_capped(key, value, len).await
+    }
+
+    async fn ping(&mut self) -> Result<(), StorageError> {
+      self.inner().await?.ping().await
+    }
+
+    async fn key_count(&mut self) -> Result<usize, StorageError> {
+      self.inner().await?.key_count().await
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sqlite() -> SqliteStorage {
+    SqliteStorage::open(Path::new(":memory:")).unwrap()
+  }
+
+  /// Exercises every request the tracking manager makes against `storage`.
+  async fn behaves_like_redis(mut storage: impl Storage) {
+    assert_eq!(storage.get("user:1").await.unwrap(), None);
+    storage.set("user:1", "a".to_string()).await.unwrap();
+    storage.set("user:1", "b".to_string()).await.unwrap();
+    storage.set("user:2", "c".to_string()).await.unwrap();
+    assert_eq!(storage.get("user:1").await.unwrap().as_deref(), Some("b"));
+    let keys = ["user:2", "user:3", "user:1"].map(String::from);
+    assert_eq!(
+      storage.get_many(&keys).await.unwrap(),
+      vec![Some("c".to_string()), None, Some("b".to_string())]
+    );
+    assert_eq!(storage.get_many(&[]).await.unwrap(), Vec::<Option<String>>::new());
+    storage.delete("user:2").await.unwrap();
+    assert_eq!(storage.get("user:2").await.unwrap(), None);
+
+    storage
+      .add_members("users", ["1", "2", "1"].map(String::from).to_vec())
+      .await
+      .unwrap();
+    storage.add_members("users", Vec::new()).await.unwrap();
+    storage.remove_member("users", "2").await.unwrap();
+    storage.remove_member("missing", "2").await.unwrap();
+    assert_eq!(storage.members("users").await.unwrap(), vec!["1"]);
+    assert!(storage.members("missing").await.unwrap().is_empty());
+
+    storage
+      .write(vec![
+        Write::HashSet("seen".to_string(), "a".to_string(), "1".to_string()),
+        Write::HashSet("seen".to_string(), "b".to_string(), "2".to_string()),
+        Write::HashSet("seen".to_string(), "a".to_string(), "3".to_string()),
+      ])
+      .await
+      .unwrap();
+    storage.hash_delete("seen", "b").await.unwrap();
+    assert_eq!(
+      storage.hash_entries("seen").await.unwrap(),
+      vec![("a".to_string(), "3".to_string())]
+    );
+
+    for x in 0..5 {
+      storage.push_capped("log", x.to_string(), 3).await.unwrap();
+    }
+
+    storage.ping().await.unwrap();
+    // user:1, users, seen and log.
+    assert_eq!(storage.key_count().await.unwrap(), 4);
+  }
+
+  #[tokio::test]
+  async fn memory_storage_behaves_like_redis() {
+    behaves_like_redis(MemoryStorage::default()).await;
+  }
+
+  #[tokio::test]
+  async fn sqlite_storage_behaves_like_redis() {
+    behaves_like_redis(sqlite()).await;
+  }
+
+  #[tokio::test]
+  async fn capped_lists_keep_the_newest_entries() {
+    let mut storage = MemoryStorage::default();
+    for x in 0..5 {
+      storage.push_capped("log", x.to_string(), 3).await.unwrap();
+    }
+    assert_eq!(storage.lists["log"], vec!["4", "3", "2"]);
+
+    let mut storage = sqlite();
+    for x in 0..5 {
+      storage.push_capped("log", x.to_string(), 3).await.unwrap();
+    }
+    let values = storage
+      .run(|x| {
+        let mut statement = x.prepare("SELECT value FROM lists WHERE key = 'log' ORDER BY id DESC")?;
+        let values = statement.query_map([], |row| row.get::<_, String>(0))?;
+        values.collect::<rusqlite::Result<Vec<_>>>()
+      })
+      .await
+      .unwrap();
+    assert_eq!(values, vec!["4", "3", "2"]);
+  }
+
+  #[tokio::test]
+  async fn sqlite_batches_are_all_or_nothing() {
+    let mut storage = sqlite();
+    storage.set("kept", "1".to_string()).await.unwrap();
+    storage
+      .run(|x| {
+        x.execute_batch(
+          "CREATE TRIGGER no_bad BEFORE INSERT ON kv WHEN NEW.key = 'bad' BEGIN SELECT RAISE(ABORT, 'bad'); END",
+        )
+      })
+      .await
+      .unwrap();
+    let result = storage
+      .write(vec![
+        Write::Set("kept".to_string(), "2".to_string()),
+        Write::Set("bad".to_string(), "x".to_string()),
+      ])
+      .await;
+    assert!(matches!(result, Err(StorageError::Sqlite(_))));
+    assert_eq!(storage.get("kept").await.unwrap().as_deref(), Some("1"));
+  }
+
+  #[tokio::test]
+  async fn sqlite_keeps_data_across_reopening() {
+    let path = std::env::temp_dir().join(format!("nexus-pls-test-{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    SqliteStorage::open(&path)
+      .unwrap()
+      .set("user:1", "a".to_string())
+      .await
+      .unwrap();
+    let value = SqliteStorage::open(&path).unwrap().get("user:1").await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(value.as_deref(), Some("a"));
+  }
+
+  #[test]
+  fn connect_delays_double_up_to_the_max() {
+    let delays = (1..CONNECT_ATTEMPTS).map(connect_delay).collect::<Vec<_>>();
+    assert_eq!(
+      delays,
+      [500, 1_000, 2_000, 4_000, 8_000, 16_000, 30_000, 30_000, 30_000].map(Duration::from_millis)
+    );
+    assert_eq!(connect_delay(u32::MAX), CONNECT_MAX_DELAY);
+  }
+
+  /// A local address nothing listens on yet.
+  fn free_address() -> std::net::SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn connecting_retries_until_redis_is_up() {
+    let address = free_address();
+    let server = tokio::spawn(async move {
+      tokio::time::sleep(Duration::from_millis(700)).await;
+      let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+      listener.accept().await.unwrap()
+    });
+
+    let started = std::time::Instant::now();
+    let client = Client::open(format!("redis://{}/", address)).unwrap();
+    assert!(RedisStorage::connect(client).await.is_ok());
+    // Refused at once and after the first delay, connected after the second.
+    assert!(started.elapsed() >= connect_delay(1) + connect_delay(2));
+    server.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn requests_reconnect_after_the_connection_drops() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+      // The first connection is severed on its first request.
+      let (mut severed, _) = listener.accept().await.unwrap();
+      let _ = severed.read(&mut [0; 1024]).await.unwrap();
+      drop(severed);
+
+      let (mut connection, _) = listener.accept().await.unwrap();
+      let _ = connection.read(&mut [0; 1024]).await.unwrap();
+      connection.write_all(b"$5\r\nvalue\r\n").await.unwrap();
+      connection
+    });
+
+    let client = Client::open(format!("redis://{}/", address)).unwrap();
+    let mut storage = RedisStorage::connect(client).await.unwrap();
+    assert!(storage.get("key").await.is_err());
+    assert!(storage.reconnect_needed);
+    assert_eq!(storage.get("key").await.unwrap().as_deref(), Some("value"));
+    assert!(!storage.reconnect_needed);
+    server.await.unwrap();
+  }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use sd_notify::NotifyState;
+use tracing::{info, warn};
+
+use crate::{health, CONFIG, MANAGER};
+
+/// Sends `state` to systemd. Does nothing when not started by a `Type=notify`
+/// unit, as `NOTIFY_SOCKET` is then unset.
+fn notify(state: NotifyState) {
+  if let Err(err) = sd_notify::notify(false, &[state]) {
+    warn!("Could not notify systemd: {}", err);
+  }
+}
+
+pub fn ready() {
+  notify(NotifyState::Ready);
+}
+
+pub fn stopping() {
+  notify(NotifyState::Stopping);
+}
+
+/// Whether every component succeeded within `max_age`. Components that have
+/// not succeeded yet are measured from `started`, giving them one `max_age` to
+/// come up.
+pub fn is_progressing(now: Instant, started: Instant, components: &[Option<Instant>], max_age: Duration) -> bool {
+  components
+    .iter()
+    .all(|x| now.saturating_duration_since(x.unwrap_or(started)) <= max_age)
+}
+
+/// Pings the systemd watchdog while the collector and dispatcher make
+/// progress, if the unit sets `WatchdogSec`. Pings stop when either stalls so
+/// systemd restarts the bot.
+pub fn spawn_watchdog() {
+  let mut usec = 0;
+  if !sd_notify::watchdog_enabled(false, &mut usec) {
+    return;
+  }
+
+  let interval = Duration::from_micros(usec) / 2;
+  let started = Instant::now();
+  info!("Pinging systemd watchdog every {:?}", interval);
+  tokio::spawn(async move {
+    loop {
+      tokio::time::sleep(interval).await;
+      // Every command handler needs the manager, so a stuck lock means the
+      // dispatcher can't make progress.
+      if tokio::time::timeout(interval, MANAGER.lock()).await.is_ok() {
+        health::DISPATCHER.mark();
+      }
+
+      let components = [health::COLLECTOR.last_success(), health::DISPATCHER.last_success()];
+      if is_progressing(Instant::now(), started, &components, CONFIG.ready_max_age) {
+        notify(NotifyState::Watchdog);
+      } else {
+        warn!("Collector or dispatcher stalled, withholding systemd watchdog ping");
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MAX_AGE: Duration = Duration::from_secs(60);
+
+  #[test]
+  fn components_have_one_max_age_to_come_up() {
+    let started = Instant::now();
+    assert!(is_progressing(started + MAX_AGE, started, &[None, None], MAX_AGE));
+    assert!(!is_progressing(
+      started + MAX_AGE + Duration::from_secs(1),
+      started,
+      &[None, Some(started + MAX_AGE)],
+      MAX_AGE
+    ));
+  }
+
+  #[test]
+  fn every_component_has_to_succeed_within_the_max_age() {
+    let started = Instant::now();
+    let now = started + Duration::from_secs(600);
+    let recent = Some(now - Duration::from_secs(10));
+    let stale = Some(now - MAX_AGE - Duration::from_secs(1));
+    assert!(is_progressing(now, started, &[recent, recent], MAX_AGE));
+    assert!(is_progressing(now, started, &[Some(now - MAX_AGE), recent], MAX_AGE));
+    assert!(!is_progressing(now, started, &[recent, stale], MAX_AGE));
+    assert!(!is_progressing(now, started, &[stale, recent], MAX_AGE));
+    // A success stamped after `now` is still fresh.
+    assert!(is_progressing(
+      now,
+      started,
+      &[Some(now + Duration::from_secs(1))],
+      MAX_AGE
+    ));
+    assert!(is_progressing(now, started, &[], MAX_AGE));
+  }
+}
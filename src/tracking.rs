@@ -1,52 +1,477 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-use redis::aio::Connection;
-use redis::{AsyncCommands, Client};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use teloxide::types::Update;
+use thiserror::Error;
 use tracing::{info, warn};
 
-use crate::center::CenterId;
+use crate::center::{
+  normalize_name, resolve_region, Availability, Center, CenterId, PendingNotification, Region, Service, Slot,
+};
+use crate::closure::Closure;
+use crate::storage::{SharedStorage, Storage, StorageError, Write};
+use crate::{centers, regions, CONFIG};
 
 pub type UserId = u64;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Why a change to a user's tracking failed. The first variants are the
+/// user's doing, the rest are storage failures that shouldn't be shown as is.
+#[derive(Debug, Error)]
+pub enum TrackingError {
+  #[error("already tracking this center")]
+  AlreadyTracking,
+  #[error("not tracking this center")]
+  NotTracking,
+  #[error("already tracking this region")]
+  AlreadyTrackingRegion,
+  #[error("not tracking this region")]
+  NotTrackingRegion,
+  #[error("not tracking any centers")]
+  NoSubscriptions,
+  #[error("already tracking the maximum of {0} centers")]
+  TooManySubscriptions(usize),
+  #[error("center is not snoozed")]
+  NotSnoozed,
+  #[error("user not found")]
+  UserNotFound,
+  #[error("{0}")]
+  Storage(#[from] StorageError),
+  #[error("could not serialize data: {0}")]
+  Serialization(#[from] serde_json::Error),
+  #[error("could not serialize data: {0}")]
+  TomlSerialization(#[from] toml::ser::Error),
+}
+
+impl TrackingError {
+  /// Whether the error is a storage failure rather than the user's doing.
+  pub fn is_internal(&self) -> bool {
+    matches!(
+      self,
+      Self::Storage(_) | Self::Serialization(_) | Self::TomlSerialization(_)
+    )
+  }
+
+  /// What to tell the user, without any details of storage failures.
+  pub fn user_message(&self) -> String {
+    match self {
+      Self::AlreadyTracking => "You are already tracking this center.".to_string(),
+      Self::NotTracking => "You are not tracking this center!".to_string(),
+      Self::AlreadyTrackingRegion => "You are already tracking this region.".to_string(),
+      Self::NotTrackingRegion => "You are not tracking this region!".to_string(),
+      Self::NoSubscriptions => "You are not tracking any centers!".to_string(),
+      Self::TooManySubscriptions(limit) => format!("You can track at most {} centers.", limit),
+      Self::NotSnoozed => "This center is not snoozed.".to_string(),
+      Self::UserNotFound => "You have no saved settings.".to_string(),
+      Self::Storage(_) | Self::Serialization(_) | Self::TomlSerialization(_) => {
+        "Something went wrong saving your settings, please try again later.".to_string()
+      },
+    }
+  }
+}
+
+/// How a user hears about open slots.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationMode {
+  /// A message as soon as a slot opens.
+  #[default]
+  Instant,
+  /// One summary of the open centers a day, at `digest_time`.
+  Digest,
+}
+
+impl NotificationMode {
+  pub fn parse(value: &str) -> Option<Self> {
+    match value.trim().to_lowercase().as_str() {
+      "instant" => Some(Self::Instant),
+      "digest" | "daily" => Some(Self::Digest),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
 pub struct UserData {
   pub subscriptions: Vec<CenterId>,
   pub chat_id: i64,
+  #[serde(default)]
+  pub map_link: bool,
+  #[serde(default)]
+  pub volatile: bool,
+  #[serde(default)]
+  pub active_until: Option<NaiveDate>,
+  #[serde(default)]
+  pub active_until_prompted: bool,
+  #[serde(default)]
+  pub muted: bool,
+  #[serde(default)]
+  pub quiet_hours: Option<(NaiveTime, NaiveTime)>,
+  /// Minutes the user's clock is ahead of UTC, for quiet hours. Without one
+  /// the bot's local time is used.
+  #[serde(default)]
+  pub utc_offset: Option<i32>,
+  #[serde(default)]
+  pub notification_mode: NotificationMode,
+  /// Day the last daily digest was sent.
+  #[serde(default)]
+  pub digest_sent: Option<NaiveDate>,
+  #[serde(default)]
+  pub home: Option<String>,
+  #[serde(default)]
+  pub regions: Vec<String>,
+  #[serde(default)]
+  pub earliest: Option<NaiveDate>,
+  #[serde(default)]
+  pub latest: Option<NaiveDate>,
+  /// Services of subscriptions that aren't for NEXUS.
+  #[serde(default)]
+  pub services: BTreeMap<CenterId, Service>,
+  /// Chat each subscription was made from. Centers missing here, such as
+  /// region members, are notified in `chat_id`.
+  #[serde(default)]
+  pub chats: BTreeMap<CenterId, i64>,
+  /// Tracked centers alerts are held back for, until the bot's local time
+  /// passes the expiry.
+  #[serde(default)]
+  pub snoozed: BTreeMap<CenterId, NaiveDateTime>,
+  /// Only alert for slots earlier than `best_seen` at their center.
+  #[serde(default)]
+  pub earlier_only: bool,
+  /// Soonest slot alerted per center while `earlier_only` is on, or the
+  /// date set with /threshold until a sooner slot is alerted.
+  #[serde(default)]
+  pub best_seen: BTreeMap<CenterId, NaiveDateTime>,
+  #[serde(default)]
+  pub last_notified: BTreeMap<CenterId, LastNotified>,
+}
+
+/// The last slot alert a user was sent for a center.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LastNotified {
+  /// When the alert was sent, in the bot's local time.
+  pub at: NaiveDateTime,
+  /// Start of the soonest slot in the alert.
+  pub slot: NaiveDateTime,
+}
+
+impl UserData {
+  /// Whether the user still wants notifications, i.e. `today` is not past
+  /// their `active_until` date.
+  pub fn is_active(&self, today: NaiveDate) -> bool {
+    !matches!(self.active_until, Some(until) if today > until)
+  }
+
+  /// Whether a slot on `date` falls in the user's date window. Without one
+  /// every date from `today` on is wanted.
+  pub fn wants_date(&self, date: NaiveDate, today: NaiveDate) -> bool {
+    date >= self.earliest.unwrap_or(today) && self.latest.is_none_or(|latest| date <= latest)
+  }
+
+  pub fn service_for(&self, center: CenterId) -> Service {
+    self.services.get(&center).copied().unwrap_or_default()
+  }
+
+  pub fn chat_for(&self, center: CenterId) -> i64 {
+    self.chats.get(&center).copied().unwrap_or(self.chat_id)
+  }
+
+  /// Gives subscriptions stored before chats were tracked per subscription
+  /// the user's single chat, returning whether any were missing one.
+  fn assign_chats(&mut self) -> bool {
+    let mut assigned = false;
+    for center in &self.subscriptions {
+      if !self.chats.contains_key(center) {
+        self.chats.insert(*center, self.chat_id);
+        assigned = true;
+      }
+    }
+    assigned
+  }
+
+  /// Whether a slot starting at `start` is worth an alert for a user who
+  /// only wants slots earlier than the soonest they have seen.
+  pub fn beats_best_seen(&self, center: CenterId, start: NaiveDateTime) -> bool {
+    !self.earlier_only || self.best_seen.get(&center).is_none_or(|best| start < *best)
+  }
+
+  pub fn is_snoozed(&self, center: CenterId, now: NaiveDateTime) -> bool {
+    self.snoozed.get(&center).is_some_and(|until| now < *until)
+  }
+
+  /// Drops snoozes that ended by `now`, returning whether there were any.
+  fn drop_expired_snoozes(&mut self, now: NaiveDateTime) -> bool {
+    let before = self.snoozed.len();
+    self.snoozed.retain(|_, until| now < *until);
+    self.snoozed.len() != before
+  }
+
+  /// The time on the user's clock at `now`.
+  pub fn clock(&self, now: DateTime<Utc>) -> NaiveTime {
+    match self.utc_offset {
+      Some(offset) => (now + chrono::Duration::minutes(offset.into())).time(),
+      None => now.with_timezone(&Local).time(),
+    }
+  }
+
+  /// Whether `now` falls in the user's quiet hours, which wrap past midnight
+  /// when they start later than they end.
+  pub fn is_quiet(&self, now: NaiveTime) -> bool {
+    match self.quiet_hours {
+      Some((start, end)) if start <= end => start <= now && now < end,
+      Some((start, end)) => now >= start || now < end,
+      None => false,
+    }
+  }
+
+  pub fn window_description(&self) -> Option<String> {
+    match (self.earliest, self.latest) {
+      (Some(earliest), Some(latest)) => Some(format!("Slots from {} to {}", earliest, latest)),
+      (Some(earliest), None) => Some(format!("Slots from {}", earliest)),
+      (None, Some(latest)) => Some(format!("Slots until {}", latest)),
+      (None, None) => None,
+    }
+  }
+
+  pub fn is_tracking_region(&self, region: &str) -> bool {
+    self.regions.iter().any(|x| normalize_name(x) == normalize_name(region))
+  }
+
+  /// Directly tracked centers plus the current members of tracked regions,
+  /// so centers added to a region reach its existing subscribers.
+  pub fn tracked_centers(&self) -> Vec<CenterId> {
+    self.tracked_centers_in(&regions(), &centers())
+  }
+
+  /// [`Self::tracked_centers`] with the given regions and known centers.
+  pub fn tracked_centers_in(&self, regions: &[Region], centers: &[Center]) -> Vec<CenterId> {
+    let mut tracked = self.subscriptions.clone();
+    for region in self.regions.iter().filter_map(|x| resolve_region(regions, x)) {
+      tracked.extend(region.members(centers).iter().map(|x| x.id));
+    }
+    tracked.sort_unstable();
+    tracked.dedup();
+    tracked
+  }
 }
 
 impl From<(Vec<u32>, i64)> for UserData {
   fn from((subscriptions, chat_id): (Vec<u32>, i64)) -> Self {
-    Self { subscriptions, chat_id }
+    Self {
+      subscriptions,
+      chat_id,
+      ..Default::default()
+    }
   }
 }
 
+/// Parses a stored user or user list, falling back to the toml older versions
+/// wrote. The flag is set when the value came from toml and should be
+/// rewritten as JSON.
+fn parse_stored<T: DeserializeOwned>(raw: &str) -> Option<(T, bool)> {
+  match serde_json::from_str(raw) {
+    Ok(value) => Some((value, false)),
+    Err(_) => toml::from_str(raw).ok().map(|value| (value, true)),
+  }
+}
+
+/// Set of every user id with stored data.
+pub const USERS_KEY: &str = "users";
+
+/// The user list as older versions stored it, a single value under
+/// `all_users`.
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 struct AllUsers {
   pub list: Vec<UserId>,
 }
 
-impl From<Vec<UserId>> for AllUsers {
-  fn from(list: Vec<UserId>) -> Self {
-    Self { list }
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+struct ClosedCenters {
+  pub list: Vec<CenterId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StoredClosure {
+  pub center: CenterId,
+  pub closure: Closure,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+struct Closures {
+  pub list: Vec<StoredClosure>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+struct PendingNotifications {
+  pub list: Vec<PendingNotification>,
+}
+
+/// Hash of the slot starts each user was alerted about, by `user:center`.
+const NOTIFIED_KEY: &str = "notified";
+
+/// A slot a user has already been alerted about, as stored in the single
+/// `notified_slots` list before [`NOTIFIED_KEY`].
+#[derive(Debug, Deserialize)]
+struct NotifiedSlot {
+  pub user: UserId,
+  pub center: CenterId,
+  pub start: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct NotifiedSlots {
+  pub list: Vec<NotifiedSlot>,
+}
+
+fn notified_field((user, center): (UserId, CenterId)) -> String {
+  format!("{}:{}", user, center)
+}
+
+fn parse_notified_field(field: &str) -> Option<(UserId, CenterId)> {
+  let (user, center) = field.split_once(':')?;
+  Some((user.parse().ok()?, center.parse().ok()?))
+}
+
+/// Entries kept in the audit log.
+const AUDIT_LOG_LEN: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+  pub time: i64,
+  pub source: String,
+  pub user: UserId,
+  pub action: String,
+}
+
+/// Overview of the bot's users and what the collector polls, for `/stats`.
+pub struct Stats {
+  pub users: usize,
+  pub active_users: usize,
+  /// Active subscribers per center with the soonest slot last seen there,
+  /// most subscribed first.
+  pub centers: Vec<(CenterId, usize, Option<Slot>)>,
+  /// Subscribed centers that aren't closed, i.e. the ones polled each cycle.
+  pub polled_centers: usize,
+  pub storage_keys: Option<usize>,
+}
+
+/// A slot found during a user's quiet hours, held for their digest.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DigestEntry {
+  pub center: CenterId,
+  pub start: String,
+}
+
+/// Active subscribers per center as of `date`, as active periods end with
+/// the day.
+#[derive(Debug, Default)]
+struct SubscriberIndex {
+  date: Option<NaiveDate>,
+  centers: HashMap<CenterId, Vec<UserId>>,
+  /// Centers each user is indexed under, so a change is worked out against
+  /// what was indexed even after the regions changed.
+  tracked: HashMap<UserId, Vec<CenterId>>,
+}
+
+impl SubscriberIndex {
+  fn build(
+    users: &[UserId],
+    user_data: &HashMap<UserId, UserData>,
+    today: NaiveDate,
+    regions: &[Region],
+    centers: &[Center],
+  ) -> Self {
+    let mut index = Self {
+      date: Some(today),
+      ..Default::default()
+    };
+    for user in users {
+      index.update(*user, user_data.get(user), regions, centers);
+    }
+    index
+  }
+
+  /// Moves `user` from the centers they are indexed under to the ones
+  /// `current` tracks. An index that hasn't been built yet is left alone.
+  fn update(&mut self, user: UserId, current: Option<&UserData>, regions: &[Region], centers: &[Center]) {
+    let Some(date) = self.date else {
+      return;
+    };
+    let current = match current {
+      Some(user_data) if user_data.is_active(date) => user_data.tracked_centers_in(regions, centers),
+      _ => Vec::new(),
+    };
+    let previous = self.tracked.remove(&user).unwrap_or_default();
+    for center in previous.iter().filter(|x| !current.contains(x)) {
+      if let Some(users) = self.centers.get_mut(center) {
+        users.retain(|x| *x != user);
+        if users.is_empty() {
+          self.centers.remove(center);
+        }
+      }
+    }
+    for center in current.iter().filter(|x| !previous.contains(x)) {
+      let users = self.centers.entry(*center).or_default();
+      if !users.contains(&user) {
+        users.push(user);
+      }
+    }
+    if !current.is_empty() {
+      self.tracked.insert(user, current);
+    }
   }
 }
 
 pub struct TrackingManager {
-  db_connection: Connection,
+  storage: SharedStorage,
   user_data: HashMap<UserId, UserData>,
   all_users: AllUsers,
+  closed_centers: HashSet<CenterId>,
+  availability: HashMap<CenterId, Availability>,
+  closures: Closures,
+  subscribers: SubscriberIndex,
+  notified: HashMap<(UserId, CenterId), BTreeSet<String>>,
+  /// Slots held per user until their quiet hours end.
+  digests: BTreeMap<UserId, Vec<DigestEntry>>,
+  /// Users whose cached data couldn't be saved, written again once storage
+  /// recovers. Their cached data is newer than the stored one until then.
+  unsaved: BTreeSet<UserId>,
+  /// Counts changes to the cached user data, so data read from storage
+  /// without holding the manager isn't cached over newer changes.
+  changes: u64,
+}
+
+/// Subscribers being reloaded from storage without holding the manager, see
+/// [`TrackingManager::begin_refresh`].
+pub struct Refresh {
+  users: Vec<UserId>,
+  pub keys: Vec<String>,
+  changes: u64,
 }
 
 impl TrackingManager {
-  pub async fn new(client: Client) -> Self {
+  pub async fn new(storage: Box<dyn Storage>) -> Self {
     let mut s = Self {
-      db_connection: client.get_async_connection().await.unwrap(),
+      storage: SharedStorage::new(storage),
       user_data: HashMap::new(),
       all_users: AllUsers::default(),
+      closed_centers: HashSet::new(),
+      availability: HashMap::new(),
+      closures: Closures::default(),
+      subscribers: SubscriberIndex::default(),
+      notified: HashMap::new(),
+      digests: BTreeMap::new(),
+      unsaved: BTreeSet::new(),
+      changes: 0,
     };
 
+    s.migrate_all_users().await;
     s.sync_all_users().await;
+    s.sync_closed_centers().await;
+    s.sync_closures().await;
+    s.sync_notified().await;
+    s.sync_digests().await;
 
     for user in s.all_users.list.clone() {
       if let Some(user_data) = s.get_db_user_data(user).await {
@@ -63,148 +488,1577 @@ impl TrackingManager {
   }
 
   async fn get_db_user_data(&mut self, user: UserId) -> Option<UserData> {
-    let user_data: Result<String, _> = self.db_connection.get(user).await;
+    let user_data = self.storage.get(&user.to_string()).await;
 
-    if let Ok(user_data) = user_data {
+    if let Ok(Some(user_data)) = user_data {
       info!("{}", user_data);
-      let data: Result<UserData, _> = toml::from_str(user_data.as_str());
-      if let Ok(user_data) = data {
+      if let Some((mut user_data, legacy)) = parse_stored::<UserData>(&user_data) {
+        let expired = user_data.drop_expired_snoozes(Local::now().naive_local());
+        if user_data.assign_chats() || expired || legacy {
+          self.migrate_user_data(user, &user_data).await;
+        }
         Some(user_data)
       } else {
         warn!("Could not parse user data");
         None
       }
     } else {
-      warn!("{}", user_data.unwrap_err().to_string());
+      if let Err(err) = user_data {
+        warn!(user_id = user, "Could not load user data, using cached data: {}", err);
+      }
       None
     }
   }
 
-  async fn ensure_user_in_list(&mut self, user: UserId) {
-    info!("Ensuring {} is in all users list", user);
-    let all_users: Result<String, _> = self.db_connection.get("all_users").await;
+  /// Rewrites a user stored in an older format, i.e. as toml or without a
+  /// chat per subscription, or with snoozes that have ended.
+  /// Failures are only logged, as the data read is still used.
+  async fn migrate_user_data(&mut self, user: UserId, user_data: &UserData) {
+    info!(user_id = user, "Migrating user data");
+    if let Err(err) = self.store_user_data(user, user_data).await {
+      warn!(user_id = user, "Could not migrate user data: {}", err);
+    }
+  }
+
+  async fn store_user_data(&mut self, user: UserId, user_data: &UserData) -> Result<(), TrackingError> {
+    Ok(
+      self
+        .storage
+        .set(&user.to_string(), serde_json::to_string(user_data)?)
+        .await?,
+    )
+  }
 
-    info!("{:?}", all_users);
-    if let Ok(all_users) = all_users {
-      let data: Result<AllUsers, _> = toml::from_str(all_users.as_str());
-      if let Ok(mut all_users) = data {
-        if !all_users.list.contains(&user) {
-          all_users.list.push(user);
-          self.all_users = all_users.clone();
-          let all_users: String = toml::to_string(&all_users).unwrap();
-          let _: Result<(), _> = self.db_connection.set("all_users", all_users).await;
-        }
+  async fn ensure_user_in_list(&mut self, user: UserId) -> Result<(), StorageError> {
+    self.storage.add_members(USERS_KEY, vec![user.to_string()]).await?;
+    if !self.all_users.list.contains(&user) {
+      self.all_users.list.push(user);
+    }
+    Ok(())
+  }
+
+  /// Moves the user list older versions kept under `all_users` into the
+  /// users set. The old key is only deleted once every user is in the set.
+  async fn migrate_all_users(&mut self) {
+    let stored = match self.storage.get("all_users").await {
+      Ok(Some(stored)) => stored,
+      Ok(None) => return,
+      Err(err) => {
+        warn!("Could not check for an all users list to migrate: {}", err);
         return;
-      } else {
-        warn!("Failed to parse all users");
-      }
+      },
+    };
+    let Some((all_users, _)) = parse_stored::<AllUsers>(&stored) else {
+      warn!("Could not parse all users list from db, leaving it in place");
+      return;
+    };
+
+    info!(users = all_users.list.len(), "Migrating all users list to a set");
+    let members = all_users.list.iter().map(|x| x.to_string()).collect();
+    if let Err(err) = self.storage.add_members(USERS_KEY, members).await {
+      warn!("Could not migrate all users list: {}", err);
+      return;
     }
+    if let Err(err) = self.storage.delete("all_users").await {
+      warn!("Could not delete migrated all users list: {}", err);
+    }
+  }
 
-    warn!("Failed to get all users, defaulting to new list. Hopefully this is expected");
-    let _: Result<(), _> = self
-      .db_connection
-      .set("all_users", toml::to_string(&AllUsers::from(vec![user])).unwrap())
-      .await;
+  /// Saves `user_data`, which has to be cached already. When storage is
+  /// down the cached data is kept and saved by [`Self::save_unsaved`] later.
+  async fn set_db_user_data(&mut self, user: UserId, user_data: UserData) -> Result<(), TrackingError> {
+    let user_data: String = serde_json::to_string(&user_data)?;
+    let result = match self.ensure_user_in_list(user).await {
+      Ok(()) => self.storage.set(&user.to_string(), user_data).await,
+      Err(err) => Err(err),
+    };
+    match result {
+      Ok(()) => {
+        self.unsaved.remove(&user);
+      },
+      Err(err) => {
+        warn!(
+          user_id = user,
+          "Could not save user data, keeping it until storage recovers: {}", err
+        );
+        if !self.all_users.list.contains(&user) {
+          self.all_users.list.push(user);
+        }
+        self.unsaved.insert(user);
+      },
+    }
+    Ok(())
   }
 
-  async fn set_db_user_data(&mut self, user: UserId, user_data: UserData) -> Result<(), String> {
-    let user_data: String = toml::to_string(&user_data).unwrap();
-    self.ensure_user_in_list(user).await;
-    self.db_connection.set(user, user_data).await.map_err(|x| x.to_string())
+  /// Saves the users whose data couldn't be saved while storage was down,
+  /// stopping at the first failure. Returns how many are left unsaved.
+  pub async fn save_unsaved(&mut self) -> usize {
+    while let Some(user) = self.unsaved.first().copied() {
+      let Some(user_data) = self.user_data.get(&user).cloned() else {
+        self.unsaved.remove(&user);
+        continue;
+      };
+      let _ = self.set_db_user_data(user, user_data).await;
+      if self.unsaved.contains(&user) {
+        break;
+      }
+      info!(user_id = user, "Saved user data kept during storage outage");
+    }
+    self.unsaved.len()
   }
 
   async fn sync_all_users(&mut self) {
     info!("Syncing all users...");
-    let all_users: Result<String, _> = self.db_connection.get("all_users").await;
-    if let Ok(all_users) = all_users {
-      if let Ok(all_users) = toml::from_str(all_users.as_str()) {
-        self.all_users = all_users;
+    let members = match self.storage.members(USERS_KEY).await {
+      Ok(members) => members,
+      Err(err) => {
+        warn!("Could not get all users: {}", err);
+        return;
+      },
+    };
+    let mut list = members
+      .iter()
+      .filter_map(|x| match x.parse::<UserId>() {
+        Ok(user) => Some(user),
+        Err(_) => {
+          warn!("Skipping invalid user id {:?} in users set", x);
+          None
+        },
+      })
+      .collect::<Vec<_>>();
+    list.sort_unstable();
+
+    let (regions, centers) = (regions(), centers());
+    for user in self.all_users.list.iter().filter(|x| !list.contains(x)) {
+      self.subscribers.update(*user, None, &regions, &centers);
+    }
+    for user in list.iter().filter(|x| !self.all_users.list.contains(x)) {
+      self
+        .subscribers
+        .update(*user, self.user_data.get(user), &regions, &centers);
+    }
+    self.all_users = AllUsers { list };
+  }
+
+  async fn sync_closed_centers(&mut self) {
+    if let Ok(Some(closed_centers)) = self.storage.get("closed_centers").await {
+      if let Ok(closed_centers) = toml::from_str::<ClosedCenters>(closed_centers.as_str()) {
+        self.closed_centers = closed_centers.list.into_iter().collect();
       } else {
-        warn!("Could not parse all users list from db!");
+        warn!("Could not parse closed centers list from db!");
       }
     } else {
-      warn!("Could not get all users!");
+      info!("No closed centers recorded");
+    }
+  }
+
+  pub fn get_closed_centers(&self) -> &HashSet<CenterId> {
+    &self.closed_centers
+  }
+
+  /// Replaces the set of temporarily closed centers, returning the centers
+  /// that closed and reopened since the last recorded status. Stored centers
+  /// that are no longer known count as reopened, which drops them.
+  pub async fn set_closed_centers(&mut self, closed: HashSet<CenterId>) -> (Vec<CenterId>, Vec<CenterId>) {
+    let newly_closed = closed.difference(&self.closed_centers).copied().collect::<Vec<_>>();
+    let reopened = self.closed_centers.difference(&closed).copied().collect::<Vec<_>>();
+
+    self.closed_centers = closed;
+    if !newly_closed.is_empty() || !reopened.is_empty() {
+      if let Err(err) = self.store_closed_centers().await {
+        warn!("Failed to persist closed centers: {}", err);
+      }
     }
+    (newly_closed, reopened)
   }
 
-  async fn sync_with_db(&mut self, user: UserId) -> Result<(), String> {
-    info!("Getting data for user id {}", user);
+  async fn store_closed_centers(&mut self) -> Result<(), TrackingError> {
+    let list = ClosedCenters {
+      list: self.closed_centers.iter().copied().collect(),
+    };
+    Ok(self.storage.set("closed_centers", toml::to_string(&list)?).await?)
+  }
 
-    self.sync_all_users().await;
+  async fn sync_notified(&mut self) {
+    match self.storage.hash_entries(NOTIFIED_KEY).await {
+      Ok(entries) => {
+        for (field, starts) in entries {
+          match (parse_notified_field(&field), serde_json::from_str(&starts)) {
+            (Some(key), Ok(starts)) => {
+              self.notified.insert(key, starts);
+            },
+            _ => warn!("Could not parse notified slots {} from db!", field),
+          }
+        }
+      },
+      Err(err) => warn!("Failed to load notified slots: {}", err),
+    }
 
-    if let Some(user_data) = self.get_db_user_data(user).await {
-      self.user_data.insert(user, user_data);
-    } else {
-      warn!("Could not find or parse user data");
+    let Ok(Some(legacy)) = self.storage.get("notified_slots").await else {
+      return;
+    };
+    let Ok(legacy) = toml::from_str::<NotifiedSlots>(&legacy) else {
+      warn!("Could not parse notified slots from db!");
+      return;
+    };
+    let mut keys = BTreeSet::new();
+    for slot in legacy.list {
+      keys.insert((slot.user, slot.center));
+      self
+        .notified
+        .entry((slot.user, slot.center))
+        .or_default()
+        .insert(slot.start);
+    }
+    let migrated = match self.notified_writes(keys) {
+      Ok(mut writes) => {
+        writes.push(Write::Delete("notified_slots".to_string()));
+        self.storage.write(writes).await.map_err(TrackingError::from)
+      },
+      Err(err) => Err(err),
+    };
+    if let Err(err) = migrated {
+      warn!("Failed to migrate notified slots: {}", err);
+    }
+  }
+
+  /// Writes saving the notified slots of each user and center in `keys`.
+  fn notified_writes(&self, keys: impl IntoIterator<Item = (UserId, CenterId)>) -> Result<Vec<Write>, TrackingError> {
+    let mut writes = Vec::new();
+    for key in keys {
+      writes.push(match self.notified.get(&key) {
+        Some(starts) => Write::HashSet(
+          NOTIFIED_KEY.to_string(),
+          notified_field(key),
+          serde_json::to_string(starts)?,
+        ),
+        None => Write::HashDelete(NOTIFIED_KEY.to_string(), notified_field(key)),
+      });
+    }
+    Ok(writes)
+  }
+
+  pub fn was_notified(&self, user: UserId, slot: &Slot) -> bool {
+    self
+      .notified
+      .get(&(user, slot.location_id))
+      .is_some_and(|x| x.contains(&slot.start_timestamp))
+  }
+
+  /// Remembers that `user` was alerted about each of `slots`, so they are only
+  /// alerted again once the slot has disappeared and reopened. Returns the
+  /// writes that persist it.
+  pub fn mark_notified(&mut self, notified: Vec<(UserId, Slot)>) -> Result<Vec<Write>, TrackingError> {
+    let mut changed = BTreeSet::new();
+    for (user, slot) in notified {
+      let key = (user, slot.location_id);
+      if self.notified.entry(key).or_default().insert(slot.start_timestamp) {
+        changed.insert(key);
+      }
+    }
+    self.notified_writes(changed)
+  }
+
+  /// Lowers the soonest slot seen per user and center to the starts of newly
+  /// alerted slots, for users only alerted about earlier slots. Returns the
+  /// users changed, to save with [`Self::user_writes`].
+  pub fn record_best_seen(&mut self, seen: Vec<(UserId, CenterId, NaiveDateTime)>) -> Vec<UserId> {
+    let mut changed = BTreeSet::new();
+    for (user, center, start) in seen {
+      if let Some(user_data) = self.user_data.get_mut(&user).filter(|x| x.earlier_only) {
+        let best = user_data.best_seen.entry(center).or_insert(start);
+        if start <= *best {
+          *best = start;
+          changed.insert(user);
+        }
+      }
     }
+    if !changed.is_empty() {
+      self.changes += 1;
+    }
+    changed.into_iter().collect()
+  }
 
+  /// Records the alerts sent at `at`, as user, center and soonest slot start,
+  /// for `/status`.
+  pub async fn record_last_notified(
+    &mut self,
+    sent: Vec<(UserId, CenterId, NaiveDateTime)>,
+    at: NaiveDateTime,
+  ) -> Result<(), TrackingError> {
+    let mut changed = BTreeSet::new();
+    for (user, center, slot) in sent {
+      if let Some(user_data) = self.user_data.get_mut(&user) {
+        user_data.last_notified.insert(center, LastNotified { at, slot });
+        changed.insert(user);
+      }
+    }
+    for user in changed {
+      let user_data = self.user_data[&user].clone();
+      self.set_db_user_data(user, user_data).await?;
+    }
     Ok(())
   }
 
-  pub async fn track_center(&mut self, channel_id: i64, user: UserId, center: CenterId) -> Result<(), String> {
-    if let Err(err) = self.sync_with_db(user).await {
+  /// Forgets alerts for slots of `center` that are no longer `available`, and
+  /// for any slot that has already started.
+  pub async fn forget_unavailable_slots(
+    &mut self,
+    center: CenterId,
+    available: &[Slot],
+    now: NaiveDateTime,
+  ) -> Result<(), TrackingError> {
+    let mut changed = Vec::new();
+    self.notified.retain(|key, starts| {
+      let count = starts.len();
+      starts.retain(|start| {
+        let upcoming = matches!(NaiveDateTime::parse_from_str(start, "%Y-%m-%dT%H:%M"), Ok(start) if start > now);
+        upcoming && (key.1 != center || available.iter().any(|slot| slot.start_timestamp == *start))
+      });
+      if starts.len() != count {
+        changed.push(*key);
+      }
+      !starts.is_empty()
+    });
+    if changed.is_empty() {
+      return Ok(());
+    }
+    let writes = self.notified_writes(changed)?;
+    Ok(self.storage.write(writes).await?)
+  }
+
+  async fn sync_digests(&mut self) {
+    match self.storage.get("quiet_digests").await {
+      Ok(Some(digests)) => match serde_json::from_str(&digests) {
+        Ok(digests) => self.digests = digests,
+        Err(err) => warn!("Could not parse quiet hour digests from db: {}", err),
+      },
+      Ok(None) => info!("No quiet hour digests recorded"),
+      Err(err) => warn!("Could not get quiet hour digests: {}", err),
+    }
+  }
+
+  fn digests_write(&self) -> Result<Write, TrackingError> {
+    Ok(Write::Set(
+      "quiet_digests".to_string(),
+      serde_json::to_string(&self.digests)?,
+    ))
+  }
+
+  async fn store_digests(&mut self) -> Result<(), TrackingError> {
+    let write = self.digests_write()?;
+    Ok(self.storage.write(vec![write]).await?)
+  }
+
+  /// Holds slots found during users' quiet hours for their digests. Returns
+  /// the writes that persist them.
+  pub fn add_to_digests(&mut self, entries: Vec<(UserId, DigestEntry)>) -> Result<Vec<Write>, TrackingError> {
+    if entries.is_empty() {
+      return Ok(Vec::new());
+    }
+    for (user, entry) in entries {
+      let digest = self.digests.entry(user).or_default();
+      if !digest.contains(&entry) {
+        digest.push(entry);
+      }
+    }
+    Ok(vec![self.digests_write()?])
+  }
+
+  /// Users with slots held for a digest.
+  pub fn digest_users(&self) -> Vec<UserId> {
+    self.digests.keys().copied().collect()
+  }
+
+  /// Removes and returns the slots held for `user`.
+  pub async fn take_digest(&mut self, user: UserId) -> Result<Vec<DigestEntry>, TrackingError> {
+    let Some(digest) = self.digests.remove(&user) else {
+      return Ok(Vec::new());
+    };
+    if let Err(err) = self.store_digests().await {
+      self.digests.insert(user, digest);
       return Err(err);
     }
+    Ok(digest)
+  }
 
-    let current_list = self.user_data.get_mut(&user).cloned();
-    if let Some(mut current_list) = current_list {
-      if current_list.subscriptions.contains(&center) {
-        Err("You are already tracking this center.".to_string())
+  async fn sync_closures(&mut self) {
+    if let Ok(Some(closures)) = self.storage.get("closures").await {
+      if let Ok(closures) = toml::from_str::<Closures>(closures.as_str()) {
+        self.closures = closures;
       } else {
-        current_list.subscriptions.push(center);
-        self.user_data.insert(user, current_list.clone());
-        self.set_db_user_data(user, current_list).await
+        warn!("Could not parse closures from db!");
       }
     } else {
-      let list = Vec::from([center]);
-      let user_data = UserData::from((list, channel_id));
-      self.user_data.insert(user, user_data.clone());
-      self.set_db_user_data(user, user_data).await
+      info!("No closures recorded");
     }
   }
 
-  pub async fn untrack_center(&mut self, user: UserId, center: CenterId) -> Result<(), String> {
-    if let Err(err) = self.sync_with_db(user).await {
-      return Err(err);
+  /// Closures added at runtime with `/addclosure`, on top of the ones in the
+  /// centers config.
+  pub fn get_closures(&self, center: CenterId) -> Vec<Closure> {
+    self
+      .closures
+      .list
+      .iter()
+      .filter(|x| x.center == center)
+      .map(|x| x.closure)
+      .collect()
+  }
+
+  pub async fn add_closure(&mut self, center: CenterId, closure: Closure) -> Result<(), TrackingError> {
+    let mut closures = self.closures.clone();
+    closures.list.push(StoredClosure { center, closure });
+    self.storage.set("closures", toml::to_string(&closures)?).await?;
+    self.closures = closures;
+    Ok(())
+  }
+
+  /// Saves notifications that could not be sent before shutdown.
+  pub async fn push_pending_notifications(
+    &mut self,
+    notifications: Vec<PendingNotification>,
+  ) -> Result<(), TrackingError> {
+    let mut pending = self.take_pending_notifications().await;
+    pending.extend(notifications);
+    Ok(
+      self
+        .storage
+        .set(
+          "pending_notifications",
+          toml::to_string(&PendingNotifications { list: pending })?,
+        )
+        .await?,
+    )
+  }
+
+  /// Removes and returns the notifications saved at the last shutdown.
+  pub async fn take_pending_notifications(&mut self) -> Vec<PendingNotification> {
+    match self.storage.get("pending_notifications").await {
+      Ok(Some(pending)) => {
+        let _ = self.storage.delete("pending_notifications").await;
+        match toml::from_str::<PendingNotifications>(pending.as_str()) {
+          Ok(pending) => pending.list,
+          Err(_) => {
+            warn!("Could not parse pending notifications from db!");
+            Vec::new()
+          },
+        }
+      },
+      _ => Vec::new(),
     }
+  }
+
+  pub fn record_availability(&mut self, center: CenterId, availability: Availability) {
+    self.availability.insert(center, availability);
+  }
+
+  pub fn get_availability(&self, center: CenterId) -> Option<&Availability> {
+    self.availability.get(&center)
+  }
+
+  fn cache_user_data(&mut self, user: UserId, user_data: UserData) {
+    if self.user_data.get(&user) != Some(&user_data) {
+      self.changes += 1;
+      self.user_data.insert(user, user_data);
+      self
+        .subscribers
+        .update(user, self.user_data.get(&user), &regions(), &centers());
+    }
+  }
+
+  /// Starts reloading `users` with a single request made by the caller on
+  /// [`Self::storage`], leaving out users whose cached data is newer than the
+  /// stored one.
+  pub fn begin_refresh(&self, users: &[UserId]) -> Refresh {
+    let users = users
+      .iter()
+      .copied()
+      .filter(|x| !self.unsaved.contains(x))
+      .collect::<Vec<_>>();
+    Refresh {
+      keys: users.iter().map(|x| x.to_string()).collect(),
+      users,
+      changes: self.changes,
+    }
+  }
+
+  /// Caches the data read for `refresh`, unless user data changed since it
+  /// started, as then the read data may be older than the cached one. Returns
+  /// the writes migrating users stored in an older format.
+  pub fn finish_refresh(&mut self, refresh: Refresh, stored: Vec<Option<String>>) -> Result<Vec<Write>, TrackingError> {
+    if self.changes != refresh.changes {
+      info!("User data changed during refresh, keeping cached data");
+      return Ok(Vec::new());
+    }
+    let mut writes = Vec::new();
+    for (user, user_data) in refresh.users.iter().zip(stored) {
+      match user_data.map(|x| parse_stored::<UserData>(&x)) {
+        Some(Some((user_data, legacy))) => {
+          if legacy {
+            info!(user_id = *user, "Migrating user data");
+            writes.push(Write::Set(user.to_string(), serde_json::to_string(&user_data)?));
+          }
+          self.cache_user_data(*user, user_data)
+        },
+        Some(None) => warn!(user_id = *user, "Could not parse user data"),
+        None => {},
+      }
+    }
+    Ok(writes)
+  }
+
+  /// Handle on the manager's storage, for requests made without holding the
+  /// manager.
+  pub fn storage(&self) -> SharedStorage {
+    self.storage.clone()
+  }
+
+  /// Writes for the cached data of `users`.
+  pub fn user_writes(&self, users: &[UserId]) -> Result<Vec<Write>, TrackingError> {
+    let mut writes = Vec::new();
+    for user in users {
+      if let Some(user_data) = self.user_data.get(user) {
+        writes.push(Write::Set(user.to_string(), serde_json::to_string(user_data)?));
+      }
+    }
+    Ok(writes)
+  }
+
+  /// Keeps the cached data of `users` to save again with
+  /// [`Self::save_unsaved`], after writing it failed.
+  pub fn mark_unsaved(&mut self, users: impl IntoIterator<Item = UserId>) {
+    self.unsaved.extend(users);
+  }
+
+  /// User data as last loaded, without going to storage.
+  pub fn cached_user_data(&self, user: UserId) -> Option<&UserData> {
+    self.user_data.get(&user)
+  }
+
+  async fn sync_with_db(&mut self, user: UserId) -> Result<(), TrackingError> {
+    info!(user_id = user, "Getting user data");
+
+    self.save_unsaved().await;
+    if !self.unsaved.is_empty() {
+      warn!(user_id = user, "Storage is down, using cached user data");
+      return Ok(());
+    }
+    self.sync_all_users().await;
+
+    if let Some(user_data) = self.get_db_user_data(user).await {
+      self.cache_user_data(user, user_data);
+    } else {
+      warn!("Could not find or parse user data");
+    }
+
+    Ok(())
+  }
+
+  /// Subscribes the user to `center` for `service` with notifications going
+  /// to `channel_id`, switching the service or chat of an existing
+  /// subscription.
+  pub async fn track_center(
+    &mut self,
+    channel_id: i64,
+    user: UserId,
+    center: CenterId,
+    service: Service,
+  ) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
+
+    let mut user_data = match self.user_data.get(&user).cloned() {
+      Some(current_list) => {
+        if current_list.subscriptions.contains(&center)
+          && current_list.service_for(center) == service
+          && current_list.chat_for(center) == channel_id
+        {
+          return Err(TrackingError::AlreadyTracking);
+        }
+        current_list
+      },
+      None => UserData::from((Vec::new(), channel_id)),
+    };
+    if !user_data.subscriptions.contains(&center) {
+      if user_data.subscriptions.len() >= CONFIG.max_subscriptions {
+        return Err(TrackingError::TooManySubscriptions(CONFIG.max_subscriptions));
+      }
+      user_data.subscriptions.push(center);
+    }
+    match service {
+      Service::Nexus => user_data.services.remove(&center),
+      service => user_data.services.insert(center, service),
+    };
+    user_data.chats.insert(center, channel_id);
+    self.cache_user_data(user, user_data.clone());
+    self.set_db_user_data(user, user_data).await
+  }
+
+  pub async fn untrack_center(&mut self, user: UserId, center: CenterId) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
 
     let current_list = self.user_data.get_mut(&user).cloned();
     if let Some(mut current_list) = current_list {
       if let Some(index) = current_list.subscriptions.iter().position(|&x| x == center) {
         current_list.subscriptions.remove(index);
-        self.user_data.insert(user, current_list.clone());
+        current_list.services.remove(&center);
+        current_list.chats.remove(&center);
+        current_list.snoozed.remove(&center);
+        current_list.best_seen.remove(&center);
+        current_list.last_notified.remove(&center);
+        self.cache_user_data(user, current_list.clone());
         self.set_db_user_data(user, current_list).await
       } else {
-        Err("You are not tracking this center!".to_string())
+        Err(TrackingError::NotTracking)
       }
     } else {
-      Err("You are not tracking any centers!".to_string())
+      Err(TrackingError::NoSubscriptions)
     }
   }
 
-  pub async fn get_user_data(&mut self, user: UserId) -> Result<Option<&UserData>, String> {
-    if let Err(err) = self.sync_with_db(user).await {
-      return Err(err);
+  pub async fn track_region(&mut self, channel_id: i64, user: UserId, region: &str) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
+
+    if self.user_data.get(&user).is_some_and(|x| x.is_tracking_region(region)) {
+      return Err(TrackingError::AlreadyTrackingRegion);
+    }
+    self
+      .update_user_data(channel_id, user, |x| x.regions.push(region.to_string()))
+      .await
+  }
+
+  pub async fn untrack_region(&mut self, user: UserId, region: &str) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
+
+    match self.user_data.get(&user) {
+      Some(user_data) if user_data.is_tracking_region(region) => {
+        let mut user_data = user_data.clone();
+        user_data
+          .regions
+          .retain(|x| normalize_name(x) != normalize_name(region));
+        self.cache_user_data(user, user_data.clone());
+        self.set_db_user_data(user, user_data).await
+      },
+      _ => Err(TrackingError::NotTrackingRegion),
+    }
+  }
+
+  /// Drops every center and region the user tracks in one write, returning
+  /// how many there were.
+  pub async fn untrack_all(&mut self, user: UserId) -> Result<usize, TrackingError> {
+    self.sync_with_db(user).await?;
+
+    match self.user_data.get(&user) {
+      Some(user_data) if !user_data.subscriptions.is_empty() || !user_data.regions.is_empty() => {
+        let mut user_data = user_data.clone();
+        let removed = user_data.subscriptions.len() + user_data.regions.len();
+        user_data.subscriptions.clear();
+        user_data.regions.clear();
+        user_data.services.clear();
+        user_data.chats.clear();
+        user_data.snoozed.clear();
+        user_data.best_seen.clear();
+        user_data.last_notified.clear();
+        self.cache_user_data(user, user_data.clone());
+        self.set_db_user_data(user, user_data).await?;
+        Ok(removed)
+      },
+      _ => Err(TrackingError::NoSubscriptions),
+    }
+  }
+
+  /// Holds back alerts for a tracked `center` until `until`.
+  pub async fn snooze_center(
+    &mut self,
+    user: UserId,
+    center: CenterId,
+    until: NaiveDateTime,
+  ) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
+
+    match self.user_data.get(&user) {
+      Some(user_data) if user_data.tracked_centers().contains(&center) => {
+        let mut user_data = user_data.clone();
+        user_data.snoozed.insert(center, until);
+        self.cache_user_data(user, user_data.clone());
+        self.set_db_user_data(user, user_data).await
+      },
+      _ => Err(TrackingError::NotTracking),
     }
+  }
+
+  pub async fn unsnooze_center(&mut self, user: UserId, center: CenterId) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
+
+    match self.user_data.get(&user) {
+      Some(user_data) if user_data.snoozed.contains_key(&center) => {
+        let mut user_data = user_data.clone();
+        user_data.snoozed.remove(&center);
+        self.cache_user_data(user, user_data.clone());
+        self.set_db_user_data(user, user_data).await
+      },
+      _ => Err(TrackingError::NotSnoozed),
+    }
+  }
+
+  pub async fn update_user_data<F>(&mut self, channel_id: i64, user: UserId, update: F) -> Result<(), TrackingError>
+  where
+    F: FnOnce(&mut UserData),
+  {
+    self.sync_with_db(user).await?;
+
+    let mut user_data = self
+      .user_data
+      .get(&user)
+      .cloned()
+      .unwrap_or_else(|| UserData::from((Vec::new(), channel_id)));
+    update(&mut user_data);
+    self.cache_user_data(user, user_data.clone());
+    self.set_db_user_data(user, user_data).await
+  }
+
+  pub async fn get_user_data(&mut self, user: UserId) -> Result<Option<&UserData>, TrackingError> {
+    self.sync_with_db(user).await?;
 
     Ok(self.user_data.get(&user))
   }
 
-  pub fn get_center_subscribers(&mut self) -> HashMap<CenterId, Vec<UserId>> {
-    let mut result: HashMap<u32, Vec<u64>> = HashMap::new();
+  /// Users whose active period ended before `today` and who have not yet been
+  /// told their notifications are paused.
+  pub fn get_newly_inactive_users(&self, today: NaiveDate) -> Vec<(UserId, UserData)> {
+    self
+      .user_data
+      .iter()
+      .filter(|(_, user_data)| !user_data.is_active(today) && !user_data.active_until_prompted)
+      .map(|(user, user_data)| (*user, user_data.clone()))
+      .collect()
+  }
 
-    for user in self.all_users.list.iter() {
-      if let Some(user_data) = self.user_data.get(user) {
-        for center in user_data.subscriptions.iter() {
-          if let Some(list) = result.get_mut(center) {
-            list.push(*user);
-          } else {
-            result.insert(*center, vec![*user]);
+  /// Active, unmuted digest users who haven't had their daily digest by
+  /// `today`.
+  pub fn get_daily_digest_users(&self, today: NaiveDate) -> Vec<(UserId, UserData)> {
+    self
+      .user_data
+      .iter()
+      .filter(|(_, x)| x.notification_mode == NotificationMode::Digest && x.is_active(today) && !x.muted)
+      .filter(|(_, x)| x.digest_sent.is_none_or(|sent| sent < today))
+      .map(|(user, user_data)| (*user, user_data.clone()))
+      .collect()
+  }
+
+  /// Removes a user and their settings entirely.
+  pub async fn delete_user(&mut self, user: UserId) -> Result<(), TrackingError> {
+    self.sync_with_db(user).await?;
+    if self.user_data.remove(&user).is_none() {
+      return Err(TrackingError::UserNotFound);
+    }
+    self.changes += 1;
+    self.subscribers.update(user, None, &regions(), &centers());
+
+    self.storage.remove_member(USERS_KEY, &user.to_string()).await?;
+    self.all_users.list.retain(|x| *x != user);
+    Ok(self.storage.delete(&user.to_string()).await?)
+  }
+
+  /// Drops the subscriptions `user` gets alerts for in `chat`, including their
+  /// regions when it is their default chat, and removes the user entirely
+  /// once nothing is left. Returns how many subscriptions were dropped.
+  pub async fn remove_chat(&mut self, user: UserId, chat: i64) -> Result<usize, TrackingError> {
+    self.sync_with_db(user).await?;
+
+    let mut user_data = match self.user_data.get(&user) {
+      Some(user_data) => user_data.clone(),
+      None => return Err(TrackingError::UserNotFound),
+    };
+    let dropped = user_data
+      .subscriptions
+      .iter()
+      .copied()
+      .filter(|x| user_data.chat_for(*x) == chat)
+      .collect::<Vec<_>>();
+    user_data.subscriptions.retain(|x| !dropped.contains(x));
+    for center in &dropped {
+      user_data.services.remove(center);
+      user_data.chats.remove(center);
+      user_data.snoozed.remove(center);
+      user_data.last_notified.remove(center);
+    }
+    let regions = if user_data.chat_id == chat {
+      std::mem::take(&mut user_data.regions).len()
+    } else {
+      0
+    };
+
+    if user_data.subscriptions.is_empty() && user_data.regions.is_empty() {
+      self.delete_user(user).await?;
+    } else {
+      self.cache_user_data(user, user_data.clone());
+      self.set_db_user_data(user, user_data).await?;
+    }
+    Ok(dropped.len() + regions)
+  }
+
+  /// Appends a change made on behalf of `user` to the audit log, keeping the
+  /// latest [`AUDIT_LOG_LEN`] entries.
+  pub async fn record_audit(&mut self, source: &str, user: UserId, action: &str) -> Result<(), TrackingError> {
+    let entry = AuditEntry {
+      time: Utc::now().timestamp(),
+      source: source.to_string(),
+      user,
+      action: action.to_string(),
+    };
+    Ok(
+      self
+        .storage
+        .push_capped("audit_log", serde_json::to_string(&entry)?, AUDIT_LOG_LEN)
+        .await?,
+    )
+  }
+
+  /// The offset long polling continues from, one past the last update received.
+  pub async fn get_update_offset(&mut self) -> Option<i32> {
+    self
+      .storage
+      .get("update_offset")
+      .await
+      .ok()
+      .flatten()
+      .and_then(|x| x.parse().ok())
+  }
+
+  /// Journals `updates` until they are handled and saves the polling offset.
+  pub async fn record_updates(&mut self, updates: &[Update], offset: i32) -> Result<(), TrackingError> {
+    let mut writes = Vec::new();
+    for update in updates {
+      writes.push(Write::HashSet(
+        "update_journal".to_string(),
+        update.id.to_string(),
+        serde_json::to_string(update)?,
+      ));
+    }
+    writes.push(Write::Set("update_offset".to_string(), offset.to_string()));
+    Ok(self.storage.write(writes).await?)
+  }
+
+  pub async fn complete_update(&mut self, update: i32) -> Result<(), TrackingError> {
+    Ok(self.storage.hash_delete("update_journal", &update.to_string()).await?)
+  }
+
+  /// Updates received but never handled, oldest first. Entries that can't be
+  /// parsed are dropped from the journal so they aren't retried forever.
+  pub async fn journaled_updates(&mut self) -> Vec<Update> {
+    let journal = self.storage.hash_entries("update_journal").await.unwrap_or_default();
+    let mut updates = Vec::new();
+    for (id, update) in journal {
+      match serde_json::from_str::<Update>(&update) {
+        Ok(update) => updates.push(update),
+        Err(err) => {
+          warn!("Dropping journaled update {} that could not be parsed: {}", id, err);
+          if let Err(err) = self.storage.hash_delete("update_journal", &id).await {
+            warn!("Failed to remove update {} from the journal: {}", id, err);
           }
-        }
+        },
       }
     }
+    updates.sort_by_key(|x| x.id);
+    updates
+  }
+
+  /// Checks that storage answers on the manager's connection.
+  pub async fn ping(&mut self) -> Result<(), TrackingError> {
+    Ok(self.storage.ping().await?)
+  }
+
+  /// Records the end of a poll round for `nexus-pls healthcheck`.
+  pub async fn heartbeat(&mut self) -> Result<(), TrackingError> {
+    Ok(
+      self
+        .storage
+        .set("heartbeat", Utc::now().timestamp().to_string())
+        .await?,
+    )
+  }
+
+  pub async fn stats(&mut self) -> Stats {
+    let today = Local::now().naive_local().date();
+    let active_users = self.user_data.values().filter(|x| x.is_active(today)).count();
+    let mut centers = self
+      .get_center_subscribers()
+      .iter()
+      .map(|(center, users)| (*center, users.len()))
+      .collect::<Vec<_>>();
+    centers.sort_by_key(|(center, users)| (std::cmp::Reverse(*users), *center));
+    let polled_centers = centers
+      .iter()
+      .filter(|(center, _)| !self.closed_centers.contains(center))
+      .count();
+    let storage_keys = self.storage.key_count().await.ok();
+
+    Stats {
+      users: self.all_users.list.len(),
+      active_users,
+      centers: centers
+        .into_iter()
+        .map(|(center, users)| (center, users, self.availability.get(&center).map(|x| x.soonest.clone())))
+        .collect(),
+      polled_centers,
+      storage_keys,
+    }
+  }
+
+  pub fn user_count(&self) -> usize {
+    self.all_users.list.len()
+  }
+
+  /// Active subscribers per center. The index follows every change to the
+  /// cached user data and is rebuilt when the day rolls over or the known
+  /// centers or regions change.
+  pub fn get_center_subscribers(&mut self) -> &HashMap<CenterId, Vec<UserId>> {
+    if self.subscribers.date != Some(Local::now().naive_local().date()) {
+      self.rebuild_subscriber_index();
+    }
+    &self.subscribers.centers
+  }
+
+  /// Indexes every user again against the current centers and regions, to be
+  /// called whenever those change.
+  pub fn rebuild_subscriber_index(&mut self) {
+    let today = Local::now().naive_local().date();
+    self.subscribers = SubscriberIndex::build(&self.all_users.list, &self.user_data, today, &regions(), &centers());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::center::testing::center;
+  use crate::storage::testing::TestStorage;
+  use crate::storage::{MemoryStorage, Storage};
+
+  async fn manager() -> TrackingManager {
+    TrackingManager::new(Box::new(MemoryStorage::default())).await
+  }
+
+  #[test]
+  fn users_are_active_through_their_last_day() {
+    let user_data = UserData {
+      active_until: Some(today()),
+      ..Default::default()
+    };
+    assert!(user_data.is_active(today()));
+    assert!(!user_data.is_active(today().succ_opt().unwrap()));
+    assert!(UserData::default().is_active(today()));
+  }
+
+  #[tokio::test]
+  async fn expired_users_are_prompted_once() {
+    let mut manager = manager().await;
+    let yesterday = today().pred_opt().unwrap();
+    manager
+      .update_user_data(7, 7, |x| {
+        x.subscriptions.push(5161);
+        x.active_until = Some(yesterday);
+      })
+      .await
+      .unwrap();
+    manager.track_center(8, 8, 5161, Service::Nexus).await.unwrap();
+
+    let inactive = manager.get_newly_inactive_users(today());
+    assert_eq!(inactive.iter().map(|(user, _)| *user).collect::<Vec<_>>(), [7]);
+    assert_eq!(manager.get_center_subscribers().get(&5161), Some(&vec![8]));
+
+    manager
+      .update_user_data(7, 7, |x| x.active_until_prompted = true)
+      .await
+      .unwrap();
+    assert!(manager.get_newly_inactive_users(today()).is_empty());
+  }
+
+  #[tokio::test]
+  async fn closed_centers_report_each_change_once() {
+    let mut manager = manager().await;
+    let (closed, reopened) = manager.set_closed_centers(HashSet::from([1, 2])).await;
+    assert_eq!((sorted(closed), sorted(reopened)), (vec![1, 2], vec![]));
+
+    let (closed, reopened) = manager.set_closed_centers(HashSet::from([2, 3])).await;
+    assert_eq!((closed, reopened), (vec![3], vec![1]));
+
+    let (closed, reopened) = manager.set_closed_centers(HashSet::from([2, 3])).await;
+    assert!(closed.is_empty() && reopened.is_empty());
+
+    let stored = manager.storage.get("closed_centers").await.unwrap().unwrap();
+    let stored = toml::from_str::<ClosedCenters>(&stored).unwrap().list;
+    assert_eq!(sorted(stored), [2, 3]);
+  }
+
+  #[tokio::test]
+  async fn tracked_centers_are_saved_to_storage() {
+    let storage = TestStorage::default();
+    let mut manager = TrackingManager::new(Box::new(storage.clone())).await;
+    assert!(matches!(
+      manager.untrack_center(7, 5161).await,
+      Err(TrackingError::NoSubscriptions)
+    ));
+    manager.track_center(7, 7, 5161, Service::Nexus).await.unwrap();
+    manager.track_center(7, 7, 5022, Service::Nexus).await.unwrap();
+    assert!(matches!(
+      manager.track_center(7, 7, 5161, Service::Nexus).await,
+      Err(TrackingError::AlreadyTracking)
+    ));
+
+    manager.untrack_center(7, 5161).await.unwrap();
+    assert!(matches!(
+      manager.untrack_center(7, 5161).await,
+      Err(TrackingError::NotTracking)
+    ));
+
+    let mut restarted = TrackingManager::new(Box::new(storage)).await;
+    assert_eq!(restarted.user_count(), 1);
+    let user_data = restarted.get_user_data(7).await.unwrap().unwrap();
+    assert_eq!(user_data.subscriptions, [5022]);
+    assert_eq!(restarted.get_center_subscribers().get(&5022), Some(&vec![7]));
+    assert_eq!(restarted.get_center_subscribers().get(&5161), None);
+  }
+
+  #[tokio::test]
+  async fn regions_are_tracked_as_one_subscription() {
+    let mut manager = manager().await;
+    manager.track_region(7, 7, "PNW").await.unwrap();
+    assert!(matches!(
+      manager.track_region(7, 7, " pnw").await,
+      Err(TrackingError::AlreadyTrackingRegion)
+    ));
+
+    manager.untrack_region(7, "Pnw").await.unwrap();
+    assert!(manager.get_user_data(7).await.unwrap().unwrap().regions.is_empty());
+    assert!(matches!(
+      manager.untrack_region(7, "pnw").await,
+      Err(TrackingError::NotTrackingRegion)
+    ));
+  }
+
+  #[tokio::test]
+  async fn stats_count_active_subscribers_per_center() {
+    let mut manager = manager().await;
+    manager.track_center(7, 7, 5022, Service::Nexus).await.unwrap();
+    manager.track_center(7, 7, 5161, Service::Nexus).await.unwrap();
+    manager.track_center(8, 8, 5161, Service::Nexus).await.unwrap();
+    manager
+      .update_user_data(9, 9, |x| {
+        x.subscriptions.push(5020);
+        x.active_until = Some(today().pred_opt().unwrap());
+      })
+      .await
+      .unwrap();
+    manager.set_closed_centers(HashSet::from([5022])).await;
+    let soonest = slot_at(5161, today().succ_opt().unwrap().and_hms_opt(9, 30, 0).unwrap());
+    manager.record_availability(
+      5161,
+      Availability {
+        observed_at: Local::now().naive_local(),
+        soonest: soonest.clone(),
+      },
+    );
+
+    let stats = manager.stats().await;
+    assert_eq!((stats.users, stats.active_users), (3, 2));
+    assert_eq!(
+      stats
+        .centers
+        .iter()
+        .map(|(center, users, soonest)| (*center, *users, soonest.as_ref().map(|x| x.start_timestamp.clone())))
+        .collect::<Vec<_>>(),
+      [(5161, 2, Some(soonest.start_timestamp)), (5022, 1, None)]
+    );
+    assert_eq!(stats.polled_centers, 1);
+    assert_eq!(stats.storage_keys, manager.storage.key_count().await.ok());
+  }
+
+  #[tokio::test]
+  async fn subscriptions_remember_their_service() {
+    let mut manager = manager().await;
+    manager.track_center(7, 7, 5020, Service::GlobalEntry).await.unwrap();
+    manager.track_center(7, 7, 5161, Service::Nexus).await.unwrap();
+
+    let user_data = manager.get_user_data(7).await.unwrap().unwrap();
+    assert_eq!(user_data.service_for(5020), Service::GlobalEntry);
+    assert_eq!(user_data.service_for(5161), Service::Nexus);
+    assert_eq!(user_data.service_for(5021), Service::Nexus);
+  }
+
+  #[test]
+  fn user_data_round_trips_through_json() {
+    let user_data = UserData {
+      subscriptions: vec![5020, 5161, 5022],
+      chat_id: -100,
+      regions: vec!["PNW".to_string()],
+      services: BTreeMap::from([(5020, Service::GlobalEntry)]),
+      chats: BTreeMap::from([(5161, 7)]),
+      quiet_hours: Some((
+        NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+        NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+      )),
+      ..Default::default()
+    };
+    let stored = serde_json::to_string(&user_data).unwrap();
+    assert_eq!(parse_stored::<UserData>(&stored), Some((user_data, false)));
+  }
+
+  #[test]
+  fn toml_user_data_is_read_as_legacy() {
+    let (user_data, legacy) = parse_stored::<UserData>("subscriptions = [5020, 5161]\nchat_id = 7\n").unwrap();
+    assert!(legacy);
+    assert_eq!(user_data, UserData::from((vec![5020, 5161], 7)));
+    assert!(parse_stored::<UserData>("subscriptions = ").is_none());
+  }
+
+  #[tokio::test]
+  async fn toml_users_are_rewritten_as_json_on_start() {
+    let mut storage = MemoryStorage::default();
+    storage.set("all_users", "list = [7]\n".to_string()).await.unwrap();
+    storage
+      .set("7", "subscriptions = [5020, 5161]\nchat_id = 7\n".to_string())
+      .await
+      .unwrap();
+
+    let mut manager = TrackingManager::new(Box::new(storage)).await;
+    assert_eq!(manager.user_count(), 1);
+    assert!(manager.storage.get("all_users").await.unwrap().is_none());
+    assert_eq!(manager.storage.members(USERS_KEY).await.unwrap(), ["7"]);
+    let stored = manager.storage.get("7").await.unwrap().unwrap();
+    let user_data = serde_json::from_str::<UserData>(&stored).unwrap();
+    assert_eq!(user_data.subscriptions, [5020, 5161]);
+  }
+
+  #[tokio::test]
+  async fn only_toml_users_are_rewritten_when_read() {
+    let storage = TestStorage::default();
+    let mut manager = TrackingManager::new(Box::new(storage.clone())).await;
+    let mut stored = storage.clone();
+    let json = "{ \"subscriptions\": [5161], \"chat_id\": 8, \"chats\": { \"5161\": 8 } }";
+    stored
+      .set("7", "subscriptions = [5020]\nchat_id = 7\n".to_string())
+      .await
+      .unwrap();
+    stored.set("8", json.to_string()).await.unwrap();
+    stored
+      .add_members(USERS_KEY, vec!["7".to_string(), "8".to_string()])
+      .await
+      .unwrap();
+
+    let user_data = manager.get_user_data(7).await.unwrap().unwrap();
+    assert_eq!(user_data.subscriptions, [5020]);
+    let rewritten = stored.get("7").await.unwrap().unwrap();
+    assert_eq!(
+      serde_json::from_str::<UserData>(&rewritten).unwrap().subscriptions,
+      [5020]
+    );
+
+    let user_data = manager.get_user_data(8).await.unwrap().unwrap();
+    assert_eq!(user_data.subscriptions, [5161]);
+    assert_eq!(stored.get("8").await.unwrap().unwrap(), json);
+  }
+
+  #[tokio::test]
+  async fn subscriptions_are_capped_before_saving() {
+    let mut manager = manager().await;
+    let cap = CONFIG.max_subscriptions as CenterId;
+    for center in 0..cap {
+      manager.track_center(7, 7, center, Service::Nexus).await.unwrap();
+    }
+
+    assert!(matches!(
+      manager.track_center(7, 7, cap, Service::Nexus).await,
+      Err(TrackingError::TooManySubscriptions(limit)) if limit == CONFIG.max_subscriptions
+    ));
+    let stored = manager.storage.get("7").await.unwrap().unwrap();
+    assert!(!serde_json::from_str::<UserData>(&stored)
+      .unwrap()
+      .subscriptions
+      .contains(&cap));
+
+    manager.track_center(7, 7, 0, Service::GlobalEntry).await.unwrap();
+    manager.untrack_center(7, 0).await.unwrap();
+    manager.track_center(7, 7, cap, Service::Nexus).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn snoozes_last_until_they_expire_or_are_lifted() {
+    let mut manager = manager().await;
+    let now = Local::now().naive_local();
+    let until = now + chrono::Duration::hours(6);
+    assert!(matches!(
+      manager.snooze_center(7, 5161, until).await,
+      Err(TrackingError::NotTracking)
+    ));
+
+    manager.track_center(7, 7, 5161, Service::Nexus).await.unwrap();
+    manager.snooze_center(7, 5161, until).await.unwrap();
+    let user_data = manager.get_user_data(7).await.unwrap().unwrap();
+    assert!(user_data.is_snoozed(5161, now));
+    assert!(!user_data.is_snoozed(5161, until));
+    assert!(!user_data.is_snoozed(5020, now));
+
+    manager.unsnooze_center(7, 5161).await.unwrap();
+    assert!(matches!(
+      manager.unsnooze_center(7, 5161).await,
+      Err(TrackingError::NotSnoozed)
+    ));
+  }
+
+  #[tokio::test]
+  async fn expired_snoozes_are_dropped_when_loaded() {
+    let now = Local::now().naive_local();
+    let user_data = UserData {
+      snoozed: BTreeMap::from([
+        (5020, now - chrono::Duration::minutes(1)),
+        (5161, now + chrono::Duration::days(1)),
+      ]),
+      ..UserData::from((vec![5020, 5161], 7))
+    };
+    let mut storage = MemoryStorage::default();
+    storage.add_members(USERS_KEY, vec!["7".to_string()]).await.unwrap();
+    storage
+      .set("7", serde_json::to_string(&user_data).unwrap())
+      .await
+      .unwrap();
+
+    let mut manager = TrackingManager::new(Box::new(storage)).await;
+    let snoozed = &manager.get_user_data(7).await.unwrap().unwrap().snoozed;
+    assert_eq!(snoozed.keys().collect::<Vec<_>>(), [&5161]);
+    let stored = manager.storage.get("7").await.unwrap().unwrap();
+    assert_eq!(serde_json::from_str::<UserData>(&stored).unwrap().snoozed.len(), 1);
+  }
+
+  #[test]
+  fn only_slots_before_the_best_seen_beat_it() {
+    let best = today().and_hms_opt(9, 0, 0).unwrap();
+    let mut user_data = UserData {
+      best_seen: BTreeMap::from([(5020, best)]),
+      ..Default::default()
+    };
+    let hour = chrono::Duration::hours(1);
+    assert!(user_data.beats_best_seen(5020, best + hour));
+
+    user_data.earlier_only = true;
+    assert!(user_data.beats_best_seen(5020, best - hour));
+    assert!(!user_data.beats_best_seen(5020, best));
+    assert!(!user_data.beats_best_seen(5020, best + hour));
+    assert!(user_data.beats_best_seen(5161, best + hour));
+  }
+
+  #[tokio::test]
+  async fn best_seen_only_moves_earlier() {
+    let mut manager = manager().await;
+    let best = today().and_hms_opt(9, 0, 0).unwrap();
+    let hour = chrono::Duration::hours(1);
+    manager.track_center(7, 7, 5020, Service::Nexus).await.unwrap();
+    manager.track_center(8, 8, 5020, Service::Nexus).await.unwrap();
+    manager.update_user_data(7, 7, |x| x.earlier_only = true).await.unwrap();
+
+    assert_eq!(manager.record_best_seen(vec![(7, 5020, best), (8, 5020, best)]), [7]);
+    assert!(manager.record_best_seen(vec![(7, 5020, best + hour)]).is_empty());
+    assert_eq!(manager.cached_user_data(7).unwrap().best_seen.get(&5020), Some(&best));
+    assert!(manager.cached_user_data(8).unwrap().best_seen.is_empty());
+
+    let changed = manager.record_best_seen(vec![(7, 5020, best - hour)]);
+    let writes = manager.user_writes(&changed).unwrap();
+    manager.storage.write(writes).await.unwrap();
+    let stored = manager.storage.get("7").await.unwrap().unwrap();
+    let stored = serde_json::from_str::<UserData>(&stored).unwrap();
+    assert_eq!(stored.best_seen.get(&5020), Some(&(best - hour)));
+  }
+
+  fn sorted(mut centers: Vec<CenterId>) -> Vec<CenterId> {
+    centers.sort_unstable();
+    centers
+  }
+
+  fn region(name: &str, members: &[&str]) -> Region {
+    Region {
+      name: name.to_string(),
+      centers: members.iter().map(|x| x.to_string()).collect(),
+    }
+  }
+
+  fn today() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2024, 5, 1).unwrap()
+  }
+
+  fn subscribers(index: &SubscriberIndex, center: CenterId) -> Vec<UserId> {
+    let mut users = index.centers.get(&center).cloned().unwrap_or_default();
+    users.sort_unstable();
+    users
+  }
+
+  #[test]
+  fn region_subscribers_follow_region_changes() {
+    let centers = [
+      center(1, "blaine", "Blaine"),
+      center(2, "seattle", "Seattle"),
+      center(3, "sweetgrass", "Sweetgrass"),
+    ];
+    let user_data = HashMap::from([(
+      7,
+      UserData {
+        regions: vec!["pnw".to_string()],
+        ..Default::default()
+      },
+    )]);
+    let mut index = SubscriberIndex::build(&[7], &user_data, today(), &[region("PNW", &["blaine"])], &centers);
+    assert_eq!(subscribers(&index, 1), [7]);
+
+    let regions = [region("PNW", &["seattle", "sweetgrass"])];
+    index.update(7, user_data.get(&7), &regions, &centers);
+    assert_eq!(subscribers(&index, 1), Vec::<UserId>::new());
+    assert_eq!(subscribers(&index, 2), [7]);
+    assert_eq!(subscribers(&index, 3), [7]);
+  }
+
+  #[test]
+  fn direct_and_region_subscriptions_are_indexed_once() {
+    let centers = [center(1, "blaine", "Blaine"), center(2, "seattle", "Seattle")];
+    let user_data = HashMap::from([
+      (
+        7,
+        UserData {
+          subscriptions: vec![1],
+          regions: vec!["PNW".to_string()],
+          ..Default::default()
+        },
+      ),
+      (8, UserData::from((vec![2], 8))),
+    ]);
+    let index = SubscriberIndex::build(
+      &[7, 8],
+      &user_data,
+      today(),
+      &[region("PNW", &["blaine", "seattle"])],
+      &centers,
+    );
+
+    assert_eq!(subscribers(&index, 1), [7]);
+    assert_eq!(subscribers(&index, 2), [7, 8]);
+  }
+
+  #[test]
+  fn inactive_and_removed_users_are_not_indexed() {
+    let centers = [center(1, "blaine", "Blaine")];
+    let user_data = HashMap::from([
+      (
+        7,
+        UserData {
+          subscriptions: vec![1],
+          active_until: Some(today().pred_opt().unwrap()),
+          ..Default::default()
+        },
+      ),
+      (8, UserData::from((vec![1], 8))),
+    ]);
+    let mut index = SubscriberIndex::build(&[7, 8], &user_data, today(), &[], &centers);
+    assert_eq!(subscribers(&index, 1), [8]);
+
+    index.update(8, None, &[], &centers);
+    assert!(index.centers.is_empty());
+    assert!(index.tracked.is_empty());
+  }
+
+  #[test]
+  fn unbuilt_index_ignores_updates() {
+    let mut index = SubscriberIndex::default();
+    index.update(
+      8,
+      Some(&UserData::from((vec![1], 8))),
+      &[],
+      &[center(1, "blaine", "Blaine")],
+    );
+    assert!(index.centers.is_empty());
+  }
+
+  fn sorted_index(centers: &HashMap<CenterId, Vec<UserId>>) -> BTreeMap<CenterId, Vec<UserId>> {
+    centers
+      .iter()
+      .map(|(center, users)| {
+        let mut users = users.clone();
+        users.sort_unstable();
+        (*center, users)
+      })
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn updated_index_matches_a_rebuilt_one() {
+    let mut manager = manager().await;
+    manager.get_center_subscribers();
+    manager.track_center(1, 1, 5161, Service::Nexus).await.unwrap();
+    manager.track_center(2, 2, 5161, Service::Nexus).await.unwrap();
+    manager.track_center(2, 2, 5022, Service::GlobalEntry).await.unwrap();
+    manager.track_region(3, 3, "niagara frontier").await.unwrap();
+    manager.track_center(3, 3, 5027, Service::Nexus).await.unwrap();
+    manager.untrack_center(1, 5161).await.unwrap();
+    manager.track_center(1, 1, 5025, Service::Nexus).await.unwrap();
+    manager.untrack_center(2, 5161).await.unwrap();
+    manager.untrack_region(3, "niagara frontier").await.unwrap();
+    manager.track_center(4, 4, 5022, Service::Nexus).await.unwrap();
+    manager.untrack_center(4, 5022).await.unwrap();
+
+    let today = Local::now().naive_local().date();
+    let rebuilt = SubscriberIndex::build(
+      &manager.all_users.list,
+      &manager.user_data,
+      today,
+      &regions(),
+      &centers(),
+    );
+    let updated = sorted_index(manager.get_center_subscribers());
+    assert_eq!(updated, sorted_index(&rebuilt.centers));
+    assert_eq!(
+      updated,
+      BTreeMap::from([(5022, vec![2]), (5025, vec![1]), (5027, vec![3])])
+    );
+  }
+
+  fn slot_at(center: CenterId, start: NaiveDateTime) -> Slot {
+    serde_json::from_value(serde_json::json!({
+      "locationId": center,
+      "startTimestamp": start.format("%Y-%m-%dT%H:%M").to_string(),
+    }))
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn slots_are_alerted_again_once_they_reopen() {
+    let mut storage = TestStorage::default();
+    let mut manager = TrackingManager::new(Box::new(storage.clone())).await;
+    let now = Local::now().naive_local();
+    let slot = slot_at(5161, now + chrono::Duration::days(1));
+    let other = slot_at(5161, now + chrono::Duration::days(2));
+
+    // Seen in the first poll.
+    assert!(!manager.was_notified(7, &slot));
+    let writes = manager
+      .mark_notified(vec![(7, slot.clone()), (8, slot.clone())])
+      .unwrap();
+    assert_eq!(writes.len(), 2);
+    storage.write(writes).await.unwrap();
+    assert!(manager.was_notified(7, &slot));
+    assert!(!manager.was_notified(7, &other));
+
+    // Still listed in the next one.
+    manager
+      .forget_unavailable_slots(5161, &[slot.clone(), other.clone()], now)
+      .await
+      .unwrap();
+    assert!(manager.was_notified(7, &slot));
+    assert!(manager.mark_notified(vec![(7, slot.clone())]).unwrap().is_empty());
+
+    // Gone, then back.
+    manager
+      .forget_unavailable_slots(5161, std::slice::from_ref(&other), now)
+      .await
+      .unwrap();
+    assert!(!manager.was_notified(7, &slot));
+    assert!(!manager.was_notified(8, &slot));
+    assert!(storage.hash_entries(NOTIFIED_KEY).await.unwrap().is_empty());
+    manager
+      .forget_unavailable_slots(5161, &[slot.clone(), other], now)
+      .await
+      .unwrap();
+    assert!(!manager.was_notified(7, &slot));
+    let writes = manager.mark_notified(vec![(7, slot.clone())]).unwrap();
+    assert_eq!(writes.len(), 1);
+    storage.write(writes).await.unwrap();
+
+    // Slots that have started are dropped whichever center is polled.
+    manager
+      .forget_unavailable_slots(5022, &[], now + chrono::Duration::days(3))
+      .await
+      .unwrap();
+    assert!(!manager.was_notified(7, &slot));
+    assert!(storage.hash_entries(NOTIFIED_KEY).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn notified_slots_are_stored_per_user_and_center() {
+    let now = Local::now().naive_local();
+    let slot = slot_at(5161, now + chrono::Duration::days(1));
+    let later = slot_at(5022, now + chrono::Duration::days(2));
+    let mut storage = MemoryStorage::default();
+    let legacy = format!(
+      "[[list]]\nuser = 7\ncenter = 5161\nstart = \"{}\"\n",
+      slot.start_timestamp
+    );
+    storage.set("notified_slots", legacy).await.unwrap();
+
+    let mut manager = TrackingManager::new(Box::new(storage)).await;
+    assert!(manager.was_notified(7, &slot));
+    let writes = manager.mark_notified(vec![(7, later.clone())]).unwrap();
+    let mut storage = manager.storage();
+    storage.write(writes).await.unwrap();
+    assert_eq!(storage.get("notified_slots").await.unwrap(), None);
+    let mut fields = storage
+      .hash_entries(NOTIFIED_KEY)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|(field, _)| field)
+      .collect::<Vec<_>>();
+    fields.sort();
+    assert_eq!(fields, ["7:5022", "7:5161"]);
+
+    let manager = TrackingManager::new(Box::new(storage)).await;
+    assert!(manager.was_notified(7, &slot));
+    assert!(manager.was_notified(7, &later));
+    assert!(!manager.was_notified(8, &later));
+  }
+
+  #[tokio::test]
+  async fn users_changed_during_an_outage_are_saved_after_it() {
+    let mut storage = TestStorage::default();
+    let mut manager = TrackingManager::new(Box::new(storage.clone())).await;
+
+    storage.set_down(true);
+    manager.track_center(9501, 9501, 5161, Service::Nexus).await.unwrap();
+    assert_eq!(manager.cached_user_data(9501).unwrap().subscriptions, [5161]);
+    assert_eq!(manager.get_center_subscribers().get(&5161), Some(&vec![9501]));
+    assert_eq!(manager.save_unsaved().await, 1);
+
+    storage.set_down(false);
+    assert_eq!(storage.get("9501").await.unwrap(), None);
+    assert_eq!(manager.save_unsaved().await, 0);
+    let stored: UserData = serde_json::from_str(&storage.get("9501").await.unwrap().unwrap()).unwrap();
+    assert_eq!(stored.subscriptions, [5161]);
+    assert_eq!(storage.members(USERS_KEY).await.unwrap(), ["9501"]);
+  }
+
+  #[tokio::test]
+  async fn leaving_a_group_keeps_subscriptions_alerted_elsewhere() {
+    let mut manager = manager().await;
+    manager.track_center(-100, 7, 5161, Service::Nexus).await.unwrap();
+    manager.track_center(7, 7, 5022, Service::Nexus).await.unwrap();
+    manager
+      .update_user_data(-100, 7, |x| x.regions.push("ontario".to_string()))
+      .await
+      .unwrap();
+
+    assert_eq!(manager.remove_chat(7, -100).await.unwrap(), 2);
+    let user_data = manager.cached_user_data(7).unwrap();
+    assert_eq!(user_data.subscriptions, [5022]);
+    assert!(user_data.regions.is_empty());
+
+    assert_eq!(manager.remove_chat(7, 7).await.unwrap(), 1);
+    assert!(manager.cached_user_data(7).is_none());
+  }
+
+  #[tokio::test]
+  async fn refreshes_overlapping_a_change_keep_the_cached_data() {
+    let mut manager = manager().await;
+    manager.track_center(7, 7, 5161, Service::Nexus).await.unwrap();
+    let refresh = manager.begin_refresh(&[7]);
+    let stored = manager.storage.get_many(&refresh.keys).await.unwrap();
+    manager.track_center(7, 7, 5022, Service::Nexus).await.unwrap();
 
-    result
+    assert!(manager.finish_refresh(refresh, stored).unwrap().is_empty());
+    assert_eq!(manager.cached_user_data(7).unwrap().subscriptions, [5161, 5022]);
   }
 }
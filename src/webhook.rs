@@ -0,0 +1,239 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use serde::Deserialize;
+use teloxide::dispatching::stop_token::AsyncStopToken;
+use teloxide::dispatching::update_listeners::{StatefulListener, UpdateListener};
+use teloxide::types::Update;
+use tracing::{info, warn};
+
+use crate::config::redact_path;
+
+/// Header Telegram sends the secret token registered with the webhook in.
+pub const SECRET_HEADER: &str = "x-telegram-bot-api-secret-token";
+
+pub type UpdateStream = UnboundedReceiver<Result<Update, Infallible>>;
+
+#[derive(Deserialize)]
+struct TelegramResponse {
+  ok: bool,
+  description: Option<String>,
+}
+
+/// Registers `url` as the webhook of the bot with `token`. The bot API only
+/// accepts `secret_token` through a direct request, so this doesn't go
+/// through teloxide.
+pub async fn register(
+  http_client: &Client<HttpsConnector<HttpConnector>>,
+  token: &str,
+  url: &str,
+  secret: Option<&str>,
+) -> Result<(), String> {
+  let mut body = serde_json::json!({ "url": url });
+  if let Some(secret) = secret {
+    body["secret_token"] = secret.into();
+  }
+
+  let request = Request::post(format!("https://api.telegram.org/bot{}/setWebhook", token))
+    .header("content-type", "application/json")
+    .body(Body::from(body.to_string()))
+    .map_err(|err| err.to_string())?;
+  let resp = http_client.request(request).await.map_err(|err| err.to_string())?;
+  let body = hyper::body::to_bytes(resp.into_body())
+    .await
+    .map_err(|err| err.to_string())?;
+  let resp: TelegramResponse = serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+
+  if resp.ok {
+    Ok(())
+  } else {
+    Err(resp.description.unwrap_or_else(|| "setWebhook failed".to_string()))
+  }
+}
+
+/// Serves webhook requests for `url` on `address`, handing their updates to the
+/// returned listener. The server shuts down when the listener is stopped.
+pub fn listen(
+  url: &str,
+  address: SocketAddr,
+  secret: Option<String>,
+) -> Result<impl UpdateListener<Infallible>, String> {
+  let path = url
+    .parse::<Uri>()
+    .map_err(|err| format!("Invalid webhook url {}: {}", redact_path(url), err))?
+    .path()
+    .to_string();
+  let (tx, rx) = mpsc::unbounded();
+  let (stop_token, stop_flag) = AsyncStopToken::new_pair();
+
+  let make_service = make_service_fn(move |_| {
+    let (tx, path, secret) = (tx.clone(), path.clone(), secret.clone());
+    async move {
+      Ok::<_, Infallible>(service_fn(move |request| {
+        handle(request, tx.clone(), path.clone(), secret.clone())
+      }))
+    }
+  });
+
+  let server = Server::try_bind(&address)
+    .map_err(|err| format!("Could not bind webhook listener to {}: {}", address, err))?
+    .serve(make_service)
+    .with_graceful_shutdown(stop_flag);
+  tokio::spawn(async move {
+    if let Err(err) = server.await {
+      warn!("Webhook listener failed: {}", err);
+    }
+  });
+  info!("Listening for webhook updates on {}", address);
+
+  Ok(StatefulListener::new(
+    (rx, stop_token),
+    stream_of as for<'a> fn(&'a mut (UpdateStream, AsyncStopToken)) -> &'a mut UpdateStream,
+    stop_token_of as for<'a> fn(&'a mut (UpdateStream, AsyncStopToken)) -> AsyncStopToken,
+  ))
+}
+
+pub fn stream_of(state: &mut (UpdateStream, AsyncStopToken)) -> &mut UpdateStream {
+  &mut state.0
+}
+
+pub fn stop_token_of(state: &mut (UpdateStream, AsyncStopToken)) -> AsyncStopToken {
+  state.1.clone()
+}
+
+async fn handle(
+  request: Request<Body>,
+  tx: UnboundedSender<Result<Update, Infallible>>,
+  path: String,
+  secret: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+  let status = if request.method() != Method::POST || request.uri().path() != path {
+    StatusCode::NOT_FOUND
+  } else if secret.is_some() && request.headers().get(SECRET_HEADER).and_then(|x| x.to_str().ok()) != secret.as_deref()
+  {
+    StatusCode::UNAUTHORIZED
+  } else {
+    match hyper::body::to_bytes(request.into_body()).await {
+      Ok(body) => match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => {
+          if tx.unbounded_send(Ok(update)).is_err() {
+            StatusCode::SERVICE_UNAVAILABLE
+          } else {
+            StatusCode::OK
+          }
+        },
+        Err(err) => {
+          // Telegram would keep redelivering an update that is rejected.
+          warn!("Could not parse webhook update: {}", err);
+          StatusCode::OK
+        },
+      },
+      Err(err) => {
+        warn!("Could not read webhook request: {}", err);
+        StatusCode::BAD_REQUEST
+      },
+    }
+  };
+
+  let mut response = Response::new(Body::empty());
+  *response.status_mut() = status;
+  Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::StreamExt;
+
+  use super::*;
+
+  const PATH: &str = "/webhook/abc";
+
+  fn update() -> String {
+    serde_json::json!({
+      "update_id": 42,
+      "message": {
+        "message_id": 1,
+        "date": 1_700_000_000,
+        "chat": { "id": 7, "type": "private", "first_name": "Test" },
+        "text": "/list",
+      },
+    })
+    .to_string()
+  }
+
+  async fn status(request: Request<Body>, tx: &UnboundedSender<Result<Update, Infallible>>) -> StatusCode {
+    handle(request, tx.clone(), PATH.to_string(), Some("s3cret".to_string()))
+      .await
+      .unwrap()
+      .status()
+  }
+
+  fn post(path: &str, secret: Option<&str>, body: String) -> Request<Body> {
+    let mut request = Request::post(path);
+    if let Some(secret) = secret {
+      request = request.header(SECRET_HEADER, secret);
+    }
+    request.body(Body::from(body)).unwrap()
+  }
+
+  #[tokio::test]
+  async fn only_posts_to_the_webhook_path_are_served() {
+    let (tx, mut rx) = mpsc::unbounded();
+    let get = Request::get(PATH)
+      .header(SECRET_HEADER, "s3cret")
+      .body(Body::empty())
+      .unwrap();
+    assert_eq!(status(get, &tx).await, StatusCode::NOT_FOUND);
+    let other = post("/webhook/other", Some("s3cret"), update());
+    assert_eq!(status(other, &tx).await, StatusCode::NOT_FOUND);
+    drop(tx);
+    assert!(rx.next().await.is_none());
+  }
+
+  #[tokio::test]
+  async fn requests_need_the_secret() {
+    let (tx, mut rx) = mpsc::unbounded();
+    assert_eq!(status(post(PATH, None, update()), &tx).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+      status(post(PATH, Some("guess"), update()), &tx).await,
+      StatusCode::UNAUTHORIZED
+    );
+    drop(tx);
+    assert!(rx.next().await.is_none());
+  }
+
+  #[tokio::test]
+  async fn valid_updates_reach_the_stream() {
+    let (tx, rx) = mpsc::unbounded();
+    assert_eq!(status(post(PATH, Some("s3cret"), update()), &tx).await, StatusCode::OK);
+    // Unparsable updates are acknowledged so Telegram doesn't redeliver them.
+    assert_eq!(
+      status(post(PATH, Some("s3cret"), "{}".to_string()), &tx).await,
+      StatusCode::OK
+    );
+    // Without a secret configured any request to the path is accepted.
+    let response = handle(post(PATH, None, update()), tx.clone(), PATH.to_string(), None)
+      .await
+      .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    drop(tx);
+
+    let ids = rx.map(|x| x.unwrap().id).collect::<Vec<_>>().await;
+    assert_eq!(ids, [42, 42]);
+  }
+
+  #[tokio::test]
+  async fn updates_are_refused_once_the_listener_is_gone() {
+    let (tx, rx) = mpsc::unbounded();
+    drop(rx);
+    assert_eq!(
+      status(post(PATH, Some("s3cret"), update()), &tx).await,
+      StatusCode::SERVICE_UNAVAILABLE
+    );
+  }
+}
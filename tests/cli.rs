@@ -0,0 +1,140 @@
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+/// The binary run from an empty directory without any of the overriding
+/// environment variables, so only the given arguments apply.
+fn nexus_pls() -> Command {
+  let mut cmd = Command::cargo_bin("nexus-pls").unwrap();
+  cmd.env_clear().current_dir(env!("CARGO_TARGET_TMPDIR"));
+  cmd
+}
+
+#[test]
+fn help_documents_every_run_option() {
+  let output = nexus_pls().args(["run", "--help"]).output().unwrap();
+  let help = String::from_utf8(output.stdout).unwrap();
+  for option in [
+    "--redis-url",
+    "--centers-path",
+    "--poll-interval",
+    "--admin-ids",
+    "--dry-run",
+    "[env: REDIS_ADDR=]",
+  ] {
+    assert!(help.contains(option), "{} is missing from:\n{}", option, help);
+  }
+}
+
+#[test]
+fn subcommands_are_listed() {
+  nexus_pls().arg("--help").assert().success().stdout(
+    predicate::str::contains("run")
+      .and(predicate::str::contains("print-config"))
+      .and(predicate::str::contains("healthcheck")),
+  );
+}
+
+#[test]
+fn flags_override_the_defaults() {
+  nexus_pls()
+    .args([
+      "print-config",
+      "--poll-interval",
+      "20",
+      "--admin-ids",
+      "1,2",
+      "--dry-run",
+    ])
+    .assert()
+    .success()
+    .stdout(
+      predicate::str::contains("poll_interval_secs = 20")
+        .and(predicate::str::contains("admin_ids = [1, 2]"))
+        .and(predicate::str::contains("dry_run = true")),
+    );
+}
+
+#[test]
+fn environment_is_used_when_flags_are_missing() {
+  nexus_pls()
+    .env("POLL_INTERVAL_SECS", "30")
+    .args(["print-config"])
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("poll_interval_secs = 30"));
+
+  nexus_pls()
+    .env("POLL_INTERVAL_SECS", "30")
+    .args(["print-config", "--poll-interval", "40"])
+    .assert()
+    .success()
+    .stdout(predicate::str::contains("poll_interval_secs = 40"));
+}
+
+#[test]
+fn malformed_values_fail_at_parse_time() {
+  nexus_pls()
+    .args(["run", "--poll-interval", "soon"])
+    .assert()
+    .code(2)
+    .stderr(predicate::str::contains("invalid value 'soon' for '--poll-interval"));
+
+  nexus_pls()
+    .args(["run", "--digest-time", "25:00"])
+    .assert()
+    .code(2)
+    .stderr(predicate::str::contains("--digest-time"));
+}
+
+#[test]
+fn invalid_combinations_are_rejected_before_starting() {
+  nexus_pls()
+    .args(["print-config", "--poll-interval", "2"])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("poll_interval_secs must be at least 5"));
+
+  nexus_pls()
+    .args(["print-config", "--poll-interval", "600", "--breaker-cooldown", "60"])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains(
+      "breaker_cooldown_secs must be at least poll_interval_secs",
+    ));
+}
+
+#[test]
+fn healthcheck_exits_with_the_instance_health() {
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+
+  nexus_pls()
+    .args(["healthcheck", "--storage-backend", "memory"])
+    .assert()
+    .code(1)
+    .stdout(predicate::str::contains("unhealthy: no heartbeat recorded"));
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap().to_string();
+  let server = std::thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let _ = stream.read(&mut [0; 1024]).unwrap();
+    stream
+      .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nready")
+      .unwrap();
+    // Accepted but never answered.
+    let _hung = listener.accept().unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(3));
+  });
+  nexus_pls()
+    .args(["healthcheck", "--http-listen", &address])
+    .assert()
+    .code(0)
+    .stdout("healthy: ready\n");
+  nexus_pls()
+    .args(["healthcheck", "--http-listen", &address])
+    .assert()
+    .code(1)
+    .stdout("unhealthy: no answer within 2s\n");
+  server.join().unwrap();
+}